@@ -11,6 +11,7 @@ pub mod android {
     use stream_replay_tx::link::*;
     use stream_replay_tx::ipc::*;
     use stream_replay_tx::source::*;
+    use stream_replay_tx::broker::*;
     use stream_replay_rx::destination::*;
     use stream_replay_rx::record::*;
 
@@ -54,14 +55,10 @@ pub mod android {
         //update manifest file
         manifest.tx_ipaddrs = vec![ipaddr1_tx.clone(), ipaddr2_tx.clone()];
         manifest.streams.iter_mut().for_each(|stream| {
-            match stream {
-                StreamParam::TCP(param) | StreamParam::UDP(param) => {
-                    param.links = vec![
-                        Link{ tx_ipaddr: ipaddr1_tx.clone(), rx_ipaddr: ipaddr1_rx.clone() },
-                        Link{ tx_ipaddr: ipaddr2_tx.clone(), rx_ipaddr: ipaddr2_rx.clone() }
-                    ]
-                }
-            }
+            stream.params_mut().links = vec![
+                Link{ tx_ipaddr: ipaddr1_tx.clone(), rx_ipaddr: ipaddr1_rx.clone(), mtu: None },
+                Link{ tx_ipaddr: ipaddr2_tx.clone(), rx_ipaddr: ipaddr2_rx.clone(), mtu: None }
+            ];
         });
 
         // parse the manifest file
@@ -70,16 +67,22 @@ pub mod android {
         println!("Sliding Window Size: {}.", window_size);
 
         // spawn the source thread
-        let mut sources:HashMap<_,_> = streams.into_iter().map(|stream| {
-            let src = SourceManager::new(stream, window_size);
+        let mut sources:HashMap<_,_> = streams.into_iter().filter_map(|stream| {
+            let src = SourceManager::new(stream, window_size, false)?;
             if !src.source.is_empty() {
                 unsafe {
                     TX_SENDER_MAP.as_mut().unwrap().insert(src.name.clone(), src.source[0].clone());
                 }
             }
             let name = src.name.clone();
-            (name, src)
+            Some((name, src))
         }).collect();
+        // schedule all streams through the broker
+        if let Some(params) = manifest.broker {
+            let mut broker = GlobalBroker::new(params);
+            sources.values_mut().for_each(|src| src.connect(&mut broker));
+            broker.start();
+        }
         let _handles:Vec<_> = sources.iter_mut().enumerate().map(|(i,(_name,src))| {
             src.start(i+1, String::from("0.0.0.0"))
         }).collect();
@@ -100,7 +103,7 @@ pub mod android {
     )
     {
         let src_ipaddrs = src_ipaddrs.split(",").map(|x| x.to_string()).collect();
-        let args = Args { port, duration, calc_rtt, rx_mode, src_ipaddrs };
+        let args = Args { port, duration, calc_rtt, rx_mode, src_ipaddrs, key: None, pcap: false, tcp: false, quic: false, rtp: false, rtp_clock_rate: RTP_CLOCK_RATE, nack: false, nack_interval: NACK_INTERVAL };
         let recv_data = Arc::new(Mutex::new(RecvData::new()));
         let recv_data_final = Arc::clone(&recv_data);
 
@@ -140,6 +143,9 @@ pub mod android {
         logging( &format!("Average Throughput: {:.3} Mbps", data_len as f64 / rx_duration / 1e6 * 8.0) );
         logging( &format!("Packet loss rate: {:.5}", non_received as f64 / recv_data.last_seq as f64) );
         logging( &format!("Stuttering rate: {:.5}", recv_data.stutter.get_stuttering()) );
+        logging( &format!("Rejected packets: {}", recv_data.malformed) );
+        logging( &format!("Corrupted packets: {}", recv_data.corrupted) );
+        logging( &format!("Recovered frames: {}", recv_data.recovered) );
     }
 
     #[no_mangle]
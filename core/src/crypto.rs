@@ -0,0 +1,85 @@
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use chacha20poly1305::aead::AeadInPlace;
+use rand::RngCore;
+
+pub const KEY_LENGTH:usize = 32;
+pub const NONCE_LENGTH:usize = 12; // optional header extension
+pub const TAG_LENGTH:usize = 16;   // appended to the encrypted payload
+pub const CIPHER_OVERHEAD:usize = NONCE_LENGTH + TAG_LENGTH;
+
+/// ChaCha20-Poly1305 over the payload, with a random nonce per packet and the header as associated data.
+#[derive(Clone)]
+pub struct PayloadCipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl PayloadCipher {
+    /// Pre-shared key given as 64 hex digits.
+    pub fn from_hex(key: &str) -> Option<Self> {
+        if key.len() != 2 * KEY_LENGTH || !key.is_ascii() {
+            return None;
+        }
+        let bytes = (0..KEY_LENGTH)
+            .map(|i| u8::from_str_radix(&key[2*i..2*i+2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self { aead: ChaCha20Poly1305::new(Key::from_slice(&bytes)) })
+    }
+
+    /// Encrypt `payload` in place, filling `nonce` and returning the tag.
+    pub fn seal(&self, nonce: &mut [u8], aad: &[u8], payload: &mut [u8]) -> Option<[u8; TAG_LENGTH]> {
+        rand::thread_rng().fill_bytes(nonce);
+        let tag = self.aead.encrypt_in_place_detached(Nonce::from_slice(nonce), aad, payload).ok()?;
+        Some(tag.into())
+    }
+
+    /// Decrypt `payload` in place, failing when the packet was not authenticated with this key.
+    pub fn open(&self, nonce: &[u8], aad: &[u8], payload: &mut [u8], tag: &[u8]) -> bool {
+        self.aead.decrypt_in_place_detached(Nonce::from_slice(nonce), aad, payload, Tag::from_slice(tag)).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn parses_key() {
+        assert!(PayloadCipher::from_hex(KEY).is_some());
+        assert!(PayloadCipher::from_hex(&KEY.to_uppercase()).is_some());
+        assert!(PayloadCipher::from_hex(&KEY[2..]).is_none());
+        assert!(PayloadCipher::from_hex(&KEY.replace("1f", "1g")).is_none());
+        assert!(PayloadCipher::from_hex(&KEY.replace("1f", "é")).is_none());
+    }
+
+    #[test]
+    fn seal_open() {
+        let cipher = PayloadCipher::from_hex(KEY).unwrap();
+        let plain: Vec<u8> = (0..100).collect();
+        let mut payload = plain.clone();
+        let mut nonce = [0u8; NONCE_LENGTH];
+        let tag = cipher.seal(&mut nonce, b"header", &mut payload).unwrap();
+        assert_ne!(payload, plain);
+        assert!(cipher.open(&nonce, b"header", &mut payload, &tag));
+        assert_eq!(payload, plain);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let cipher = PayloadCipher::from_hex(KEY).unwrap();
+        let mut payload: Vec<u8> = (0..100).collect();
+        let mut nonce = [0u8; NONCE_LENGTH];
+        let tag = cipher.seal(&mut nonce, b"header", &mut payload).unwrap();
+
+        let mut tampered = payload.clone();
+        tampered[50] ^= 0x01;
+        assert!(!cipher.open(&nonce, b"header", &mut tampered, &tag));
+        assert!(!cipher.open(&nonce, b"Header", &mut payload.clone(), &tag));
+        let mut tag_tampered = tag;
+        tag_tampered[0] ^= 0x01;
+        assert!(!cipher.open(&nonce, b"header", &mut payload.clone(), &tag_tampered));
+        let other = PayloadCipher::from_hex(&KEY.replace("00", "ff")).unwrap();
+        assert!(!other.open(&nonce, b"header", &mut payload.clone(), &tag));
+    }
+}
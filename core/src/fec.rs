@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::packet::{PacketError, PacketStruct, RtpHeader, LAST_OF_FRAME};
+
+pub const REPAIR_HEADER_LENGTH:usize = 8; // optional header extension
+const SHARD_PREFIX_LENGTH:usize = 2;      // fragment length, ahead of its payload in a shard
+pub const REPAIR_OVERHEAD:usize = REPAIR_HEADER_LENGTH + SHARD_PREFIX_LENGTH; // repair packet size beyond the largest fragment
+const MAX_SHARDS:usize = 256;             // data and parity shards of a group, over GF(2^8)
+
+const fn _default_parity() -> usize { 1 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FecScheme {
+    #[serde(rename = "xor")]
+    Xor,
+    #[serde(rename = "rs")]
+    ReedSolomon,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FecParams {
+    pub scheme: FecScheme,
+    #[serde(default)] pub group: usize,   //default: 0, the whole frame
+    #[serde(default = "_default_parity")] //default:
+    pub parity: usize,                    //         1, always 1 for "xor"
+}
+
+impl FecParams {
+    /// Repair packets per group.
+    pub fn parity(&self) -> usize {
+        match self.scheme {
+            FecScheme::Xor => 1,
+            FecScheme::ReedSolomon => self.parity,
+        }
+    }
+
+    /// Data fragments per group, for a frame of `num` fragments.
+    pub fn group_size(&self, num: usize) -> usize {
+        let group = if self.group == 0 { num } else { self.group.min(num) };
+        group.min(MAX_SHARDS - self.parity()).max(1)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (1..MAX_SHARDS).contains(&self.parity())
+    }
+}
+
+// Repair header extension (little-endian), see `PacketStruct`:
+//   first: u16 | count: u16 | scheme: u8 | index: u8 | parity: u8 | reserved: u8
+// The repair payload is the `index`-th parity shard over the fragments `first..first+count`,
+// each shard being the fragment length (u16) followed by its payload, zero-padded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RepairInfo {
+    pub first: u16,    // lowest offset of the group
+    pub count: u16,    // number of fragments in the group
+    pub scheme: FecScheme,
+    pub index: u8,     // index of this repair packet within the group
+    pub parity: u8,    // number of repair packets of the group
+}
+
+impl RepairInfo {
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.first.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.count.to_le_bytes());
+        buf[4] = match self.scheme {
+            FecScheme::Xor => 0,
+            FecScheme::ReedSolomon => 1,
+        };
+        buf[5] = self.index;
+        buf[6] = self.parity;
+        buf[7] = 0;
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, PacketError> {
+        let scheme = match buf[4] {
+            0 => FecScheme::Xor,
+            1 => FecScheme::ReedSolomon,
+            _ => return Err(PacketError::InvalidRepair),
+        };
+        let info = RepairInfo {
+            first: u16::from_le_bytes([buf[0], buf[1]]),
+            count: u16::from_le_bytes([buf[2], buf[3]]),
+            scheme,
+            index: buf[5],
+            parity: buf[6],
+        };
+        let shards = info.count as usize + info.parity as usize;
+        if info.count == 0 || info.index >= info.parity || shards > MAX_SHARDS
+            || (scheme == FecScheme::Xor && info.parity != 1) {
+            return Err(PacketError::InvalidRepair);
+        }
+        Ok(info)
+    }
+
+    pub fn offsets(&self) -> std::ops::Range<u16> {
+        self.first .. self.first + self.count
+    }
+}
+
+fn to_shard(packet: &PacketStruct, shard_len: usize) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(shard_len);
+    shard.extend_from_slice(&packet.length.to_le_bytes());
+    shard.extend_from_slice(&packet.payload[..packet.length as usize]);
+    shard.resize(shard_len, 0);
+    shard
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+/// Repair packets for a frame, `fragments` holding one packet per offset in order.
+pub fn repair_packets(params: &FecParams, fragments: &[PacketStruct]) -> Vec<PacketStruct> {
+    let num = fragments.len();
+    let group_size = params.group_size(num);
+    let parity = params.parity();
+
+    let mut repairs = Vec::new();
+    for (i, group) in fragments.chunks(group_size).enumerate() {
+        let shard_len = SHARD_PREFIX_LENGTH + group.iter().map(|p| p.length as usize).max().unwrap_or(0);
+        let data: Vec<_> = group.iter().map(|p| to_shard(p, shard_len)).collect();
+
+        let shards = match params.scheme {
+            FecScheme::Xor => {
+                let mut shard = vec![0u8; shard_len];
+                data.iter().for_each(|d| xor_into(&mut shard, d));
+                vec![shard]
+            }
+            FecScheme::ReedSolomon => {
+                let mut shards = data;
+                shards.resize(group.len() + parity, vec![0u8; shard_len]);
+                let codec = ReedSolomon::new(group.len(), parity).unwrap();
+                codec.encode(&mut shards).unwrap();
+                shards.split_off(group.len())
+            }
+        };
+
+        // sent along with the last fragment of the group
+        let last = &group[group.len()-1];
+        let first = (i * group_size) as u16;
+        let indicators = if first as usize + group.len() == num { LAST_OF_FRAME } else { 0 };
+        for (index, shard) in shards.into_iter().enumerate() {
+            let mut packet = last.header();
+            packet.offset = (num + repairs.len()) as u16; // after the fragments, unused by the receiver
+            packet.indicators = indicators;
+            packet.checksum = last.checksum;
+            packet.encrypted = last.encrypted;
+            packet.rtp = last.rtp.map(|rtp| RtpHeader { marker: false, ..rtp });
+            packet.repair = Some(RepairInfo {
+                first, count: group.len() as u16, scheme: params.scheme,
+                index: index as u8, parity: parity as u8
+            });
+            packet.set_length(shard_len as u16);
+            packet.payload = shard;
+            repairs.push(packet);
+        }
+    }
+    repairs
+}
+
+/// Missing fragments of a group rebuilt from its repair packets, if enough of them arrived.
+pub fn recover(repairs: &[PacketStruct], fragments: &HashMap<u16, PacketStruct>) -> Vec<PacketStruct> {
+    let Some(info) = repairs.first().and_then(|p| p.repair) else {
+        return Vec::new();
+    };
+    let shard_len = repairs[0].length as usize;
+    let missing: Vec<u16> = info.offsets().filter(|i| !fragments.contains_key(i)).collect();
+    if missing.is_empty() || missing.len() > repairs.len()
+        || repairs.iter().any(|p| p.repair.is_none_or(|r| r.first != info.first) || p.length as usize != shard_len)
+        || info.offsets().filter_map(|i| fragments.get(&i)).any(|p| SHARD_PREFIX_LENGTH + p.length as usize > shard_len) {
+        return Vec::new();
+    }
+
+    let shards = match info.scheme {
+        FecScheme::Xor => {
+            let mut shard = repairs[0].payload[..shard_len].to_vec();
+            info.offsets().filter_map(|i| fragments.get(&i))
+                .for_each(|p| xor_into(&mut shard, &to_shard(p, shard_len)));
+            vec![shard]
+        }
+        FecScheme::ReedSolomon => {
+            let count = info.count as usize;
+            let mut shards: Vec<Option<Vec<u8>>> = info.offsets()
+                .map(|i| fragments.get(&i).map(|p| to_shard(p, shard_len)))
+                .collect();
+            shards.resize(count + info.parity as usize, None);
+            for repair in repairs {
+                let index = repair.repair.unwrap().index as usize;
+                shards[count + index] = Some(repair.payload[..shard_len].to_vec());
+            }
+            let Ok(codec) = ReedSolomon::new(count, info.parity as usize) else {
+                return Vec::new();
+            };
+            if codec.reconstruct_data(&mut shards).is_err() {
+                return Vec::new();
+            }
+            missing.iter().map(|i| shards[(i - info.first) as usize].take().unwrap()).collect()
+        }
+    };
+
+    let is_frame_end = repairs[0].indicators & LAST_OF_FRAME != 0;
+    missing.into_iter().zip(shards).filter_map(|(offset, shard)| {
+        let length = u16::from_le_bytes([shard[0], shard[1]]);
+        if SHARD_PREFIX_LENGTH + length as usize > shard_len {
+            return None;
+        }
+        let mut packet = repairs[0].header();
+        packet.offset = offset;
+        packet.indicators = if is_frame_end && offset == info.first + info.count - 1 { LAST_OF_FRAME } else { 0 };
+        packet.set_length(length);
+        packet.payload = shard[SHARD_PREFIX_LENGTH..SHARD_PREFIX_LENGTH + length as usize].to_vec();
+        Some(packet)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one packet per offset, of varying lengths and contents
+    fn fragments(num: usize) -> Vec<PacketStruct> {
+        (0..num).map(|i| {
+            let mut packet = PacketStruct::new(5202, 0);
+            packet.seq = i as u32;
+            packet.offset = i as u16;
+            packet.indicators = if i == num - 1 { LAST_OF_FRAME } else { 0 };
+            packet.payload = (0..100 + 7 * i).map(|j| (i * 31 + j) as u8).collect();
+            packet.set_length(packet.payload.len() as u16);
+            packet
+        }).collect()
+    }
+
+    fn received(fragments: &[PacketStruct], lost: &[u16]) -> HashMap<u16, PacketStruct> {
+        fragments.iter().filter(|p| !lost.contains(&p.offset)).map(|p| (p.offset, p.clone())).collect()
+    }
+
+    fn assert_recovered(recovered: &[PacketStruct], fragments: &[PacketStruct], lost: &[u16]) {
+        let mut offsets: Vec<_> = recovered.iter().map(|p| p.offset).collect();
+        offsets.sort();
+        assert_eq!(offsets, lost);
+        for packet in recovered {
+            let fragment = &fragments[packet.offset as usize];
+            assert_eq!(packet.length, fragment.length);
+            assert_eq!(packet.payload, fragment.payload);
+            assert_eq!(packet.indicators, fragment.indicators);
+        }
+    }
+
+    #[test]
+    fn xor_recovers_one() {
+        let params = FecParams{ scheme: FecScheme::Xor, group: 4, parity: 1 };
+        let fragments = fragments(10);
+        let repairs = repair_packets(&params, &fragments);
+        assert_eq!(repairs.len(), 3);
+        for lost in [0, 5, 9] {
+            let group = &repairs[lost as usize / 4..lost as usize / 4 + 1];
+            let recovered = recover(group, &received(&fragments, &[lost]));
+            assert_recovered(&recovered, &fragments, &[lost]);
+        }
+        // beyond the parity of the group
+        assert!(recover(&repairs[..1], &received(&fragments, &[0, 1])).is_empty());
+    }
+
+    #[test]
+    fn rs_recovers_parity() {
+        let params = FecParams{ scheme: FecScheme::ReedSolomon, group: 0, parity: 3 };
+        let fragments = fragments(8);
+        let repairs = repair_packets(&params, &fragments);
+        assert_eq!(repairs.len(), 3);
+        assert_recovered(&recover(&repairs, &received(&fragments, &[4])), &fragments, &[4]);
+        assert_recovered(&recover(&repairs, &received(&fragments, &[0, 3, 7])), &fragments, &[0, 3, 7]);
+        // any of the repair packets will do
+        assert_recovered(&recover(&repairs[1..], &received(&fragments, &[2, 6])), &fragments, &[2, 6]);
+        assert!(recover(&repairs, &received(&fragments, &[0, 1, 2, 3])).is_empty());
+        assert!(recover(&repairs, &received(&fragments, &[])).is_empty());
+    }
+
+    #[test]
+    fn repair_roundtrip() {
+        let params = FecParams{ scheme: FecScheme::ReedSolomon, group: 3, parity: 2 };
+        let fragments = fragments(5);
+        let repairs = repair_packets(&params, &fragments);
+        assert_eq!(repairs.len(), 4);
+        let decoded: Vec<_> = repairs.iter()
+            .map(|p| PacketStruct::decode(&p.to_bytes().unwrap(), None).unwrap())
+            .collect();
+        assert_eq!(decoded[2].repair, Some(RepairInfo{ first: 3, count: 2, scheme: FecScheme::ReedSolomon, index: 0, parity: 2 }));
+        assert_recovered(&recover(&decoded[2..], &received(&fragments, &[3, 4])), &fragments, &[3, 4]);
+    }
+
+    #[test]
+    fn rejects_invalid_repair() {
+        let info = RepairInfo{ first: 0, count: 4, scheme: FecScheme::Xor, index: 0, parity: 1 };
+        let mut buf = [0u8; REPAIR_HEADER_LENGTH];
+        info.encode(&mut buf);
+        assert_eq!(RepairInfo::decode(&buf), Ok(info));
+        for (i, value) in [(2, 0), (4, 2), (5, 1), (6, 2)] {
+            let mut invalid = buf;
+            invalid[i] = value;
+            assert_eq!(RepairInfo::decode(&invalid), Err(PacketError::InvalidRepair));
+        }
+    }
+}
@@ -1,3 +1,9 @@
+pub mod crypto;
+pub mod fec;
 pub mod logger;
 pub mod packet;
+pub mod pcap;
+pub mod quic;
 pub mod socket;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
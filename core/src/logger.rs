@@ -30,7 +30,6 @@ pub fn init_log(if_rx: bool) {
 
     // Set up env_logger to write to the log file
     let file = OpenOptions::new()
-        .write(true)
         .append(true)
         .open(log_file)
         .unwrap();
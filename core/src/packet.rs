@@ -1,10 +1,96 @@
 #![allow(dead_code)]
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use crate::fec::{RepairInfo, REPAIR_HEADER_LENGTH};
+use crate::crypto::{PayloadCipher, NONCE_LENGTH, TAG_LENGTH};
 
-const IP_HEADER_LENGTH:usize = 20;
+pub const ETHERNET_MTU:usize = 1500;
+const IPV4_HEADER_LENGTH:usize = 20;
+const IPV6_HEADER_LENGTH:usize = 40;
 const UDP_HEADER_LENGTH:usize = 8;
-pub const APP_HEADER_LENGTH:usize = 19;
-pub const UDP_MAX_LENGTH:usize = 1500 - IP_HEADER_LENGTH - UDP_HEADER_LENGTH;
-pub const MAX_PAYLOAD_LEN:usize = UDP_MAX_LENGTH - APP_HEADER_LENGTH;
+pub const APP_HEADER_LENGTH:usize = 24;
+pub const UDP_MAX_LENGTH:usize = udp_max_length(ETHERNET_MTU, false);
+pub const MAX_PAYLOAD_LEN:usize = max_payload_len(ETHERNET_MTU, false);
+pub const MAX_DATAGRAM_LENGTH:usize = 65535; // receive buffer size, fits any UDP datagram
+
+/// Largest UDP payload fitting in the given MTU over IPv4 or IPv6.
+pub const fn udp_max_length(mtu: usize, ipv6: bool) -> usize {
+    let ip_header_length = if ipv6 { IPV6_HEADER_LENGTH } else { IPV4_HEADER_LENGTH };
+    mtu.saturating_sub(ip_header_length + UDP_HEADER_LENGTH)
+}
+
+/// Largest fragment payload fitting in the given MTU over IPv4 or IPv6.
+pub const fn max_payload_len(mtu: usize, ipv6: bool) -> usize {
+    udp_max_length(mtu, ipv6).saturating_sub(APP_HEADER_LENGTH)
+}
+
+pub const HEADER_MAGIC:u16 = 0x5352;   // "SR", stream-replay
+pub const PROTOCOL_VERSION:u8 = 3;
+pub const CHECKSUM_LENGTH:usize = 4; // optional header extension
+
+// RTP framing (RFC 3550, big-endian), the native datagram following as is:
+//   V=2 P=0 X=1 CC=0 | M PT | sequence: u16 | timestamp: u32 | ssrc: u32
+//   profile: u16 = RTP_EXTENSION_PROFILE | words: u16, the native header in 32-bit words
+// so that the native header forms the header extension and its payload the RTP payload.
+pub const RTP_HEADER_LENGTH:usize = 12;
+const RTP_EXTENSION_HEADER_LENGTH:usize = 4;
+pub const RTP_OVERHEAD:usize = RTP_HEADER_LENGTH + RTP_EXTENSION_HEADER_LENGTH;
+pub const RTP_VERSION:u8 = 2;
+pub const RTP_EXTENSION_PROFILE:u16 = HEADER_MAGIC;
+pub const RTP_CLOCK_RATE:f64 = 90_000.0; // Hz, as for video
+const fn _default_payload_type() -> u8 { 96 } // first dynamic payload type
+fn _random_ssrc() -> u32 { rand::thread_rng().gen() }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RtpParams {
+    #[serde(default = "_random_ssrc")]          //default:
+    pub ssrc: u32,                              //         <random>
+    #[serde(default = "_default_payload_type")] //default:
+    pub payload_type: u8,                       //         96
+}
+
+/// RTP fields of a packet, the marker bit being set on the last fragment of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RtpHeader {
+    pub payload_type: u8,
+    pub sequence: u16,  // per packet
+    pub timestamp: u32, // per frame, at RTP_CLOCK_RATE
+    pub ssrc: u32,      // per stream
+    pub marker: bool,
+}
+
+impl RtpHeader {
+    pub fn new(params: &RtpParams) -> Self {
+        RtpHeader { payload_type: params.payload_type & 0x7F, sequence: rand::thread_rng().gen(),
+            timestamp: rand::thread_rng().gen(), ssrc: params.ssrc, marker: false }
+    }
+
+    /// Parse the fixed header, skipping CSRCs, returning it with the offset of the header extension or payload.
+    pub fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        if buf.len() < RTP_HEADER_LENGTH || buf[0] >> 6 != RTP_VERSION {
+            return None;
+        }
+        let header = RtpHeader {
+            payload_type: buf[1] & 0x7F,
+            sequence: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            ssrc: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            marker: buf[1] & 0x80 != 0,
+        };
+        let start = RTP_HEADER_LENGTH + 4 * (buf[0] & 0x0F) as usize;
+        (buf.len() >= start).then_some((header, start))
+    }
+
+    fn encode(&self, buf: &mut [u8], extension_words: u16) {
+        buf[0] = RTP_VERSION << 6 | 0x10; // with a header extension
+        buf[1] = if self.marker { 0x80 } else { 0 } | (self.payload_type & 0x7F);
+        buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        buf[12..14].copy_from_slice(&RTP_EXTENSION_PROFILE.to_be_bytes());
+        buf[14..16].copy_from_slice(&extension_words.to_be_bytes());
+    }
+}
 
 pub type PacketSender   = flume::Sender<PacketStruct>;
 pub type PacketReceiver = flume::Receiver<PacketStruct>;
@@ -12,45 +98,85 @@ pub type PacketReceiver = flume::Receiver<PacketStruct>;
 pub type BufferSender = flume::Sender<Vec<u8>>;
 pub type BufferReceiver = flume::Receiver<Vec<u8>>;
 
-pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        ::std::mem::size_of::<T>(),
-    )
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    TooShort(usize),
+    BadMagic(u16),
+    UnsupportedVersion(u8),
+    BadHeaderLength(u8),
+    Truncated { length: usize, available: usize },
+    PayloadTooShort { length: usize, available: usize },
+    BufferTooSmall { required: usize, available: usize },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    InvalidRepair,
+    InvalidRtp,
+    MissingKey,
+    NotEncrypted,
+    EncryptionFailed,
+    DecryptionFailed,
 }
 
-#[derive(Clone)]
-pub enum PacketType {
-    SNL,
-    SL,
-    DFN,
-    DFL,
-    DSS,
-    DSF,
-    DSM,
-    DSL,
-    SLFL,
-    SLSL,
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "datagram too short for header: {} bytes", len),
+            Self::BadMagic(magic) => write!(f, "bad magic number: {:#06x}", magic),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported protocol version: {}", version),
+            Self::BadHeaderLength(len) => write!(f, "bad header length: {}", len),
+            Self::Truncated { length, available } => write!(f, "payload truncated: {} bytes declared, {} available", length, available),
+            Self::PayloadTooShort { length, available } => write!(f, "payload too short: {} bytes declared, {} available", length, available),
+            Self::BufferTooSmall { required, available } => write!(f, "buffer too small: {} bytes required, {} available", required, available),
+            Self::ChecksumMismatch { expected, actual } => write!(f, "checksum mismatch: expected {:#010x}, computed {:#010x}", expected, actual),
+            Self::InvalidRepair => write!(f, "invalid repair header"),
+            Self::InvalidRtp => write!(f, "RTP packet without a stream-replay header extension"),
+            Self::MissingKey => write!(f, "encrypted payload without a key"),
+            Self::NotEncrypted => write!(f, "payload not encrypted"),
+            Self::EncryptionFailed => write!(f, "payload encryption failed"),
+            Self::DecryptionFailed => write!(f, "payload authentication failed"),
+        }
+    }
 }
 
-#[repr(C,packed)]
-#[derive(Copy, Clone, Debug)]
+impl std::error::Error for PacketError {}
+
+// Bits of `PacketStruct.indicators`
+pub const FIRST_OF_PATH:u8  = 0b0001; // lowest offset of the frame carried on this path
+pub const LAST_OF_PATH:u8   = 0b0010; // highest offset of the frame carried on this path
+pub const LAST_OF_FRAME:u8  = 0b0100; // the last offset of the frame
+pub const FRAME_COMPLETE:u8 = 0b1000; // (ACK only) the whole frame has been received
+pub const NACK:u8 = 0b1000_0000;      // (ACK only) fragments of the frame are missing, see `PacketStruct::set_nack`
+const HAS_CHECKSUM:u8 = 0b1_0000;  // (wire only) a CRC32 follows the base header
+const HAS_REPAIR:u8   = 0b10_0000; // (wire only) a FEC repair packet, see `fec::RepairInfo`
+const IS_ENCRYPTED:u8 = 0b100_0000; // (wire only) the payload is encrypted, see `crypto::PayloadCipher`
+
+// Wire format (little-endian), `header_len` bytes followed by `length` bytes of payload
+// (and a TAG_LENGTH bytes authentication tag when IS_ENCRYPTED):
+//   magic: u16 | version: u8 | header_len: u8 | seq: u32 | offset: u16 | length: u16 | port: u16 | path: u8 | indicators: u8 | timestamp: f64
+// followed by the optional header extensions, in this order:
+//   checksum: u32, CRC32 of the whole datagram with this field zeroed (HAS_CHECKSUM)
+//   repair: REPAIR_HEADER_LENGTH bytes, group of the parity shard in the payload (HAS_REPAIR)
+//   nonce: NONCE_LENGTH bytes, the whole header with a zeroed checksum being the associated data (IS_ENCRYPTED)
+#[derive(Clone, Debug)]
 pub struct PacketStruct {
     pub seq: u32,       //4 Bytes
     pub offset: u16,    //2 Bytes, how much left to send
     pub length: u16,    //2 Bytes
     pub port: u16,      //2 Bytes
-    pub indicators: u8, //1 Byte, 0 - 1 represents the interface id, 10~19 represents the last packet of interface id 
+    pub path: u8,       //1 Byte, index of the link carrying this packet
+    pub indicators: u8, //1 Byte, bitwise OR of FIRST_OF_PATH, LAST_OF_PATH, LAST_OF_FRAME, FRAME_COMPLETE and NACK
     pub timestamp: f64, //8 Bytes
-    pub payload: [u8; MAX_PAYLOAD_LEN]
+    pub checksum: bool, //4 Bytes when set, CRC32 computed on encoding and verified on decoding
+    pub repair: Option<RepairInfo>, //8 Bytes when set, the payload is a parity shard instead of a fragment
+    pub encrypted: bool, //28 Bytes when set, payload encrypted on encoding and decrypted on decoding
+    pub rtp: Option<RtpHeader>, //16 Bytes ahead when set, framed as RTP
+    pub payload: Vec<u8>, // at least `length` bytes
 }
 
 impl PacketStruct {
-    pub fn new(port: u16) -> Self {
-        // dummy payload content from 0..MAX_PAYLOAD_LEN
-        let mut payload = [0u8; MAX_PAYLOAD_LEN];
-        (0..MAX_PAYLOAD_LEN).for_each(|i| payload[i] = i as u8);
-        PacketStruct { seq: 0, offset: 0, length: 0, port, timestamp:0.0, indicators:0 , payload }
+    pub fn new(port: u16, payload_len: usize) -> Self {
+        // dummy payload content from 0..payload_len
+        let payload = (0..payload_len).map(|i| i as u8).collect();
+        PacketStruct { seq: 0, offset: 0, length: 0, port, timestamp:0.0, path:0, indicators:0, checksum:false, repair:None, encrypted:false, rtp:None, payload }
     }
     pub fn set_length(&mut self, length: u16) {
         self.length = length;
@@ -63,57 +189,243 @@ impl PacketStruct {
         self.offset = offset;
     }
 
-    pub fn set_indicator(&mut self, packet_type: PacketType){
-        self.indicators = to_indicator(packet_type);
+    pub fn set_path(&mut self, path: u8, indicators: u8) {
+        self.path = path;
+        self.indicators = indicators;
+    }
+
+    pub fn is_last_of_path(&self) -> bool {
+        self.indicators & LAST_OF_PATH != 0
+    }
+
+    /// Turn an ACK header into a NACK of the `missing` offsets (u16 each in the payload, at most
+    /// `MAX_PAYLOAD_LEN / 2`), and of all offsets from `tail` on while the end of the frame is unknown.
+    pub fn set_nack(&mut self, missing: &[u16], tail: Option<u16>) {
+        self.payload = missing.iter().take(MAX_PAYLOAD_LEN / 2).flat_map(|offset| offset.to_le_bytes()).collect();
+        self.length = self.payload.len() as u16;
+        self.offset = tail.unwrap_or(0);
+        self.indicators = NACK | if tail.is_none() { LAST_OF_FRAME } else { 0 };
+    }
+
+    /// The missing offsets and the missing tail, if a NACK.
+    pub fn nacked(&self) -> Option<(Vec<u16>, Option<u16>)> {
+        if self.indicators & NACK == 0 {
+            return None;
+        }
+        let missing = self.payload[..self.length as usize].chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let tail = (self.indicators & LAST_OF_FRAME == 0).then_some(self.offset);
+        Some((missing, tail))
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
+        if self.payload.len() < payload.len() {
+            self.payload.resize(payload.len(), 0);
+        }
         self.payload[..payload.len()].copy_from_slice(payload);
     }
-}
 
-pub fn channel_info(indicator: u8) -> u8{
-    (indicator & 0b00000100) >> 2
-}
+    /// Copy of the header fields with an empty payload, e.g. for ACKs.
+    pub fn header(&self) -> Self {
+        PacketStruct { length: 0, checksum: false, repair: None, encrypted: false, rtp: None, payload: Vec::new(), ..*self }
+    }
 
-pub fn from_buffer(buffer: &[u8]) -> PacketStruct {
-    // usafe to cast buffer to PacketStruct
-    unsafe {
-        let mut packet: PacketStruct = std::mem::zeroed();
-        let packet_ptr = &mut packet as *mut PacketStruct as *mut u8;
-        std::ptr::copy_nonoverlapping(buffer.as_ptr(), packet_ptr, std::mem::size_of::<PacketStruct>());
-        packet
+    /// Size of the encoded header, extensions included.
+    pub fn header_length(&self) -> usize {
+        APP_HEADER_LENGTH
+            + if self.checksum { CHECKSUM_LENGTH } else { 0 }
+            + if self.repair.is_some() { REPAIR_HEADER_LENGTH } else { 0 }
+            + if self.encrypted { NONCE_LENGTH } else { 0 }
     }
-}
 
-pub fn to_indicator(packet_type: PacketType) -> u8 {
-    match packet_type {
-        PacketType::SNL =>  0b00000000,
-        PacketType::SL  =>  0b00000001,
-        PacketType::DFN =>  0b00000010,
-        PacketType::DFL =>  0b00000011,
-        PacketType::DSS =>  0b00000100,
-        PacketType::DSF =>  0b00000101,
-        PacketType::DSM =>  0b00000110,
-        PacketType::DSL =>  0b00000111,
-        PacketType::SLFL => 0b00001000,
-        PacketType::SLSL => 0b00001100,
+    /// Size of the encoded datagram, header included.
+    pub fn wire_length(&self) -> usize {
+        (if self.rtp.is_some() { RTP_OVERHEAD } else { 0 })
+            + self.header_length() + self.length as usize + if self.encrypted { TAG_LENGTH } else { 0 }
     }
-}
-pub fn get_packet_type(indicators: u8) -> PacketType {
-    match indicators {
-        0b00000000 => PacketType::SNL,
-        0b00000001 => PacketType::SL,
-        0b00000010 => PacketType::DFN,
-        0b00000011 => PacketType::DFL,
-        0b00000100 => PacketType::DSS,
-        0b00000101 => PacketType::DSF,
-        0b00000110 => PacketType::DSM,
-        0b00000111 => PacketType::DSL,
-        0b00001000 => PacketType::SLFL,
-        0b00001100 => PacketType::SLSL,
-        _ => panic!("Invalid packet type")
+
+    /// Serialize header and payload into `buf`, returning the number of bytes written.
+    /// An `encrypted` packet requires the `cipher`.
+    pub fn encode(&self, buf: &mut [u8], cipher: Option<&PayloadCipher>) -> Result<usize, PacketError> {
+        if let Some(rtp) = self.rtp {
+            // the native header is a whole number of words
+            let words = (self.header_length() / 4) as u16;
+            if buf.len() < RTP_OVERHEAD {
+                return Err(PacketError::BufferTooSmall { required: self.wire_length(), available: buf.len() });
+            }
+            rtp.encode(buf, words);
+            return self.encode_native(&mut buf[RTP_OVERHEAD..], cipher).map(|length| RTP_OVERHEAD + length);
+        }
+        self.encode_native(buf, cipher)
+    }
+
+    fn encode_native(&self, buf: &mut [u8], cipher: Option<&PayloadCipher>) -> Result<usize, PacketError> {
+
+        let length = self.length as usize;
+        if length > self.payload.len() {
+            return Err(PacketError::PayloadTooShort { length, available: self.payload.len() });
+        }
+        let header_len = self.header_length();
+        let required = header_len + length + if self.encrypted { TAG_LENGTH } else { 0 };
+        if buf.len() < required {
+            return Err(PacketError::BufferTooSmall { required, available: buf.len() });
+        }
+
+        let indicators = self.indicators
+            | if self.checksum { HAS_CHECKSUM } else { 0 }
+            | if self.repair.is_some() { HAS_REPAIR } else { 0 }
+            | if self.encrypted { IS_ENCRYPTED } else { 0 };
+        buf[0..2].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        buf[2] = PROTOCOL_VERSION;
+        buf[3] = header_len as u8;
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.offset.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.length.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.port.to_le_bytes());
+        buf[14] = self.path;
+        buf[15] = indicators;
+        buf[16..24].copy_from_slice(&self.timestamp.to_le_bytes());
+        if self.checksum {
+            buf[APP_HEADER_LENGTH..APP_HEADER_LENGTH+CHECKSUM_LENGTH].fill(0);
+        }
+        if let Some(repair) = self.repair {
+            let start = APP_HEADER_LENGTH + if self.checksum { CHECKSUM_LENGTH } else { 0 };
+            repair.encode(&mut buf[start..start + REPAIR_HEADER_LENGTH]);
+        }
+        buf[header_len..header_len+length].copy_from_slice(&self.payload[..length]);
+        if self.encrypted {
+            let cipher = cipher.ok_or(PacketError::MissingKey)?;
+            let (header, body) = buf.split_at_mut(header_len);
+            let (aad, nonce) = header.split_at_mut(header_len - NONCE_LENGTH);
+            let tag = cipher.seal(nonce, aad, &mut body[..length]).ok_or(PacketError::EncryptionFailed)?;
+            body[length..length+TAG_LENGTH].copy_from_slice(&tag);
+        }
+        if self.checksum {
+            let checksum = datagram_checksum(&buf[..required]);
+            buf[APP_HEADER_LENGTH..APP_HEADER_LENGTH+CHECKSUM_LENGTH].copy_from_slice(&checksum.to_le_bytes());
+        }
+        Ok(required)
     }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut buf = vec![0u8; self.wire_length()];
+        self.encode(&mut buf, None)?;
+        Ok(buf)
+    }
+
+    /// Parse a received datagram, natively or RTP framed, rejecting foreign or malformed traffic.
+    /// With a `cipher`, the payload must be encrypted and is decrypted; without, it must not be.
+    pub fn decode(buf: &[u8], cipher: Option<&PayloadCipher>) -> Result<Self, PacketError> {
+        if buf.len() < 4 {
+            return Err(PacketError::TooShort(buf.len()));
+        }
+        // the native magic never reads as RTP version 2
+        if buf[0] >> 6 == RTP_VERSION {
+            let (rtp, start) = RtpHeader::decode(buf).ok_or(PacketError::TooShort(buf.len()))?;
+            let native = &buf[start..];
+            if buf[0] & 0x10 == 0 || native.len() < RTP_EXTENSION_HEADER_LENGTH + 4
+                || u16::from_be_bytes([native[0], native[1]]) != RTP_EXTENSION_PROFILE
+                || 4 * u16::from_be_bytes([native[2], native[3]]) as usize != native[RTP_EXTENSION_HEADER_LENGTH + 3] as usize {
+                return Err(PacketError::InvalidRtp);
+            }
+            let mut packet = Self::decode(&native[RTP_EXTENSION_HEADER_LENGTH..], cipher)?;
+            packet.rtp = Some(rtp);
+            return Ok(packet);
+        }
+        let magic = u16::from_le_bytes([buf[0], buf[1]]);
+        if magic != HEADER_MAGIC {
+            return Err(PacketError::BadMagic(magic));
+        }
+        if buf[2] != PROTOCOL_VERSION {
+            return Err(PacketError::UnsupportedVersion(buf[2]));
+        }
+        let header_len = buf[3] as usize;
+        if header_len < APP_HEADER_LENGTH {
+            return Err(PacketError::BadHeaderLength(buf[3]));
+        }
+        if buf.len() < header_len {
+            return Err(PacketError::TooShort(buf.len()));
+        }
+        // verify before trusting any other field
+        let checksum = buf[15] & HAS_CHECKSUM != 0;
+        if checksum {
+            if header_len < APP_HEADER_LENGTH + CHECKSUM_LENGTH {
+                return Err(PacketError::BadHeaderLength(buf[3]));
+            }
+            let expected = u32::from_le_bytes(buf[APP_HEADER_LENGTH..APP_HEADER_LENGTH+CHECKSUM_LENGTH].try_into().unwrap());
+            let actual = datagram_checksum(buf);
+            if expected != actual {
+                return Err(PacketError::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        let encrypted = buf[15] & IS_ENCRYPTED != 0;
+        let tag_len = if encrypted { TAG_LENGTH } else { 0 };
+        let length = u16::from_le_bytes([buf[10], buf[11]]);
+        let available = buf.len() - header_len;
+        if length as usize + tag_len > available {
+            return Err(PacketError::Truncated { length: length as usize + tag_len, available });
+        }
+        // every other bit is an indicator, none left to reject
+        let indicators = buf[15] & !(HAS_CHECKSUM | HAS_REPAIR | IS_ENCRYPTED);
+        let repair = if buf[15] & HAS_REPAIR != 0 {
+            let start = APP_HEADER_LENGTH + if checksum { CHECKSUM_LENGTH } else { 0 };
+            if header_len < start + REPAIR_HEADER_LENGTH {
+                return Err(PacketError::BadHeaderLength(buf[3]));
+            }
+            Some(RepairInfo::decode(&buf[start..start + REPAIR_HEADER_LENGTH])?)
+        } else {
+            None
+        };
+
+        let mut payload = buf[header_len..header_len + length as usize].to_vec();
+        match (encrypted, cipher) {
+            (true, Some(cipher)) => {
+                let extensions = APP_HEADER_LENGTH
+                    + if checksum { CHECKSUM_LENGTH } else { 0 }
+                    + if repair.is_some() { REPAIR_HEADER_LENGTH } else { 0 };
+                if header_len < extensions + NONCE_LENGTH {
+                    return Err(PacketError::BadHeaderLength(buf[3]));
+                }
+                let nonce_start = header_len - NONCE_LENGTH;
+                let mut aad = buf[..nonce_start].to_vec();
+                if checksum {
+                    aad[APP_HEADER_LENGTH..APP_HEADER_LENGTH+CHECKSUM_LENGTH].fill(0);
+                }
+                let tag = &buf[header_len + length as usize..header_len + length as usize + TAG_LENGTH];
+                if !cipher.open(&buf[nonce_start..header_len], &aad, &mut payload, tag) {
+                    return Err(PacketError::DecryptionFailed);
+                }
+            }
+            (true, None) => return Err(PacketError::MissingKey),
+            (false, Some(_)) => return Err(PacketError::NotEncrypted),
+            (false, None) => {}
+        }
+        Ok(PacketStruct {
+            seq: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            offset: u16::from_le_bytes([buf[8], buf[9]]),
+            length,
+            port: u16::from_le_bytes([buf[12], buf[13]]),
+            path: buf[14],
+            indicators,
+            timestamp: f64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            checksum,
+            repair,
+            encrypted,
+            rtp: None,
+            payload,
+        })
+    }
+}
+
+// CRC32 over the datagram, with the checksum field itself taken as zero
+fn datagram_checksum(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[..APP_HEADER_LENGTH]);
+    hasher.update(&[0u8; CHECKSUM_LENGTH]);
+    hasher.update(&buf[APP_HEADER_LENGTH+CHECKSUM_LENGTH..]);
+    hasher.finalize()
 }
 
 //Reference: https://wireless.wiki.kernel.org/en/developers/documentation/mac80211/queues
@@ -127,3 +439,264 @@ pub fn tos2ac(tos: u8) -> usize {
         _ => { panic!("Impossible ToS value.") }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CIPHER_OVERHEAD;
+
+    fn packet(length: u16) -> PacketStruct {
+        let mut packet = PacketStruct::new(5202, length as usize);
+        packet.seq = 42;
+        packet.offset = 3;
+        packet.set_length(length);
+        packet.set_path(1, FIRST_OF_PATH | LAST_OF_FRAME);
+        packet.timestamp = 1.5;
+        packet
+    }
+
+    fn assert_same(decoded: &PacketStruct, packet: &PacketStruct) {
+        assert_eq!((decoded.seq, decoded.offset, decoded.length, decoded.port, decoded.path, decoded.indicators),
+            (packet.seq, packet.offset, packet.length, packet.port, packet.path, packet.indicators));
+        assert_eq!(decoded.timestamp, packet.timestamp);
+        assert_eq!(decoded.payload, packet.payload[..packet.length as usize]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let packet = packet(100);
+        let buf = packet.to_bytes().unwrap();
+        assert_eq!(buf.len(), APP_HEADER_LENGTH + 100);
+        assert_same(&PacketStruct::decode(&buf, None).unwrap(), &packet);
+        // larger than the default MTU allows
+        let packet = self::packet(8000);
+        assert_same(&PacketStruct::decode(&packet.to_bytes().unwrap(), None).unwrap(), &packet);
+    }
+
+    #[test]
+    fn roundtrip_empty() {
+        let packet = packet(100).header();
+        let buf = packet.to_bytes().unwrap();
+        assert_eq!(buf.len(), APP_HEADER_LENGTH);
+        assert_same(&PacketStruct::decode(&buf, None).unwrap(), &packet);
+    }
+
+    #[test]
+    fn payload_fits_mtu() {
+        assert_eq!(udp_max_length(ETHERNET_MTU, false), 1472);
+        assert_eq!(udp_max_length(ETHERNET_MTU, true), 1452);
+        assert_eq!(max_payload_len(ETHERNET_MTU, false), MAX_PAYLOAD_LEN);
+        assert_eq!(max_payload_len(9000, true) + APP_HEADER_LENGTH + IPV6_HEADER_LENGTH + UDP_HEADER_LENGTH, 9000);
+        assert_eq!(max_payload_len(50, false), 0);
+    }
+
+    #[test]
+    fn rejects_short() {
+        let buf = packet(100).to_bytes().unwrap();
+        assert_eq!(PacketStruct::decode(&buf[..3], None).unwrap_err(), PacketError::TooShort(3));
+        assert_eq!(PacketStruct::decode(&buf[..10], None).unwrap_err(), PacketError::TooShort(10));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut buf = packet(100).to_bytes().unwrap();
+        buf[0] = 0;
+        assert_eq!(PacketStruct::decode(&buf, None).unwrap_err(), PacketError::BadMagic(0x5300));
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut buf = packet(100).to_bytes().unwrap();
+        buf[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(PacketStruct::decode(&buf, None).unwrap_err(), PacketError::UnsupportedVersion(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_bad_header_length() {
+        let mut buf = packet(100).to_bytes().unwrap();
+        buf[3] = APP_HEADER_LENGTH as u8 - 1;
+        assert_eq!(PacketStruct::decode(&buf, None).unwrap_err(), PacketError::BadHeaderLength(APP_HEADER_LENGTH as u8 - 1));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let buf = packet(100).to_bytes().unwrap();
+        assert_eq!(PacketStruct::decode(&buf[..APP_HEADER_LENGTH + 99], None).unwrap_err(),
+            PacketError::Truncated { length: 100, available: 99 });
+    }
+
+    #[test]
+    fn rejects_short_payload() {
+        let mut packet = packet(100);
+        packet.payload.truncate(99);
+        assert_eq!(packet.to_bytes().unwrap_err(), PacketError::PayloadTooShort { length: 100, available: 99 });
+    }
+
+    #[test]
+    fn rejects_small_buffer() {
+        let mut buf = [0u8; APP_HEADER_LENGTH + 99];
+        assert_eq!(packet(100).encode(&mut buf, None).unwrap_err(),
+            PacketError::BufferTooSmall { required: APP_HEADER_LENGTH + 100, available: APP_HEADER_LENGTH + 99 });
+    }
+
+    #[test]
+    fn decodes_indicators() {
+        let mut buf = packet(100).to_bytes().unwrap();
+        for indicators in [0, FIRST_OF_PATH | LAST_OF_PATH | LAST_OF_FRAME, FRAME_COMPLETE, NACK | FIRST_OF_PATH,
+                           FIRST_OF_PATH | LAST_OF_PATH | LAST_OF_FRAME | FRAME_COMPLETE | NACK] {
+            buf[15] = indicators;
+            assert_eq!(PacketStruct::decode(&buf, None).unwrap().indicators, indicators);
+        }
+    }
+
+    #[test]
+    fn checksum_roundtrip() {
+        let mut packet = packet(100);
+        packet.checksum = true;
+        let buf = packet.to_bytes().unwrap();
+        assert_eq!(buf.len(), APP_HEADER_LENGTH + CHECKSUM_LENGTH + 100);
+        let decoded = PacketStruct::decode(&buf, None).unwrap();
+        assert!(decoded.checksum);
+        assert_same(&decoded, &packet);
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut packet = packet(100);
+        packet.checksum = true;
+        let buf = packet.to_bytes().unwrap();
+        for i in [5, APP_HEADER_LENGTH, buf.len() - 1] {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            assert!(matches!(PacketStruct::decode(&corrupted, None), Err(PacketError::ChecksumMismatch { .. })));
+        }
+    }
+
+    #[test]
+    fn rejects_checksum_without_room() {
+        let mut packet = packet(0).header();
+        packet.checksum = true;
+        let mut buf = packet.to_bytes().unwrap();
+        buf[3] = APP_HEADER_LENGTH as u8;
+        assert_eq!(PacketStruct::decode(&buf, None).unwrap_err(), PacketError::BadHeaderLength(APP_HEADER_LENGTH as u8));
+    }
+
+    fn cipher() -> PayloadCipher {
+        PayloadCipher::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap()
+    }
+
+    #[test]
+    fn encrypted_roundtrip() {
+        let cipher = cipher();
+        let mut packet = packet(100);
+        packet.checksum = true;
+        packet.encrypted = true;
+        let mut buf = vec![0u8; packet.wire_length()];
+        assert_eq!(packet.encode(&mut buf, Some(&cipher)), Ok(APP_HEADER_LENGTH + CHECKSUM_LENGTH + CIPHER_OVERHEAD + 100));
+        assert_ne!(buf[APP_HEADER_LENGTH + CHECKSUM_LENGTH + NONCE_LENGTH..][..100], packet.payload[..]);
+        let decoded = PacketStruct::decode(&buf, Some(&cipher)).unwrap();
+        assert!(decoded.encrypted);
+        assert_same(&decoded, &packet);
+    }
+
+    #[test]
+    fn rejects_key_mismatch() {
+        let cipher = cipher();
+        let mut packet = packet(100);
+        assert_eq!(PacketStruct::decode(&packet.to_bytes().unwrap(), Some(&cipher)).unwrap_err(), PacketError::NotEncrypted);
+        packet.encrypted = true;
+        assert_eq!(packet.to_bytes().unwrap_err(), PacketError::MissingKey);
+        let mut buf = vec![0u8; packet.wire_length()];
+        packet.encode(&mut buf, Some(&cipher)).unwrap();
+        assert_eq!(PacketStruct::decode(&buf, None).unwrap_err(), PacketError::MissingKey);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let cipher = cipher();
+        let mut packet = packet(100);
+        packet.encrypted = true;
+        let mut buf = vec![0u8; packet.wire_length()];
+        packet.encode(&mut buf, Some(&cipher)).unwrap();
+        // the header is authenticated as well as the payload and the tag
+        for i in [4, APP_HEADER_LENGTH, APP_HEADER_LENGTH + NONCE_LENGTH, buf.len() - 1] {
+            let mut tampered = buf.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(PacketStruct::decode(&tampered, Some(&cipher)).unwrap_err(), PacketError::DecryptionFailed);
+        }
+        let mut buf = buf[..buf.len() - 1].to_vec();
+        assert_eq!(PacketStruct::decode(&buf, Some(&cipher)).unwrap_err(),
+            PacketError::Truncated { length: 100 + TAG_LENGTH, available: 100 + TAG_LENGTH - 1 });
+        buf[3] = (APP_HEADER_LENGTH + NONCE_LENGTH - 1) as u8;
+        buf.push(0);
+        assert_eq!(PacketStruct::decode(&buf, Some(&cipher)).unwrap_err(), PacketError::BadHeaderLength(buf[3]));
+    }
+
+    fn rtp_packet(length: u16) -> PacketStruct {
+        let mut packet = packet(length);
+        packet.rtp = Some(RtpHeader { payload_type: 96, sequence: 0xFFFF, timestamp: 90_000, ssrc: 0x1234_5678, marker: true });
+        packet
+    }
+
+    #[test]
+    fn rtp_roundtrip() {
+        let mut packet = rtp_packet(100);
+        packet.checksum = true;
+        let buf = packet.to_bytes().unwrap();
+        assert_eq!(buf.len(), RTP_OVERHEAD + APP_HEADER_LENGTH + CHECKSUM_LENGTH + 100);
+        // a plain RTP packet to third parties, the native header as its extension
+        assert_eq!(buf[0], 0x90);
+        assert_eq!(buf[1], 0x80 | 96);
+        assert_eq!(buf[2..4], [0xFF, 0xFF]);
+        assert_eq!(RtpHeader::decode(&buf), Some((packet.rtp.unwrap(), RTP_HEADER_LENGTH)));
+        assert_eq!(u16::from_be_bytes([buf[14], buf[15]]) as usize * 4, APP_HEADER_LENGTH + CHECKSUM_LENGTH);
+
+        let decoded = PacketStruct::decode(&buf, None).unwrap();
+        assert_eq!(decoded.rtp, packet.rtp);
+        assert_same(&decoded, &packet);
+    }
+
+    #[test]
+    fn rtp_skips_csrcs() {
+        let mut buf = [0u8; RTP_HEADER_LENGTH + 8 + 4];
+        buf[0] = RTP_VERSION << 6 | 2;
+        assert_eq!(RtpHeader::decode(&buf).map(|(_, start)| start), Some(RTP_HEADER_LENGTH + 8));
+        assert_eq!(RtpHeader::decode(&buf[..RTP_HEADER_LENGTH + 7]), None);
+        buf[0] = 1 << 6;
+        assert_eq!(RtpHeader::decode(&buf), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rtp() {
+        let buf = rtp_packet(100).to_bytes().unwrap();
+        assert_eq!(PacketStruct::decode(&buf[..RTP_HEADER_LENGTH - 1], None).unwrap_err(), PacketError::TooShort(RTP_HEADER_LENGTH - 1));
+        // without an extension, of another profile, of another length, or too short for one
+        let mut without = buf.clone();
+        without[0] &= !0x10;
+        let mut profile = buf.clone();
+        profile[12] = 0xBE;
+        let mut words = buf.clone();
+        words[15] += 1;
+        for invalid in [&without[..], &profile, &words, &buf[..RTP_OVERHEAD + 3]] {
+            assert_eq!(PacketStruct::decode(invalid, None).unwrap_err(), PacketError::InvalidRtp);
+        }
+        // the native header is still checked
+        let mut magic = buf.clone();
+        magic[RTP_OVERHEAD] = 0;
+        assert_eq!(PacketStruct::decode(&magic, None).unwrap_err(), PacketError::BadMagic(0x5300));
+        let mut buf = [0u8; RTP_OVERHEAD - 1];
+        assert_eq!(rtp_packet(0).encode(&mut buf, None).unwrap_err(),
+            PacketError::BufferTooSmall { required: RTP_OVERHEAD + APP_HEADER_LENGTH, available: RTP_OVERHEAD - 1 });
+    }
+
+    #[test]
+    fn nack_roundtrip() {
+        let mut packet = packet(0).header();
+        packet.set_nack(&[1, 4, 7], Some(9));
+        let decoded = PacketStruct::decode(&packet.to_bytes().unwrap(), None).unwrap();
+        assert_eq!(decoded.nacked(), Some((vec![1, 4, 7], Some(9))));
+        packet.set_nack(&[2], None);
+        let decoded = PacketStruct::decode(&packet.to_bytes().unwrap(), None).unwrap();
+        assert_eq!(decoded.nacked(), Some((vec![2], None)));
+    }
+}
@@ -0,0 +1,536 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+// pcapng blocks, see https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
+const SECTION_HEADER_BLOCK:u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK:u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK:u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC:u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW:u16 = 101; // IPv4 or IPv6, without link-layer header
+const OPT_END:u16 = 0;
+const OPT_IF_NAME:u16 = 2;
+const IP_TTL:u8 = 64;
+const IPPROTO_UDP:u8 = 17;
+
+/// pcapng capture of UDP datagrams, one interface per link.
+/// IP and UDP headers are synthesized around each datagram, so no raw socket (nor root) is needed.
+pub struct PcapWriter {
+    file: File,
+}
+
+impl PcapWriter {
+    pub fn create(path: &str, interfaces: &[String]) -> io::Result<Self> {
+        let mut writer = Self { file: File::create(path)? };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // major version
+        body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        body.extend_from_slice(&(-1i64).to_le_bytes()); // section length, unspecified
+        writer.write_block(SECTION_HEADER_BLOCK, &body)?;
+
+        for name in interfaces {
+            let mut body = Vec::new();
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+            body.extend_from_slice(&0u32.to_le_bytes()); // snaplen, unlimited
+            push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+            push_option(&mut body, OPT_END, &[]);
+            writer.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)?;
+        }
+        Ok(writer)
+    }
+
+    /// Record a datagram sent from `src` to `dst`, timestamped now.
+    pub fn write(&mut self, interface: u32, src: SocketAddr, dst: SocketAddr, tos: u8, datagram: &[u8]) -> io::Result<()> {
+        let packet = ip_udp_packet(src, dst, tos, datagram);
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        body.extend_from_slice(&packet);
+        body.resize(body.len().next_multiple_of(4), 0);
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    // one write per block, so that a capture cut short by exiting stays readable
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let total_length = (12 + body.len()) as u32;
+        let mut block = Vec::with_capacity(total_length as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&total_length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&total_length.to_le_bytes());
+        self.file.write_all(&block)
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+// an unspecified address (e.g. of a wildcard socket) takes the family of the other end
+fn ip_pair(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+    let (src, dst) = (src.to_canonical(), dst.to_canonical());
+    match (src, dst) {
+        (IpAddr::V4(_), IpAddr::V6(v6)) if v6.is_unspecified() => (src, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        (IpAddr::V6(v6), IpAddr::V4(_)) if v6.is_unspecified() => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), dst),
+        _ => (src, dst),
+    }
+}
+
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+// ones' complement sum, folded
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            sum += u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, tos: u8, datagram: &[u8]) -> Vec<u8> {
+    let udp_length = (8 + datagram.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_length as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_length.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(datagram);
+
+    let mut packet = Vec::with_capacity(40 + udp.len());
+    match ip_pair(src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let pseudo = [&src.octets()[..], &dst.octets()[..], &[0, IPPROTO_UDP], &udp_length.to_be_bytes()].concat();
+            let udp_checksum = match checksum(&[&pseudo, &udp]) { 0 => 0xFFFF, c => c };
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+            packet.extend_from_slice(&[0x45, tos]);
+            packet.extend_from_slice(&(20 + udp_length).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, IP_TTL, IPPROTO_UDP, 0, 0]); // id, DF, ttl, protocol, checksum
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let ip_checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let (src, dst) = (to_ipv6(src), to_ipv6(dst));
+            let pseudo = [&src.octets()[..], &dst.octets()[..], &(udp_length as u32).to_be_bytes(), &[0, 0, 0, IPPROTO_UDP]].concat();
+            let udp_checksum = match checksum(&[&pseudo, &udp]) { 0 => 0xFFFF, c => c };
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+            packet.extend_from_slice(&(0x6000_0000u32 | (tos as u32) << 20).to_be_bytes()); // version, traffic class
+            packet.extend_from_slice(&udp_length.to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, IP_TTL]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+    }
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+// capture file magic numbers, as read in little-endian
+const PCAP_MAGIC_MICROS:u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS:u32 = 0xA1B2_3C4D;
+const SIMPLE_PACKET_BLOCK:u32 = 0x0000_0003;
+const OPT_IF_TSRESOL:u16 = 9;
+// link types, see https://www.tcpdump.org/linktypes.html
+const LINKTYPE_NULL:u16 = 0;
+const LINKTYPE_ETHERNET:u16 = 1;
+const LINKTYPE_RAW_ALT:[u16; 2] = [12, 14];
+const LINKTYPE_LINUX_SLL:u16 = 113;
+const LINKTYPE_IPV4:u16 = 228;
+const LINKTYPE_IPV6:u16 = 229;
+const LINKTYPE_LINUX_SLL2:u16 = 276;
+const ETHERTYPE_IPV4:u16 = 0x0800;
+const ETHERTYPE_IPV6:u16 = 0x86DD;
+const ETHERTYPE_VLAN:[u16; 2] = [0x8100, 0x88A8];
+const IPPROTO_TCP:u8 = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    #[serde(rename = "udp")]
+    Udp,
+    #[serde(rename = "tcp")]
+    Tcp,
+}
+
+/// 5-tuple of the flow to replay, any field left empty matching everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FlowFilter {
+    #[serde(default)] pub protocol: Option<Protocol>,
+    #[serde(default)] pub src_ip: Option<IpAddr>,
+    #[serde(default)] pub src_port: Option<u16>,
+    #[serde(default)] pub dst_ip: Option<IpAddr>,
+    #[serde(default)] pub dst_port: Option<u16>,
+}
+
+impl FlowFilter {
+    fn matches(&self, protocol: Protocol, src: SocketAddr, dst: SocketAddr) -> bool {
+        let ip_matches = |filter: Option<IpAddr>, ip: IpAddr| filter.is_none_or(|f| f.to_canonical() == ip.to_canonical());
+        self.protocol.is_none_or(|p| p == protocol)
+            && ip_matches(self.src_ip, src.ip()) && self.src_port.is_none_or(|p| p == src.port())
+            && ip_matches(self.dst_ip, dst.ip()) && self.dst_port.is_none_or(|p| p == dst.port())
+    }
+}
+
+/// Transport payload of a captured packet.
+pub struct FlowPacket {
+    pub timestamp: f64,
+    pub size: usize,      // as sent, even if the capture was truncated
+    pub payload: Vec<u8>, // the captured part of the payload, up to `size` bytes
+}
+
+/// Packets of the flow matching `filter` in a pcap or pcapng file, in capture order.
+/// Packets without a transport payload (e.g. bare TCP ACKs) are skipped.
+pub fn read_flow(path: &str, filter: &FlowFilter) -> io::Result<Vec<FlowPacket>> {
+    let data = std::fs::read(path)?;
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg));
+    if data.len() < 4 {
+        return Err(invalid("not a capture file"));
+    }
+
+    let mut packets = Vec::new();
+    let mut push = |linktype: u16, timestamp: f64, frame: &[u8]| {
+        if let Some((protocol, src, dst, size, payload)) = parse_frame(linktype, frame) {
+            if size > 0 && filter.matches(protocol, src, dst) {
+                packets.push(FlowPacket { timestamp, size, payload: payload[..payload.len().min(size)].to_vec() });
+            }
+        }
+    };
+
+    let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    if magic == SECTION_HEADER_BLOCK {
+        read_pcapng(&data, &mut push).ok_or_else(|| invalid("malformed pcapng"))?;
+    } else {
+        read_pcap(&data, &mut push).ok_or_else(|| invalid("malformed pcap"))?;
+    }
+    Ok(packets)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at+2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at+4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+}
+
+fn read_pcap(data: &[u8], push: &mut impl FnMut(u16, f64, &[u8])) -> Option<()> {
+    let magic = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let (big_endian, nanos) = match magic {
+        PCAP_MAGIC_MICROS => (false, false),
+        PCAP_MAGIC_NANOS => (false, true),
+        _ if magic.swap_bytes() == PCAP_MAGIC_MICROS => (true, false),
+        _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+        _ => return None,
+    };
+    let reader = Reader { data, big_endian };
+    let linktype = reader.u32(20)? as u16;
+    let resolution = if nanos { 1e-9 } else { 1e-6 };
+
+    let mut at = 24;
+    while at + 16 <= data.len() {
+        let timestamp = reader.u32(at)? as f64 + reader.u32(at+4)? as f64 * resolution;
+        let captured = reader.u32(at+8)? as usize;
+        let frame = data.get(at+16..at+16+captured)?;
+        push(linktype, timestamp, frame);
+        at += 16 + captured;
+    }
+    Some(())
+}
+
+fn read_pcapng(data: &[u8], push: &mut impl FnMut(u16, f64, &[u8])) -> Option<()> {
+    let mut reader = Reader { data, big_endian: false };
+    let mut interfaces: Vec<(u16, f64)> = Vec::new(); // (linktype, timestamp resolution)
+
+    let mut at = 0;
+    while at + 12 <= data.len() {
+        let block_type = reader.u32(at)?;
+        if block_type == SECTION_HEADER_BLOCK {
+            // a new section may switch byte order and resets the interfaces
+            reader.big_endian = u32::from_le_bytes(data.get(at+8..at+12)?.try_into().ok()?) != BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let length = reader.u32(at+4)? as usize;
+        if length < 12 || at + length > data.len() {
+            return None;
+        }
+        let body = at+8 .. at+length-4;
+
+        match block_type {
+            INTERFACE_DESCRIPTION_BLOCK => {
+                let linktype = reader.u16(body.start)?;
+                let mut resolution = 1e-6;
+                let mut option = body.start + 8;
+                while option + 4 <= body.end {
+                    let (code, len) = (reader.u16(option)?, reader.u16(option+2)? as usize);
+                    if code == OPT_END {
+                        break;
+                    }
+                    if code == OPT_IF_TSRESOL && len == 1 {
+                        let value = *data.get(option+4)?;
+                        let exponent = (value & 0x7F) as i32;
+                        resolution = if value & 0x80 == 0 { 10f64.powi(-exponent) } else { 2f64.powi(-exponent) };
+                    }
+                    option += 4 + len.next_multiple_of(4);
+                }
+                interfaces.push((linktype, resolution));
+            }
+            ENHANCED_PACKET_BLOCK => {
+                let (linktype, resolution) = *interfaces.get(reader.u32(body.start)? as usize)?;
+                let ticks = ((reader.u32(body.start+4)? as u64) << 32) | reader.u32(body.start+8)? as u64;
+                let captured = reader.u32(body.start+12)? as usize;
+                let frame = data.get(body.start+20..body.start+20+captured)?;
+                push(linktype, ticks as f64 * resolution, frame);
+            }
+            SIMPLE_PACKET_BLOCK => {} // no timestamp to replay
+            _ => {}
+        }
+        at += length;
+    }
+    Some(())
+}
+
+// (protocol, source, destination, payload size, captured payload) of a UDP or TCP packet
+fn parse_frame(linktype: u16, frame: &[u8]) -> Option<(Protocol, SocketAddr, SocketAddr, usize, &[u8])> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            let mut ethertype = u16::from_be_bytes(frame.get(at..at+2)?.try_into().ok()?);
+            while ETHERTYPE_VLAN.contains(&ethertype) {
+                at += 4;
+                ethertype = u16::from_be_bytes(frame.get(at..at+2)?.try_into().ok()?);
+            }
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return None;
+            }
+            frame.get(at+2..)?
+        }
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        _ if LINKTYPE_RAW_ALT.contains(&linktype) => frame,
+        _ => return None,
+    };
+
+    let (protocol, src, dst, transport) = match *ip.first()? >> 4 {
+        4 => {
+            let header_len = ((ip[0] & 0x0F) as usize) * 4;
+            let total_len = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?) as usize;
+            let fragment_offset = u16::from_be_bytes(ip.get(6..8)?.try_into().ok()?) & 0x1FFF;
+            if fragment_offset != 0 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            let transport = ip.get(header_len..total_len.max(header_len).min(ip.len()))?;
+            (ip[9], IpAddr::from(src), IpAddr::from(dst), (transport, total_len.saturating_sub(header_len)))
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?) as usize;
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            // skip hop-by-hop, routing and destination options headers
+            let (mut next, mut at) = (ip[6], 40);
+            while matches!(next, 0 | 43 | 60) {
+                next = *ip.get(at)?;
+                at += (*ip.get(at+1)? as usize + 1) * 8;
+            }
+            let transport = ip.get(at.min(ip.len())..)?;
+            (next, IpAddr::from(src), IpAddr::from(dst), (transport, (40 + payload_len).saturating_sub(at)))
+        }
+        _ => return None,
+    };
+
+    let (segment, segment_len) = transport;
+    let port = |at: usize| Some(u16::from_be_bytes(segment.get(at..at+2)?.try_into().ok()?));
+    let (src, dst) = (SocketAddr::new(src, port(0)?), SocketAddr::new(dst, port(2)?));
+    match protocol {
+        IPPROTO_UDP => {
+            let size = (port(4)? as usize).saturating_sub(8);
+            Some((Protocol::Udp, src, dst, size, segment.get(8..)?))
+        }
+        IPPROTO_TCP => {
+            let header_len = ((*segment.get(12)? >> 4) as usize) * 4;
+            let size = segment_len.saturating_sub(header_len);
+            Some((Protocol::Tcp, src, dst, size, segment.get(header_len.min(segment.len())..)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn checksums_verify() {
+        let packet = ip_udp_packet(addr("10.0.0.1:5202"), addr("10.0.0.2:15000"), 0x80, &[1, 2, 3, 4, 5]);
+        assert_eq!(packet.len(), 20 + 8 + 5);
+        assert_eq!((packet[0], packet[1], packet[9]), (0x45, 0x80, IPPROTO_UDP));
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        let pseudo = [&packet[12..20], &[0, IPPROTO_UDP], &packet[24..26]].concat();
+        assert_eq!(checksum(&[&pseudo, &packet[20..]]), 0);
+    }
+
+    #[test]
+    fn checksums_verify_ipv6() {
+        let packet = ip_udp_packet(addr("[fd00::1]:5202"), addr("[fd00::2]:15000"), 0x80, &[1, 2, 3, 4, 5]);
+        assert_eq!(packet.len(), 40 + 8 + 5);
+        assert_eq!(u32::from_be_bytes(packet[..4].try_into().unwrap()), 0x6800_0000);
+        let pseudo = [&packet[8..40], &[0, 0], &packet[44..46], &[0, 0, 0, IPPROTO_UDP]].concat();
+        assert_eq!(checksum(&[&pseudo, &packet[40..]]), 0);
+    }
+
+    #[test]
+    fn wildcard_takes_peer_family() {
+        // a dual-stack socket bound to "::" sending to an IPv4 receiver
+        let packet = ip_udp_packet(addr("[::]:5202"), addr("10.0.0.2:15000"), 0, &[]);
+        assert_eq!(packet.len(), 20 + 8);
+        assert_eq!(packet[12..20], [0, 0, 0, 0, 10, 0, 0, 2]);
+        let packet = ip_udp_packet(addr("[::ffff:10.0.0.1]:5202"), addr("[fd00::2]:15000"), 0, &[]);
+        assert_eq!(packet.len(), 40 + 8);
+        assert_eq!(packet[8..24], Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().octets());
+    }
+
+    #[test]
+    fn writes_blocks() {
+        let path = temp_path("blocks.pcapng");
+        let mut writer = PcapWriter::create(&path, &["link 0".into(), "link 1".into()]).unwrap();
+        writer.write(1, addr("10.0.0.1:5202"), addr("10.0.0.2:15000"), 0, &[1; 5]).unwrap();
+        drop(writer);
+
+        let data = std::fs::read(&path).unwrap();
+        let mut blocks = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let length = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(length % 4, 0);
+            assert_eq!(rest[length - 4..length], rest[4..8]);
+            blocks.push((block_type, rest[8..length - 4].to_vec()));
+            rest = &rest[length..];
+        }
+        let types: Vec<_> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, [SECTION_HEADER_BLOCK, INTERFACE_DESCRIPTION_BLOCK, INTERFACE_DESCRIPTION_BLOCK, ENHANCED_PACKET_BLOCK]);
+        assert_eq!(blocks[0].1[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(blocks[1].1[..2], LINKTYPE_RAW.to_le_bytes());
+
+        let body = &blocks[3].1;
+        let packet = ip_udp_packet(addr("10.0.0.1:5202"), addr("10.0.0.2:15000"), 0, &[1; 5]);
+        assert_eq!(body[..4], 1u32.to_le_bytes());
+        assert_eq!(body[12..16], (packet.len() as u32).to_le_bytes());
+        assert_eq!(body[20..20 + packet.len()], packet[..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_written_pcapng() {
+        let path = temp_path("written.pcapng");
+        let mut writer = PcapWriter::create(&path, &["link 0".into(), "link 1".into()]).unwrap();
+        writer.write(0, addr("10.0.0.1:5202"), addr("10.0.0.2:15000"), 0, &[1; 100]).unwrap();
+        writer.write(1, addr("[::]:5202"), addr("[fd00::2]:15000"), 0, &[2; 50]).unwrap();
+        writer.write(0, addr("10.0.0.1:5203"), addr("10.0.0.2:15001"), 0, &[3; 10]).unwrap();
+        drop(writer);
+
+        let all = read_flow(&path, &FlowFilter::default()).unwrap();
+        assert_eq!(all.iter().map(|p| p.size).collect::<Vec<_>>(), [100, 50, 10]);
+        assert_eq!(all[1].payload, [2; 50]);
+        assert!(all[0].timestamp > 0.0 && all[0].timestamp <= all[2].timestamp);
+
+        let filter = FlowFilter { protocol: Some(Protocol::Udp), dst_port: Some(15000), ..Default::default() };
+        assert_eq!(read_flow(&path, &filter).unwrap().len(), 2);
+        let filter = FlowFilter { dst_ip: Some("fd00::2".parse().unwrap()), ..Default::default() };
+        assert_eq!(read_flow(&path, &filter).unwrap()[0].payload, [2; 50]);
+        let filter = FlowFilter { src_ip: Some("::ffff:10.0.0.1".parse().unwrap()), src_port: Some(5203), ..Default::default() };
+        assert_eq!(read_flow(&path, &filter).unwrap()[0].payload, [3; 10]);
+        let filter = FlowFilter { protocol: Some(Protocol::Tcp), ..Default::default() };
+        assert!(read_flow(&path, &filter).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Ethernet with a VLAN tag, IPv4 with options, TCP with options, truncated capture
+    fn tcp_frame() -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x81, 0x00, 0, 1, 0x08, 0x00]);
+        let payload = [7u8; 40];
+        let total_len = (24 + 24 + payload.len()) as u16;
+        frame.extend_from_slice(&[0x46, 0]);
+        frame.extend_from_slice(&total_len.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, IP_TTL, IPPROTO_TCP, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2, 1, 1, 1, 1]);
+        frame.extend_from_slice(&5202u16.to_be_bytes());
+        frame.extend_from_slice(&443u16.to_be_bytes());
+        frame.extend_from_slice(&[0; 8]);
+        frame.extend_from_slice(&[6 << 4, 0x18, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1]);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    #[test]
+    fn reads_big_endian_pcap() {
+        let frame = tcp_frame();
+        let captured = frame.len() - 30;
+        let mut data = Vec::new();
+        data.extend_from_slice(&PCAP_MAGIC_NANOS.to_be_bytes());
+        data.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+        data.extend_from_slice(&(LINKTYPE_ETHERNET as u32).to_be_bytes());
+        for (seconds, nanos) in [(10u32, 500_000_000u32), (11, 0)] {
+            data.extend_from_slice(&seconds.to_be_bytes());
+            data.extend_from_slice(&nanos.to_be_bytes());
+            data.extend_from_slice(&(captured as u32).to_be_bytes());
+            data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            data.extend_from_slice(&frame[..captured]);
+        }
+        let path = temp_path("big-endian.pcap");
+        std::fs::write(&path, &data).unwrap();
+
+        let filter = FlowFilter { protocol: Some(Protocol::Tcp), dst_port: Some(443), ..Default::default() };
+        let flow = read_flow(&path, &filter).unwrap();
+        assert_eq!(flow.iter().map(|p| p.timestamp).collect::<Vec<_>>(), [10.5, 11.0]);
+        assert_eq!(flow[0].size, 40);
+        assert_eq!(flow[0].payload, [7; 10]);
+
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(read_flow(&path, &filter).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        std::fs::write(&path, [0u8; 24]).unwrap();
+        assert_eq!(read_flow(&path, &filter).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+/// Bytes of a QUIC packet beyond the payload of its DATAGRAM frame (short header with an 8-byte
+/// connection ID and the longest packet number, AEAD tag, frame type and length).
+pub const QUIC_DATAGRAM_OVERHEAD: usize = 1 + 8 + 4 + 16 + 1 + 8;
+/// Concurrent unidirectional streams opened by the peer, one per frame in flight.
+const MAX_CONCURRENT_STREAMS: u32 = 1024;
+const DATAGRAM_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+const MIN_INITIAL_MTU: u16 = 1200;     // of QUIC (RFC 9000, Section 14)
+/// Name in the self-signed certificate of the receiver.
+pub const SERVER_NAME: &str = "stream-replay";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuicMapping {
+    /// Each fragment in its own DATAGRAM frame, unreliable.
+    #[default]
+    #[serde(rename = "datagram")]
+    Datagram,
+    /// The fragments of each frame on a unidirectional stream of its own, length-prefixed as over TCP.
+    #[serde(rename = "stream")]
+    Stream,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn transport_config(initial_mtu: Option<u16>) -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_uni_streams(MAX_CONCURRENT_STREAMS.into())
+        .datagram_receive_buffer_size(Some(DATAGRAM_BUFFER_SIZE))
+        .datagram_send_buffer_size(DATAGRAM_BUFFER_SIZE)
+        .keep_alive_interval(Some(Duration::from_secs(1)));
+    if let Some(mtu) = initial_mtu {
+        transport.initial_mtu(mtu.max(MIN_INITIAL_MTU));
+    }
+    Arc::new(transport)
+}
+
+/// Receiver configuration, with a freshly generated self-signed certificate.
+pub fn server_config() -> Result<quinn::ServerConfig, String> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).map_err(|e| e.to_string())?;
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+    let crypto = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13]).map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der], key.into()).map_err(|e| e.to_string())?;
+    let crypto = QuicServerConfig::try_from(crypto).map_err(|e| e.to_string())?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config(None));
+    Ok(config)
+}
+
+/// Transmitter configuration, accepting the self-signed certificate of any receiver;
+/// payloads are authenticated end to end only with a pre-shared `key`.
+pub fn client_config(initial_mtu: Option<u16>) -> Result<quinn::ClientConfig, String> {
+    let crypto = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13]).map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCert(provider())))
+        .with_no_client_auth();
+    let crypto = QuicClientConfig::try_from(crypto).map_err(|e| e.to_string())?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config(initial_mtu));
+    Ok(config)
+}
+
+/// Single-threaded runtime driving the QUIC endpoints of a thread.
+pub fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread().enable_all().build()
+}
+
+// Certificate verifier checking the handshake signatures only.
+#[derive(Debug)]
+struct AnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn handshake() {
+        runtime().unwrap().block_on(async {
+            let server = quinn::Endpoint::server(server_config().unwrap(), SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+            let addr = server.local_addr().unwrap();
+            let accepted = tokio::spawn(async move {
+                let connection = server.accept().await.unwrap().await.unwrap();
+                connection.read_datagram().await.unwrap()
+            });
+
+            let mut client = quinn::Endpoint::client(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+            // below the minimum of QUIC
+            client.set_default_client_config(client_config(Some(500)).unwrap());
+            let connection = client.connect(addr, SERVER_NAME).unwrap().await.unwrap();
+            assert!(connection.max_datagram_size().is_some_and(|size| size >= QUIC_DATAGRAM_OVERHEAD));
+            connection.send_datagram(b"ping".to_vec().into()).unwrap();
+            assert_eq!(&accepted.await.unwrap()[..], b"ping");
+        });
+    }
+}
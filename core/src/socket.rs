@@ -1,16 +1,176 @@
-use std::net::UdpSocket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+/// Length prefix of each datagram carried over a TCP stream (u32, little-endian).
+pub const TCP_LENGTH_PREFIX: usize = 4;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Bind a UDP socket on `ipaddr:port`. The unspecified address ("0.0.0.0" or "::") binds
+/// dual-stack where the host supports IPv6 (default `bindv6only=0`), and IPv4 only otherwise.
+pub fn bind_udp_socket(ipaddr: &str, port: u16) -> std::io::Result<UdpSocket> {
+    match ipaddr.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => {
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+                .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)))
+        }
+        _ => UdpSocket::bind((ipaddr, port)),
+    }
+}
+
+/// Listen for TCP connections on `ipaddr:port`, dual-stack as `bind_udp_socket`. The listener
+/// is an MPTCP one where the kernel supports it, accepting plain TCP connections as well.
+pub fn bind_tcp_listener(ipaddr: &str, port: u16) -> std::io::Result<TcpListener> {
+    match ipaddr.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => {
+            listen_stream(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+                .or_else(|_| listen_stream(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)))
+        }
+        Ok(ip) => listen_stream(SocketAddr::new(ip, port)),
+        Err(_) => TcpListener::bind((ipaddr, port)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn listen_stream(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let listen = |protocol: Protocol| -> std::io::Result<TcpListener> {
+        let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(protocol))?;
+        sock.set_reuse_address(true)?;
+        sock.bind(&addr.into())?;
+        sock.listen(128)?;
+        Ok(sock.into())
+    };
+    listen(Protocol::MPTCP).or_else(|_| listen(Protocol::TCP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen_stream(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    TcpListener::bind(addr)
+}
+
+#[cfg(unix)]
+fn connect_stream(protocol: socket2::Protocol, tos: u8, tx_ipaddr: &str, rx_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    use socket2::{Domain, Socket, Type};
+
+    let tx_ip = tx_ipaddr.parse::<IpAddr>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let sock = Socket::new(Domain::for_address(rx_addr), Type::STREAM, Some(protocol))?;
+    if !tx_ip.is_unspecified() {
+        sock.bind(&SocketAddr::new(tx_ip, 0).into())?;
+    }
+    match rx_addr {
+        SocketAddr::V4(_) => sock.set_tos(tos as u32)?,
+        SocketAddr::V6(_) => sock.set_tclass_v6(tos as u32)?,
+    }
+    sock.set_nodelay(true)?;
+    sock.connect_timeout(&rx_addr.into(), TCP_CONNECT_TIMEOUT)?;
+    Ok(sock.into())
+}
+
+/// Connect from `tx_ipaddr` to `rx_addr` with Nagle's algorithm disabled, marking the traffic with `tos`.
+#[cfg(unix)]
+pub fn create_tcp_stream(tos: u8, tx_ipaddr: &str, rx_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = connect_stream(socket2::Protocol::TCP, tos, tx_ipaddr, rx_addr)?;
+    println!("tx_ipaddr: {} (TCP)", tx_ipaddr);
+    Ok(stream)
+}
+
+#[cfg(windows)]
+pub fn create_tcp_stream(_tos: u8, _tx_ipaddr: &str, rx_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&rx_addr, TCP_CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// As `create_tcp_stream` over kernel MPTCP, the initial subflow leaving from `tx_ipaddr`.
+/// Further subflows are created by the kernel path manager, per its configured endpoints.
+#[cfg(target_os = "linux")]
+pub fn create_mptcp_stream(tos: u8, tx_ipaddr: &str, rx_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = connect_stream(socket2::Protocol::MPTCP, tos, tx_ipaddr, rx_addr)?;
+    println!("tx_ipaddr: {} (MPTCP)", tx_ipaddr);
+    Ok(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn create_mptcp_stream(_tos: u8, _tx_ipaddr: &str, _rx_addr: SocketAddr) -> std::io::Result<TcpStream> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "MPTCP is only available on Linux"))
+}
+
+/// Kernel view of a TCP connection, as sampled from `TCP_INFO`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct TcpInfo {
+    pub cwnd: u32,          // segments
+    pub srtt: f64,          // seconds
+    pub rttvar: f64,        // seconds
+    pub retransmits: u32,   // total retransmitted segments
+    pub delivery_rate: f64, // Mbps
+    pub pacing_rate: f64,   // Mbps
+}
+
+// Leading fields of the kernel's `struct tcp_info` (linux/tcp.h), up to `tcpi_delivery_rate`
+// which the libc definition lacks; older kernels fill only a prefix of it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+#[derive(Default)]
+struct RawTcpInfo {
+    state: [u8; 8],
+    rto: u32, ato: u32, snd_mss: u32, rcv_mss: u32,
+    unacked: u32, sacked: u32, lost: u32, retrans: u32, fackets: u32,
+    last_data_sent: u32, last_ack_sent: u32, last_data_recv: u32, last_ack_recv: u32,
+    pmtu: u32, rcv_ssthresh: u32, rtt: u32, rttvar: u32, snd_ssthresh: u32, snd_cwnd: u32,
+    advmss: u32, reordering: u32, rcv_rtt: u32, rcv_space: u32, total_retrans: u32,
+    pacing_rate: u64, max_pacing_rate: u64, bytes_acked: u64, bytes_received: u64,
+    segs_out: u32, segs_in: u32, notsent_bytes: u32, min_rtt: u32, data_segs_in: u32, data_segs_out: u32,
+    delivery_rate: u64,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn tcp_info(stream: &TcpStream) -> std::io::Result<TcpInfo> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut raw = RawTcpInfo::default();
+    let mut len = std::mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+            &mut raw as *mut RawTcpInfo as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // rates in bytes per second, unset as u64::MAX
+    let to_mbps = |rate: u64| if rate == u64::MAX { 0.0 } else { rate as f64 * 8.0 / 1e6 };
+    Ok(TcpInfo {
+        cwnd: raw.snd_cwnd,
+        srtt: raw.rtt as f64 / 1e6,
+        rttvar: raw.rttvar as f64 / 1e6,
+        retransmits: raw.total_retrans,
+        delivery_rate: to_mbps(raw.delivery_rate),
+        pacing_rate: to_mbps(raw.pacing_rate),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn tcp_info(_stream: &TcpStream) -> std::io::Result<TcpInfo> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "TCP_INFO is only available on Linux"))
+}
 
 #[cfg(unix)]
 pub fn create_udp_socket(tos: u8, tx_ipaddr: String) -> Option<UdpSocket> {
     use std::os::unix::io::AsRawFd;
 
-    let sock = UdpSocket::bind(format!("{}:0", tx_ipaddr)).ok()?;
-    print!("tx_ipaddr: {}\n", tx_ipaddr);
+    let sock = UdpSocket::bind((tx_ipaddr.as_str(), 0)).ok()?;
+    println!("tx_ipaddr: {}", tx_ipaddr);
+    let (level, option) = match sock.local_addr().ok()? {
+        std::net::SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+        std::net::SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
     let res = unsafe{
         let fd = sock.as_raw_fd();
         let value = &(tos as i32) as *const libc::c_int as *const libc::c_void;
         let option_len = std::mem::size_of::<libc::c_int>() as u32;
-        libc::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, value, option_len)
+        libc::setsockopt(fd, level, option, value, option_len)
     };
     
     if res == 0 { Some(sock) } else { None }
@@ -82,4 +242,90 @@ pub fn create_udp_socket(tos: u8) -> Option<UdpSocket> {
         let sock = UdpSocket::from_raw_socket( raw_sock.0 as u64 );
         Some(sock)
     }
-}
\ No newline at end of file
+}
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn roundtrip(receiver: &UdpSocket, tx_ipaddr: &str, rx_ipaddr: &str) {
+        let port = receiver.local_addr().unwrap().port();
+        let sender = create_udp_socket(192, String::from(tx_ipaddr)).unwrap();
+        sender.send_to(b"ping", (rx_ipaddr, port)).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+    }
+
+    #[test]
+    fn dual_stack() {
+        let receiver = bind_udp_socket("::", 0).unwrap();
+        receiver.set_read_timeout(Some(std::time::Duration::from_secs(1))).unwrap();
+        roundtrip(&receiver, "127.0.0.1", "127.0.0.1");
+        if receiver.local_addr().unwrap().is_ipv6() {
+            roundtrip(&receiver, "::1", "::1");
+        }
+    }
+
+    #[test]
+    fn binds_given_address() {
+        let receiver = bind_udp_socket("127.0.0.1", 0).unwrap();
+        assert_eq!(receiver.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert!(create_udp_socket(192, String::from("not an address")).is_none());
+    }
+
+    #[test]
+    fn tcp_connects() {
+        use std::io::{Read, Write};
+
+        let listener = bind_tcp_listener("::", 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut stream = create_tcp_stream(192, "127.0.0.1", SocketAddr::from((Ipv4Addr::LOCALHOST, port))).unwrap();
+        assert!(stream.nodelay().unwrap());
+        stream.write_all(b"ping").unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(create_tcp_stream(192, "localhost", SocketAddr::from((Ipv4Addr::LOCALHOST, port))).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn samples_tcp_info() {
+        use std::io::Write;
+
+        let listener = bind_tcp_listener("127.0.0.1", 0).unwrap();
+        let mut stream = create_tcp_stream(0, "0.0.0.0", listener.local_addr().unwrap()).unwrap();
+        let (_accepted, _) = listener.accept().unwrap();
+        stream.write_all(&[0u8; 1000]).unwrap();
+        let info = tcp_info(&stream).unwrap();
+        assert!(info.cwnd > 0);
+        assert!(info.srtt > 0.0 && info.srtt < 1.0);
+        assert_eq!(info.retransmits, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mptcp_connects() {
+        use std::io::{Read, Write};
+
+        let listener = bind_tcp_listener("127.0.0.1", 0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // kernels without MPTCP refuse the protocol, the listener falling back to plain TCP
+        let mut stream = match create_mptcp_stream(0, "127.0.0.1", addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                assert_eq!(e.raw_os_error(), Some(libc::EPROTONOSUPPORT));
+                return;
+            }
+        };
+        stream.write_all(b"ping").unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        // plain TCP accepted as well
+        create_tcp_stream(0, "127.0.0.1", addr).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+}
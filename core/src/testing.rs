@@ -0,0 +1,19 @@
+//! Fixtures shared by the unit tests of the workspace crates.
+
+use crate::packet::PacketStruct;
+
+/// Path of a scratch file in the temporary directory, unique to this test process.
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("stream-replay-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+}
+
+/// The `num` fragments of frame `seq` to port 5202, each of `length` bytes of payload.
+pub fn frame(seq: u32, num: usize, length: u16) -> Vec<PacketStruct> {
+    (0..num).map(|offset| {
+        let mut packet = PacketStruct::new(5202, length as usize);
+        packet.seq = seq;
+        packet.set_offset(offset as u16);
+        packet.set_length(length);
+        packet
+    }).collect()
+}
@@ -1,14 +1,20 @@
-use std::net::UdpSocket;
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use std::sync::{Arc, Mutex};
 use clap::Parser;
 use log::trace;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
 
 use crate::record::{RecvData, RecvRecord};
-use core::packet::{self, PacketType};
+use core::packet::{PacketError, PacketStruct, FRAME_COMPLETE, MAX_DATAGRAM_LENGTH, RTP_CLOCK_RATE};
 use core::socket::*;
+use core::crypto::PayloadCipher;
+use core::pcap::PcapWriter;
+use core::quic;
 
 const PONG_PORT_INC: u16 = 1024;
+const NACK_WINDOW: u32 = 64; // frames ahead of the latest one, still worth a NACK
+const MAX_NACKS: u32 = 8;    // per frame, the transmitter bounding its retransmissions anyway
+pub const NACK_INTERVAL: f64 = 0.01;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about=None)]
@@ -21,89 +27,339 @@ pub struct Args {
     pub rx_mode: bool,
     #[clap(long, default_value_t = 1)]
     pub sample_rate: u32,
+    /// Unused, the path of each packet is carried in its header.
     #[clap(long)]
     pub src_ipaddrs: Vec<String>,
+    /// Pre-shared key (64 hex digits) to decrypt payloads; unencrypted packets are then rejected.
+    #[clap(long)]
+    pub key: Option<String>,
+    /// Write the received datagrams, rejected ones included, to `logs/rx-<port>.pcapng`.
+    #[clap(long)]
+    pub pcap: bool,
+    /// Also accept TCP connections on the port, MPTCP ones included, for `TCP` and `MPTCP` streams.
+    #[clap(long)]
+    pub tcp: bool,
+    /// Accept QUIC connections on the port instead of plain UDP datagrams.
+    #[clap(long)]
+    pub quic: bool,
+    /// Analyze a third-party RTP stream, its frames delimited by timestamp and marker bit, without ACKs.
+    #[clap(long)]
+    pub rtp: bool,
+    /// RTP timestamp clock rate (Hz) of the third-party stream, for its jitter.
+    #[clap(long, default_value_t = RTP_CLOCK_RATE)]
+    pub rtp_clock_rate: f64,
+    /// NACK the missing fragments of the frames ahead of the latest one, along with the ACKs of `--calc-rtt`.
+    #[clap(long)]
+    pub nack: bool,
+    /// Silence (s) of an incomplete frame before each NACK, e.g. for its fragments on a slower path.
+    #[clap(long, default_value_t = NACK_INTERVAL)]
+    pub nack_interval: f64,
+}
+
+/// ACK senders for IPv4 and IPv6 transmitters, the data socket being dual-stack.
+struct PongSockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl PongSockets {
+    fn new(tos: u8) -> Self {
+        let create = |addr: &str| {
+            let sock = create_udp_socket(tos, String::from(addr))?;
+            sock.set_nonblocking(true).ok()?;
+            Some(sock)
+        };
+        Self { v4: create("0.0.0.0"), v6: create("::") }
+    }
+
+    fn is_available(&self) -> bool {
+        self.v4.is_some() || self.v6.is_some()
+    }
+
+    fn get(&self, addr: &SocketAddr) -> Option<&UdpSocket> {
+        match addr {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+/// Handling of the datagrams, whether received over UDP or TCP.
+struct Receiver {
+    args: Args,
+    cipher: Option<PayloadCipher>,
+    pong_sockets: PongSockets,
+    recv_params: Arc<Mutex<RecvData>>,
+    lock: Arc<Mutex<bool>>,
+}
+
+impl Receiver {
+    fn handle_datagram(&self, buffer: &[u8], src_addr: SocketAddr) {
+        if self.args.rtp {
+            self.handle_rtp(buffer, src_addr);
+            return;
+        }
+        let mut data = self.recv_params.lock().unwrap();
+        let packet = match PacketStruct::decode(buffer, self.cipher.as_ref()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                trace!("Rejected: Time {} -> from {}: {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), src_addr, e);
+                // the fragment is discarded, its frame still counting in the packet loss rate
+                match e {
+                    PacketError::ChecksumMismatch { .. } => data.corrupted += 1,
+                    _ => data.malformed += 1,
+                }
+                return;
+            }
+        };
+        data.data_len += buffer.len() as u32;
+        self.start(&mut data);
+
+        if self.args.calc_rtt || self.args.nack {
+            handle_rtt(&self.args, packet, &mut data, &self.pong_sockets, &src_addr);
+        }
+    }
+
+    // Frames of a third-party RTP stream, numbered in order as native sequence numbers.
+    fn handle_rtp(&self, buffer: &[u8], src_addr: SocketAddr) {
+        let mut data = self.recv_params.lock().unwrap();
+        let time_now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+        let frames = match data.rtp.record(buffer, time_now) {
+            Ok(frames) => frames,
+            Err(e) => {
+                trace!("Rejected: Time {} -> from {}: {}", time_now, src_addr, e);
+                data.malformed += 1;
+                return;
+            }
+        };
+        data.data_len += buffer.len() as u32;
+        self.start(&mut data);
+
+        data.last_seq = data.rtp.last_number();
+        for (number, payload) in frames {
+            trace!("Frame: Time {} -> seq: {}, length: {}", time_now, number, payload.len());
+            data.stutter.update(time_now);
+            if self.args.rx_mode {
+                if let Some(ref tx) = data.tx {
+                    tx.send(payload).unwrap();
+                }
+            }
+            data.recevied += 1;
+        }
+    }
+
+    fn start(&self, data: &mut RecvData) {
+        let mut started = self.lock.lock().unwrap();
+        if !*started {
+            *started = true;
+            println!("Start");
+            data.rx_start_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        *self.lock.lock().unwrap()
+    }
 }
 
 pub fn recv_thread(args: Args, recv_params: Arc<Mutex<RecvData>>, lock: Arc<Mutex<bool>>){
-    let addr = format!("0.0.0.0:{}", args.port);    
-    let socket = UdpSocket::bind(&addr).unwrap();
+    let cipher = match args.key.as_deref().map(PayloadCipher::from_hex) {
+        Some(None) => {
+            eprintln!("Invalid key, expecting 64 hex digits.");
+            return;
+        }
+        cipher => cipher.flatten(),
+    };
+    let socket = bind_udp_socket("::", args.port).unwrap();
     socket.set_nonblocking(true).unwrap();
-    let addr = format!("0.0.0.0");
-    // let pong_socket = UdpSocket::bind(&addr).unwrap();
-    let pong_socket = create_udp_socket(192, addr.clone());
-    if let Some(pong_socket) = pong_socket {
-        pong_socket.set_nonblocking(true).unwrap();
-        println!("Waiting ...");
-        let mut buffer = [0; 2048];
-        let mut started = false;
-        loop {
-            if let Ok((_len, src_addr)) = socket.recv_from(&mut buffer) {
-                let mut data = recv_params.lock().unwrap();
-                data.data_len += _len as u32;
-                if !started {
-                    *lock.lock().unwrap() = true;
-                    println!("Start");
-                    data.rx_start_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
-                    started = true;
+    let local_addr = socket.local_addr().unwrap();
+    if args.pcap && args.quic {
+        eprintln!("Pcap capture is only supported over UDP: port {}.", args.port);
+    }
+    let mut pcap = (args.pcap && !args.quic).then(|| {
+        let interfaces = [format!("rx {}", local_addr)];
+        PcapWriter::create(&format!("logs/rx-{}.pcapng", args.port), &interfaces)
+            .map_err(|e| eprintln!("Pcap creation failure: {}", e)).ok()
+    }).flatten();
+    let pong_sockets = PongSockets::new(192);
+    if !pong_sockets.is_available() {
+        eprintln!("Error creating pong socket");
+        return;
+    }
+
+    let port = args.port;
+    recv_params.lock().unwrap().rtp.set_clock_rate(args.rtp_clock_rate);
+    let receiver = Arc::new(Receiver { args, cipher, pong_sockets, recv_params, lock });
+    if receiver.args.tcp {
+        match bind_tcp_listener("::", port) {
+            Ok(listener) => {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || tcp_listener_thread(listener, receiver));
+            }
+            Err(e) => eprintln!("TCP listener failure on port {}: {}", port, e),
+        }
+    }
+
+    println!("Waiting ...");
+    if receiver.args.quic {
+        quic_recv_thread(socket, receiver);
+        return;
+    }
+    let mut buffer = [0; MAX_DATAGRAM_LENGTH];
+    loop {
+        if let Ok((_len, src_addr)) = socket.recv_from(&mut buffer) {
+            if let Some(ref mut pcap) = pcap {
+                if let Err(e) = pcap.write(0, src_addr, local_addr, 0, &buffer[.._len]) {
+                    eprintln!("Pcap write failure: {}", e);
+                }
+            }
+            receiver.handle_datagram(&buffer[.._len], src_addr);
+        } else if !receiver.is_started() {
+            std::thread::sleep(std::time::Duration::from_nanos(100_000));
+        }
+    }
+}
+
+fn quic_recv_thread(socket: UdpSocket, receiver: Arc<Receiver>) {
+    let config = match quic::server_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("QUIC configuration failure: {}", e);
+            return;
+        }
+    };
+    let runtime = quic::runtime().unwrap();
+    runtime.block_on(async move {
+        let endpoint = match quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(config), socket, Arc::new(quinn::TokioRuntime)) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                eprintln!("QUIC endpoint failure: {}", e);
+                return;
+            }
+        };
+        while let Some(incoming) = endpoint.accept().await {
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                match incoming.await {
+                    Ok(connection) => quic_connection(connection, receiver).await,
+                    Err(e) => eprintln!("QUIC handshake failure: {}", e),
                 }
+            });
+        }
+    });
+}
 
-                if args.calc_rtt {
-                    handle_rtt(&args, &mut buffer, &mut data, &pong_socket, &src_addr);
+// DATAGRAM frames, and the datagrams of each unidirectional stream as over TCP.
+async fn quic_connection(connection: quinn::Connection, receiver: Arc<Receiver>) {
+    let src_addr = connection.remote_address();
+    trace!("Connected: Time {} -> from {} (QUIC)", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), src_addr);
+    loop {
+        tokio::select! {
+            datagram = connection.read_datagram() => match datagram {
+                Ok(datagram) => receiver.handle_datagram(&datagram, src_addr),
+                Err(_) => break,
+            },
+            stream = connection.accept_uni() => match stream {
+                Ok(mut stream) => {
+                    let receiver = receiver.clone();
+                    tokio::spawn(async move {
+                        let mut buffer = vec![0; MAX_DATAGRAM_LENGTH];
+                        loop {
+                            let mut prefix = [0; TCP_LENGTH_PREFIX];
+                            if stream.read_exact(&mut prefix).await.is_err() {
+                                break;
+                            }
+                            let length = u32::from_le_bytes(prefix) as usize;
+                            if length > MAX_DATAGRAM_LENGTH || stream.read_exact(&mut buffer[..length]).await.is_err() {
+                                break;
+                            }
+                            receiver.handle_datagram(&buffer[..length], src_addr);
+                        }
+                    });
                 }
+                Err(_) => break,
+            },
+        }
+    }
+    trace!("Disconnected: Time {} -> from {} (QUIC)", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), src_addr);
+}
 
-            } else if !started {
-                std::thread::sleep(std::time::Duration::from_nanos(100_000));
+fn tcp_listener_thread(listener: TcpListener, receiver: Arc<Receiver>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || tcp_recv_thread(stream, receiver));
             }
+            Err(e) => eprintln!("TCP accept failure: {}", e),
         }
     }
-    else {
-        eprintln!("Error creating pong socket");
+}
+
+// Datagrams of a connection, each preceded by its length (u32, little-endian).
+fn tcp_recv_thread(mut stream: TcpStream, receiver: Arc<Receiver>) {
+    let Ok(src_addr) = stream.peer_addr() else { return };
+    let _ = stream.set_nodelay(true);
+    trace!("Connected: Time {} -> from {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), src_addr);
+    let mut buffer = [0; MAX_DATAGRAM_LENGTH];
+    loop {
+        let mut prefix = [0; TCP_LENGTH_PREFIX];
+        if stream.read_exact(&mut prefix).is_err() {
+            break;
+        }
+        let length = u32::from_le_bytes(prefix) as usize;
+        if length > MAX_DATAGRAM_LENGTH {
+            eprintln!("Invalid datagram length {} from {}, closing the connection.", length, src_addr);
+            break;
+        }
+        if stream.read_exact(&mut buffer[..length]).is_err() {
+            break;
+        }
+        receiver.handle_datagram(&buffer[..length], src_addr);
     }
+    trace!("Disconnected: Time {} -> from {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), src_addr);
 }
 
 fn handle_rtt(
-    args: &Args, 
-    buffer: &mut [u8], 
-    data: &mut RecvData, 
-    pong_socket: &UdpSocket, 
-    src_addr: &std::net::SocketAddr
+    args: &Args,
+    packet: PacketStruct,
+    data: &mut RecvData,
+    pong_sockets: &PongSockets,
+    src_addr: &SocketAddr
 ) -> Option<Vec<u8>> {
-    let seq = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+    let seq = packet.seq;
+    let last_seq = data.last_seq;
     data.last_seq = if seq > data.last_seq { seq } else { data.last_seq };
 
-    if seq % args.sample_rate != 0 {
+    if !seq.is_multiple_of(args.sample_rate) {
         return None
     } 
 
-    data.recv_records.entry(seq).or_insert_with(RecvRecord::new).record(buffer);
+    // repair packets arriving after their frame are of no use
+    if packet.repair.is_some() && seq <= last_seq && !data.recv_records.contains_key(&seq) {
+        return None
+    }
+
+    let header = packet.header();
+    let time_now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+    data.recv_records.entry(seq).or_default().record(packet);
     let _record = data.recv_records.get_mut(&seq).unwrap();
+    _record.touch(time_now);
     let mut res = None;
 
-    if _record.is_fst_ack() || _record.is_scd_ack() {
-        let packet_type = if src_addr.ip().to_string() == args.src_ipaddrs[0] {
-            trace!("ACKFirst: Time {} -> seq: {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), seq);
-            _record.is_ack.0 = true;
-            if _record.is_complete() {
-                PacketType::SLFL
-            } else {
-                PacketType::DFL
-            }
-        } else {
-            trace!("ACKSecond: Time {} -> seq: {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), seq);
-            _record.is_ack.1 = true;
-            if _record.is_complete() {
-                PacketType::SLSL
-            } else {
-                PacketType::DSL
-            }
-        };
-        buffer[18..19].copy_from_slice(packet::to_indicator(packet_type).to_le_bytes().as_ref());
-        let ping_addr = format!("{}:{}", src_addr.ip().to_string(), args.port + PONG_PORT_INC);
-        send_ack(pong_socket, buffer, &ping_addr);
+    let is_complete = _record.is_complete();
+    let mut ack_paths = _record.take_acks();
+    if is_complete && ack_paths.is_empty() {
+        ack_paths.push(header.path);
+    }
+    for path in ack_paths {
+        trace!("ACK: Time {} -> seq: {}, path: {}, complete: {}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64(), seq, path, is_complete);
+        let mut ack = header.clone();
+        ack.set_path(path, if is_complete { FRAME_COMPLETE } else { 0 });
+        send_pong(args, &ack, pong_sockets, src_addr);
     }
 
-    if _record.is_complete() {
+    if is_complete {
         data.stutter.update( std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64() );
         if args.rx_mode {
             res = Some(_record.gather());
@@ -111,16 +367,71 @@ fn handle_rtt(
                 tx.send(res.clone().unwrap()).unwrap();
             }
         }
+        if _record.recovered() > 0 {
+            data.recovered += 1;
+        }
+        if _record.nacks() > 0 {
+            data.retransmitted += 1;
+        }
         data.recv_records.remove(&seq);
         data.recevied += 1;
     }
+
+    if args.nack {
+        send_nacks(args, seq, last_seq, &header, data, pong_sockets, src_addr, time_now);
+    }
     
     res
 }
 
-fn send_ack(pong_socket: &UdpSocket, buffer: &[u8], ping_addr: &str) {
+// NACK the incomplete frames ahead of `seq` once quiet, those never heard of included.
+#[allow(clippy::too_many_arguments)]
+fn send_nacks(
+    args: &Args,
+    seq: u32,
+    last_seq: u32,
+    header: &PacketStruct,
+    data: &mut RecvData,
+    pong_sockets: &PongSockets,
+    src_addr: &SocketAddr,
+    time_now: f64
+) {
+    let oldest = seq.saturating_sub(NACK_WINDOW);
+    for missing_seq in (last_seq + 1).max(oldest)..seq {
+        if missing_seq.is_multiple_of(args.sample_rate) {
+            data.recv_records.entry(missing_seq).or_insert_with(|| {
+                let mut record = RecvRecord::new();
+                record.touch(time_now);
+                record
+            });
+        }
+    }
+    for nack_seq in oldest..seq {
+        let Some(record) = data.recv_records.get_mut(&nack_seq) else { continue };
+        if !record.is_nack_due(time_now, args.nack_interval, MAX_NACKS) {
+            continue;
+        }
+        let (missing, tail) = record.nack(time_now);
+        trace!("NACK: Time {} -> seq: {}, missing: {:?}, tail: {:?}", time_now, nack_seq, missing, tail);
+        let mut nack = header.clone();
+        nack.seq = nack_seq;
+        nack.set_nack(&missing, tail);
+        send_pong(args, &nack, pong_sockets, src_addr);
+    }
+}
+
+fn send_pong(args: &Args, packet: &PacketStruct, pong_sockets: &PongSockets, src_addr: &SocketAddr) {
+    let ping_addr = SocketAddr::new(src_addr.ip().to_canonical(), args.port + PONG_PORT_INC);
+    match (packet.to_bytes(), pong_sockets.get(&ping_addr)) {
+        (Ok(buffer), Some(pong_socket)) => send_ack(pong_socket, &buffer, &ping_addr),
+        (Err(e), _) => eprintln!("Error encoding ACK: {}", e),
+        (_, None) => eprintln!("No pong socket for {}", ping_addr),
+    }
+}
+
+fn send_ack(pong_socket: &UdpSocket, buffer: &[u8], ping_addr: &SocketAddr) {
     loop {
-        match pong_socket.send_to(&buffer, ping_addr) {
+        match pong_socket.send_to(buffer, ping_addr) {
             Ok(_) => break,
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 println!("Send operation would block, retrying later...");
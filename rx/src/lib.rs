@@ -1,3 +1,4 @@
 pub mod destination;
 pub mod record;
+pub mod rtp;
 mod statistic;
\ No newline at end of file
@@ -1,5 +1,6 @@
 mod destination;
 mod record;
+mod rtp;
 mod statistic;
 
 use std::{fs::File, io::Write, sync::{mpsc, Arc, Mutex}};
@@ -20,6 +21,8 @@ fn main() {
     // Extract duration from args
     let port = args.port;
     let duration = args.duration;
+    let rtp = args.rtp;
+    let nack = args.nack;
     
     let lock = Arc::new(Mutex::new(false));
     let lock_clone = Arc::clone(&lock);
@@ -44,6 +47,15 @@ fn main() {
     let non_received = recv_data.last_seq - recv_data.recevied;
     println!("Packet loss rate: {:.5}", non_received as f64 / recv_data.last_seq as f64);
     println!("Stuttering rate: {:.5}", recv_data.stutter.get_stuttering());
+    if rtp {
+        println!("Interarrival jitter: {:.3} ms", recv_data.rtp.jitter() * 1000.0);
+    }
+    println!("Rejected packets: {}", recv_data.malformed);
+    println!("Corrupted packets: {}", recv_data.corrupted);
+    println!("Recovered frames: {}", recv_data.recovered);
+    if nack {
+        println!("Retransmitted frames: {}", recv_data.retransmitted);
+    }
 
     // Write the data to stuttering file
     let mut logger = File::create( format!("logs/stuttering-{port}.txt", ) ).unwrap();
@@ -1,33 +1,31 @@
 use std::collections::HashMap;
 
 use std::sync::mpsc::Sender;
-use core::packet::{self, PacketStruct, PacketType};
+use core::packet::{PacketStruct, FIRST_OF_PATH, LAST_OF_PATH, LAST_OF_FRAME};
+use core::fec;
 
+use crate::rtp::RtpFrames;
 use crate::statistic::stuttering::Stutter;
-#[derive(Default)]
-struct RecvOffsets {
-    sl: Option<u16>,
-    dfl: Option<u16>,
-    dsf: Option<u16>,
-    dsl: Option<u16>,
+#[derive(Default, Clone)]
+struct RecvPath {
+    first: Option<u16>,
+    last: Option<u16>,
+    complete: bool,
+    acked: bool,
 }
 
-#[derive(Default)]
-struct RecvComplete {
-    sl_complete: bool,
-    ch1_complete: bool,
-    ch2_complete: bool,
-}
-
-type IsACK = (bool, bool);
-
 pub struct RecvData{
     pub recv_records: HashMap<u32, RecvRecord>,
     pub last_seq: u32,
     pub recevied: u32,
     pub data_len: u32,
+    pub malformed: u32,
+    pub corrupted: u32,
+    pub recovered: u32,
+    pub retransmitted: u32,
     pub rx_start_time: f64,
     pub stutter: Stutter,
+    pub rtp: RtpFrames,
     pub tx: Option<Sender<Vec<u8>>>
 }
 
@@ -38,80 +36,167 @@ impl RecvData{
             last_seq: 0,
             recevied: 0,
             data_len: 0,
+            malformed: 0,
+            corrupted: 0,
+            recovered: 0,
+            retransmitted: 0,
             rx_start_time: 0.0,
             stutter: Stutter::new(),
+            rtp: RtpFrames::default(),
             tx: None,
         }
     }
 }
 
+impl Default for RecvData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
 pub struct RecvRecord {
     pub packets: HashMap<u16, PacketStruct>, // Use a HashMap to store packets by their offset
-    pub is_ack: IsACK,
-    offsets: RecvOffsets,
-    is_complete: RecvComplete,
+    repairs: HashMap<u16, Vec<PacketStruct>>, // FEC repair packets by the first offset of their group
+    paths: Vec<RecvPath>,
+    frame_end: Option<u16>,
+    recovered: usize,
+    is_complete: bool,
+    heard: f64,  // time of the last fragment or NACK
+    nacks: u32,
+}
+
+impl Default for RecvRecord {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RecvRecord {
     pub fn new() -> Self{
         Self{
             packets: HashMap::<u16, PacketStruct>::new(),
-            is_ack : (false, false),
-            offsets: RecvOffsets::default(),
-            is_complete: RecvComplete::default(),
+            repairs: HashMap::new(),
+            paths: Vec::new(),
+            frame_end: None,
+            recovered: 0,
+            is_complete: false,
+            heard: 0.0,
+            nacks: 0,
         }
     }
-    pub fn record(&mut self, data: &[u8]) {
-        let packet = packet::from_buffer(data);
-        let offset = Some(packet.offset);
+    pub fn record(&mut self, packet: PacketStruct) {
+        let path = packet.path as usize;
+        if self.paths.len() <= path {
+            self.paths.resize(path + 1, RecvPath::default());
+        }
 
-        match packet::get_packet_type(packet.indicators) {
-            PacketType::SL  => self.offsets.sl = offset,
-            PacketType::DFL => self.offsets.dfl = offset,
-            PacketType::DSF => self.offsets.dsf = offset,
-            PacketType::DSL => self.offsets.dsl = offset,
-            PacketType::DSS => {
-                self.offsets.dsf = offset;
-                self.offsets.dsl = offset;
+        if let Some(repair) = packet.repair {
+            let repairs = self.repairs.entry(repair.first).or_default();
+            if repairs.iter().all(|p| p.repair.is_some_and(|r| r.index != repair.index)) {
+                repairs.push(packet);
             }
-            _ => {}
+            self.recover();
+            self.determine_complete();
+            return;
         }
 
-        self.packets.insert(packet.offset as u16, packet);
-        self.is_complete = self.determine_complete();
+        let offset = Some(packet.offset);
+        if packet.indicators & FIRST_OF_PATH != 0 {
+            self.paths[path].first = offset;
+        }
+        if packet.indicators & LAST_OF_PATH != 0 {
+            self.paths[path].last = offset;
+        }
+        if packet.indicators & LAST_OF_FRAME != 0 {
+            self.frame_end = offset;
+        }
+
+        self.packets.insert(packet.offset, packet);
+        self.recover();
+        self.determine_complete();
+    }
+
+    /// Number of fragments rebuilt from repair packets, including those still in flight on another path.
+    pub fn recovered(&self) -> usize {
+        self.recovered
+    }
+
+    fn recover(&mut self) {
+        for repairs in self.repairs.values() {
+            for packet in fec::recover(repairs, &self.packets) {
+                if packet.indicators & LAST_OF_FRAME != 0 {
+                    self.frame_end = Some(packet.offset);
+                }
+                self.packets.insert(packet.offset, packet);
+                self.recovered += 1;
+            }
+        }
     }
 
     pub fn is_complete(&self) -> bool {
-        self.is_complete.sl_complete || (self.is_complete.ch1_complete && self.is_complete.ch2_complete)
+        self.is_complete
     }
 
-    pub fn is_fst_ack(&self) -> bool {
-        !self.is_ack.0 && (self.is_complete.sl_complete || self.is_complete.ch1_complete)
+    /// Note activity on the frame at `time`, a fragment arriving.
+    pub fn touch(&mut self, time: f64) {
+        self.heard = time;
     }
 
-    pub fn is_scd_ack(&self) -> bool {
-        !self.is_ack.1 && self.is_complete.ch2_complete
+    /// Whether the frame is still incomplete after `interval` of silence, with fewer than `max_nacks` NACKs.
+    pub fn is_nack_due(&self, time: f64, interval: f64, max_nacks: u32) -> bool {
+        !self.is_complete && self.nacks < max_nacks && time - self.heard >= interval
     }
 
-    fn determine_complete(&self) -> RecvComplete {
-        fn is_range_complete(packets: &HashMap<u16, PacketStruct>, mut range: std::ops::RangeInclusive<u16>) -> bool {
-            range.all(|i| packets.contains_key(&i))
-        }
-    
-        if let Some(sl) = self.offsets.sl {
-            if is_range_complete(&self.packets, 0..=sl) {
-                return RecvComplete { sl_complete: true, ch1_complete: false, ch2_complete: false };
+    /// Offsets missing below the highest one known, and the first offset of the missing tail
+    /// while the end of the frame is unknown, counting a NACK of them at `time`.
+    pub fn nack(&mut self, time: f64) -> (Vec<u16>, Option<u16>) {
+        self.nacks += 1;
+        self.heard = time;
+        let highest = self.frame_end.or_else(|| self.packets.keys().max().cloned());
+        let missing = highest.map_or(Vec::new(), |highest| {
+            (0..=highest).filter(|offset| !self.packets.contains_key(offset)).collect()
+        });
+        let tail = match self.frame_end {
+            Some(_) => None,
+            None => Some(highest.map_or(0, |highest| highest + 1)),
+        };
+        (missing, tail)
+    }
+
+    /// Number of NACKs sent for the frame.
+    pub fn nacks(&self) -> u32 {
+        self.nacks
+    }
+
+    /// Paths which have delivered all of their fragments but were not acknowledged yet.
+    pub fn take_acks(&mut self) -> Vec<u8> {
+        self.paths.iter_mut().enumerate().filter_map(|(i, path)| {
+            if path.complete && !path.acked {
+                path.acked = true;
+                Some(i as u8)
+            } else {
+                None
             }
-        }
-    
-        let ch1_complete = self.offsets.dfl.map_or(false, |dfl| is_range_complete(&self.packets, 0..=dfl));
-        let ch2_complete = match (self.offsets.dsf, self.offsets.dsl) {
-            (Some(dsf), Some(dsl)) => is_range_complete(&self.packets, dsl..=dsf),
-            _ => false,
+        }).collect()
+    }
+
+    fn determine_complete(&mut self) {
+        let packets = &self.packets;
+        let is_range_complete = |mut range: std::ops::RangeInclusive<u16>| {
+            range.all(|i| packets.contains_key(&i))
         };
 
-        RecvComplete { sl_complete: false, ch1_complete, ch2_complete }
+        for path in self.paths.iter_mut().filter(|path| !path.complete) {
+            if let (Some(first), Some(last)) = (path.first, path.last) {
+                path.complete = is_range_complete(first..=last);
+            }
+        }
+        self.is_complete = self.frame_end.is_some_and(|end| is_range_complete(0..=end));
+        // the fragments of a path may be recovered without telling where its range lies
+        if self.is_complete {
+            self.paths.iter_mut().for_each(|path| path.complete = true);
+        }
     }
     #[allow(dead_code)]
     pub fn gather(&self) -> Vec<u8>{
@@ -121,6 +206,6 @@ impl RecvRecord {
             let packet = self.packets.get(&(i as u16)).unwrap();
             data.extend_from_slice(&packet.payload[ ..packet.length as usize]);
         }
-        return data;
+        data
     }
-}
\ No newline at end of file
+}
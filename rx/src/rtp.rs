@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use core::packet::{RtpHeader, RTP_CLOCK_RATE};
+
+const SEQUENCE_RANGE: u64 = 1 << 16;
+const MAX_PENDING_FRAMES: u32 = 1024; // frames kept, older incomplete ones are lost
+const MAX_SEEN_PACKETS: u64 = 1 << 15; // sequence numbers kept to find frame boundaries
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RtpError {
+    NotRtp,
+    ForeignSsrc(u32),
+}
+
+impl std::fmt::Display for RtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotRtp => write!(f, "not an RTP packet"),
+            Self::ForeignSsrc(ssrc) => write!(f, "foreign SSRC: {:#010x}", ssrc),
+        }
+    }
+}
+
+impl std::error::Error for RtpError {}
+
+#[derive(Default)]
+struct RtpFrame {
+    number: u32,
+    marker: Option<u64>,              // extended sequence number of the last packet
+    packets: BTreeMap<u64, Vec<u8>>,  // payloads by extended sequence number
+    complete: bool,                   // kept until pruned, against duplicates
+}
+
+/// Frames of a third-party RTP stream, the packets sharing a timestamp up to the marker bit.
+/// Only the first SSRC received is followed.
+pub struct RtpFrames {
+    ssrc: Option<u32>,
+    highest: u64,                      // extended sequence number
+    seen: BTreeSet<u64>,               // recent extended sequence numbers
+    frames: HashMap<u32, RtpFrame>,    // recent frames by timestamp
+    latest: Option<(u32, u32)>,        // (timestamp, number) of the latest frame
+    clock_rate: f64,
+    transit: Option<f64>,
+    jitter: f64,                       // RFC 3550 interarrival jitter, in timestamp units
+}
+
+impl Default for RtpFrames {
+    fn default() -> Self {
+        Self::new(RTP_CLOCK_RATE)
+    }
+}
+
+impl RtpFrames {
+    pub fn new(clock_rate: f64) -> Self {
+        Self { ssrc: None, highest: 0, seen: BTreeSet::new(), frames: HashMap::new(), latest: None,
+            clock_rate, transit: None, jitter: 0.0 }
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.clock_rate = clock_rate;
+    }
+
+    /// Number of the latest frame; the first one, possibly joined midway, is 0 and never complete.
+    pub fn last_number(&self) -> u32 {
+        self.latest.map_or(0, |(_, number)| number)
+    }
+
+    /// Interarrival jitter, in seconds.
+    pub fn jitter(&self) -> f64 {
+        self.jitter / self.clock_rate
+    }
+
+    /// Record a datagram received at `arrival` (seconds), returning the frames it completes
+    /// with their number and payload.
+    pub fn record(&mut self, buf: &[u8], arrival: f64) -> Result<Vec<(u32, Vec<u8>)>, RtpError> {
+        let (header, payload) = parse(buf).ok_or(RtpError::NotRtp)?;
+        match self.ssrc {
+            None => self.ssrc = Some(header.ssrc),
+            Some(ssrc) if ssrc != header.ssrc => return Err(RtpError::ForeignSsrc(header.ssrc)),
+            _ => {}
+        }
+        self.update_jitter(header.timestamp, arrival);
+
+        let seq = self.extend(header.sequence);
+        self.seen.insert(seq);
+        while self.seen.len() as u64 > MAX_SEEN_PACKETS {
+            self.seen.pop_first();
+        }
+        // a late packet of a frame already given up still tells where the next one starts
+        if let Some(number) = self.number(header.timestamp) {
+            let frame = self.frames.entry(header.timestamp).or_default();
+            frame.number = number;
+            if frame.complete {
+                return Ok(Vec::new());
+            }
+            frame.packets.insert(seq, payload.to_vec());
+            if header.marker {
+                frame.marker = Some(seq);
+            }
+        }
+
+        // this packet may complete its frame, or the next one
+        let mut completed = Vec::new();
+        let next = self.frames.iter()
+            .find(|(_, frame)| frame.packets.first_key_value().is_some_and(|(first, _)| *first == seq + 1 || *first == seq + 2))
+            .map(|(timestamp, _)| *timestamp);
+        for timestamp in std::iter::once(header.timestamp).chain(next) {
+            if self.is_complete(timestamp) {
+                let frame = self.frames.get_mut(&timestamp).unwrap();
+                frame.complete = true;
+                completed.push((frame.number, std::mem::take(&mut frame.packets).into_values().flatten().collect()));
+            }
+        }
+        self.prune();
+        Ok(completed)
+    }
+
+    fn is_complete(&self, timestamp: u32) -> bool {
+        let Some(frame) = self.frames.get(&timestamp) else { return false };
+        let (Some(marker), Some((&first, _))) = (frame.marker, frame.packets.first_key_value()) else {
+            return false;
+        };
+        !frame.complete && frame.number > 0 && frame.packets.len() as u64 == marker + 1 - first && self.is_first(frame.number, first)
+    }
+
+    // whether no packet of the frame precedes `first`
+    fn is_first(&self, number: u32, first: u64) -> bool {
+        // the packet ahead belongs to the previous frame
+        if self.seen.contains(&(first - 1)) {
+            return true;
+        }
+        // or, lost, is the marker the previous frame still misses right after its last packet
+        self.frames.values().any(|frame| frame.number + 1 == number && !frame.complete && frame.marker.is_none()
+            && frame.packets.last_key_value().is_some_and(|(last, _)| last + 2 == first))
+    }
+
+    // extended sequence number, closest to the highest one so far
+    fn extend(&mut self, sequence: u16) -> u64 {
+        if self.seen.is_empty() {
+            // room for reordering below the first packet
+            self.highest = SEQUENCE_RANGE + sequence as u64;
+            return self.highest;
+        }
+        let cycle = self.highest & !(SEQUENCE_RANGE - 1);
+        let seq = [cycle.wrapping_sub(SEQUENCE_RANGE), cycle, cycle + SEQUENCE_RANGE].into_iter()
+            .map(|cycle| cycle.wrapping_add(sequence as u64))
+            .min_by_key(|seq| seq.abs_diff(self.highest))
+            .unwrap();
+        self.highest = self.highest.max(seq);
+        seq
+    }
+
+    // frames are numbered by increasing timestamp, as they appear
+    fn number(&mut self, timestamp: u32) -> Option<u32> {
+        if let Some(frame) = self.frames.get(&timestamp) {
+            return Some(frame.number);
+        }
+        match self.latest {
+            Some((latest, number)) if timestamp == latest => Some(number),
+            Some((latest, _)) if (timestamp.wrapping_sub(latest) as i32) < 0 => None,
+            _ => {
+                let number = self.latest.map_or(0, |(_, number)| number + 1);
+                self.latest = Some((timestamp, number));
+                Some(number)
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let oldest = self.last_number().saturating_sub(MAX_PENDING_FRAMES);
+        self.frames.retain(|_, frame| frame.number > oldest);
+    }
+
+    fn update_jitter(&mut self, timestamp: u32, arrival: f64) {
+        let transit = arrival * self.clock_rate - timestamp as f64;
+        if let Some(last) = self.transit {
+            // timestamps wrap around
+            let d = (transit - last).rem_euclid(u32::MAX as f64 + 1.0);
+            let d = d.min(u32::MAX as f64 + 1.0 - d);
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+    }
+}
+
+// header and payload, skipping CSRCs, header extension and padding
+fn parse(buf: &[u8]) -> Option<(RtpHeader, &[u8])> {
+    let (header, mut start) = RtpHeader::decode(buf)?;
+    if buf[0] & 0x10 != 0 {
+        let words = u16::from_be_bytes(buf.get(start + 2..start + 4)?.try_into().unwrap());
+        start += 4 + 4 * words as usize;
+    }
+    let mut end = buf.len();
+    if buf[0] & 0x20 != 0 {
+        end = end.checked_sub(*buf.last()? as usize)?;
+    }
+    (start <= end).then(|| (header, &buf[start..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtp(sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x80, if marker { 0x80 | 96 } else { 96 }];
+        buf.extend_from_slice(&sequence.to_be_bytes());
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+        buf.extend_from_slice(&0xCAFE_u32.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn extends_across_wraparound() {
+        let mut frames = RtpFrames::default();
+        let extended: Vec<_> = [0xFFFD, 0xFFFF, 0xFFFE, 0, 1, 0xFFFC, 2, 0x8000, 0xC000, 3].into_iter()
+            .map(|sequence| { let seq = frames.extend(sequence); frames.seen.insert(seq); seq })
+            .collect();
+        let base = SEQUENCE_RANGE;
+        assert_eq!(extended, [base + 0xFFFD, base + 0xFFFF, base + 0xFFFE, 2 * base, 2 * base + 1,
+            base + 0xFFFC, 2 * base + 2, 2 * base + 0x8000, 2 * base + 0xC000, 3 * base + 3]);
+    }
+
+    #[test]
+    fn extends_below_first() {
+        let mut frames = RtpFrames::default();
+        for sequence in [0, 0xFFFF, 1] {
+            let seq = frames.extend(sequence);
+            frames.seen.insert(seq);
+        }
+        assert_eq!(frames.extend(0xFFFE), SEQUENCE_RANGE - 2);
+        assert_eq!(frames.highest, SEQUENCE_RANGE + 1);
+    }
+
+    #[test]
+    fn completes_frames_across_wraparound() {
+        let mut frames = RtpFrames::default();
+        // joined midway, the first frame is never complete
+        assert_eq!(frames.record(&rtp(0xFFFC, 0, true, b"x"), 0.0), Ok(vec![]));
+        assert_eq!(frames.record(&rtp(0xFFFD, 3000, false, b"ab"), 0.0), Ok(vec![]));
+        assert_eq!(frames.record(&rtp(0xFFFE, 3000, false, b"cd"), 0.0), Ok(vec![]));
+        assert_eq!(frames.record(&rtp(0xFFFF, 3000, true, b"ef"), 0.0), Ok(vec![(1, b"abcdef".to_vec())]));
+        // reordered, across the wrap
+        assert_eq!(frames.record(&rtp(1, 6000, true, b"hi"), 0.0), Ok(vec![]));
+        assert_eq!(frames.record(&rtp(0, 6000, false, b"g"), 0.0), Ok(vec![(2, b"ghi".to_vec())]));
+        assert_eq!(frames.record(&rtp(0, 6000, false, b"g"), 0.0), Ok(vec![]));
+        assert_eq!(frames.last_number(), 2);
+    }
+
+    #[test]
+    fn completes_after_lost_marker() {
+        let mut frames = RtpFrames::default();
+        frames.record(&rtp(10, 0, true, b""), 0.0).unwrap();
+        frames.record(&rtp(11, 3000, false, b"a"), 0.0).unwrap();
+        // the marker of frame 1 (12) is lost, frame 2 still starts right after it
+        assert_eq!(frames.record(&rtp(14, 6000, true, b"c"), 0.0), Ok(vec![]));
+        assert_eq!(frames.record(&rtp(13, 6000, false, b"b"), 0.0), Ok(vec![(2, b"bc".to_vec())]));
+    }
+
+    #[test]
+    fn rejects_foreign() {
+        let mut frames = RtpFrames::default();
+        frames.record(&rtp(0, 0, true, b""), 0.0).unwrap();
+        let mut foreign = rtp(1, 0, true, b"");
+        foreign[11] = 0xFF;
+        assert_eq!(frames.record(&foreign, 0.0), Err(RtpError::ForeignSsrc(0xCAFF)));
+        assert_eq!(frames.record(&[0x40; 12], 0.0), Err(RtpError::NotRtp));
+        // padding longer than the packet
+        let mut padded = rtp(1, 0, true, &[0xFF]);
+        padded[0] |= 0x20;
+        assert_eq!(frames.record(&padded, 0.0), Err(RtpError::NotRtp));
+    }
+
+    #[test]
+    fn jitter() {
+        let mut frames = RtpFrames::new(1000.0);
+        // constant transit, even across the timestamp wrap
+        for (i, timestamp) in [u32::MAX - 1, u32::MAX, 0, 1].into_iter().enumerate() {
+            frames.record(&rtp(i as u16, timestamp, true, b""), i as f64 / 1000.0).unwrap();
+        }
+        assert!(frames.jitter().abs() < 1e-9);
+        frames.record(&rtp(4, 2, true, b""), 0.019).unwrap();
+        assert!((frames.jitter() - 0.015 / 16.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::trace;
+use serde::{Serialize, Deserialize};
+
+use core::packet::PacketStruct;
+use crate::rtt::{Feedback, FeedbackReceiver};
+use crate::throttle::RateThrottler;
+
+const fn _default_max_retries() -> usize { 2 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ArqParams {
+    #[serde(default)] pub deadline: f64,          //default: 0.0 (s), `target_rtt`
+    #[serde(default = "_default_max_retries")]     //default:
+    pub max_retries: usize,                        //         2, per fragment
+}
+
+impl ArqParams {
+    pub fn is_valid(&self) -> bool {
+        self.deadline >= 0.0 && self.max_retries > 0
+    }
+}
+
+struct SentFrame {
+    time: f64,                                    // handed to the throttler
+    fragments: HashMap<u16, (PacketStruct, usize)>, // with their retransmissions, by offset
+}
+
+/// Fragments of the recent frames of a stream, kept for retransmission until the deadline of their frame.
+pub struct SentFrames {
+    deadline: f64,
+    max_retries: usize,
+    frames: HashMap<u32, SentFrame>,
+    order: VecDeque<u32>,
+}
+
+impl SentFrames {
+    pub fn new(params: &ArqParams, target_rtt: f64) -> Self {
+        let deadline = if params.deadline > 0.0 { params.deadline } else { target_rtt };
+        Self { deadline, max_retries: params.max_retries, frames: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Keep the fragments of a frame, repair packets aside.
+    pub fn keep(&mut self, packets: &[PacketStruct]) {
+        let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        self.prune(time_now);
+        for packet in packets.iter().filter(|packet| packet.repair.is_none()) {
+            let frame = self.frames.entry(packet.seq).or_insert_with(|| {
+                self.order.push_back(packet.seq);
+                SentFrame { time: time_now, fragments: HashMap::new() }
+            });
+            frame.fragments.insert(packet.offset, (packet.clone(), 0));
+        }
+    }
+
+    /// The `missing` fragments of frame `seq`, and all from `tail` on if any, still due and not
+    /// retransmitted `max_retries` times yet, in order.
+    pub fn resend(&mut self, seq: u32, missing: &[u16], tail: Option<u16>) -> Vec<PacketStruct> {
+        let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        let Some(frame) = self.frames.get_mut(&seq) else { return Vec::new() };
+        if time_now - frame.time > self.deadline {
+            return Vec::new();
+        }
+        let mut packets: Vec<_> = frame.fragments.iter_mut()
+            .filter(|(offset, (_, retries))| {
+                (missing.contains(offset) || tail.is_some_and(|tail| **offset >= tail)) && *retries < self.max_retries
+            })
+            .map(|(_, (packet, retries))| {
+                *retries += 1;
+                packet.clone()
+            })
+            .collect();
+        packets.sort_by_key(|packet| packet.offset);
+        packets
+    }
+
+    fn prune(&mut self, time_now: f64) {
+        while let Some(seq) = self.order.front() {
+            if self.frames.get(seq).is_some_and(|frame| time_now - frame.time <= self.deadline) {
+                break;
+            }
+            self.frames.remove(seq);
+            self.order.pop_front();
+        }
+    }
+}
+
+/// Retransmission of the fragments NACKed by the receiver, ahead of the other packets in the throttler.
+pub fn nack_thread(name: String, duration: f64, throttler: Arc<Mutex<RateThrottler>>, feedback: FeedbackReceiver) {
+    let stop_time = SystemTime::now() + Duration::from_secs_f64(duration);
+
+    while let Ok(remaining_time) = stop_time.duration_since( SystemTime::now() ) {
+        match feedback.recv_timeout(remaining_time) {
+            Ok(Feedback::Nacked { seq, missing, tail }) => {
+                let resent = throttler.lock().unwrap().retransmit(seq, &missing, tail);
+                trace!("Retransmit: Time {} -> {} seq {} fragments {}",
+                    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64(), name, seq, resent);
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fec::{FecParams, FecScheme, repair_packets};
+    use core::testing::frame;
+    use crate::throttle::QueueParams;
+
+    fn offsets(packets: &[PacketStruct]) -> Vec<u16> {
+        packets.iter().map(|packet| packet.offset).collect()
+    }
+
+    #[test]
+    fn resends_missing() {
+        let mut sent = SentFrames::new(&ArqParams{ deadline: 0.0, max_retries: 2 }, 1.0);
+        let mut packets = frame(1, 6, 100);
+        packets.extend(repair_packets(&FecParams{ scheme: FecScheme::Xor, group: 0, parity: 1 }, &packets));
+        sent.keep(&packets);
+        assert_eq!(offsets(&sent.resend(1, &[3, 1], None)), [1, 3]);
+        // from the tail on, the repair packet aside
+        assert_eq!(offsets(&sent.resend(1, &[], Some(4))), [4, 5]);
+        assert_eq!(offsets(&sent.resend(1, &[1, 2], Some(5))), [1, 2, 5]);
+        // retransmitted `max_retries` times already
+        assert_eq!(offsets(&sent.resend(1, &[1, 3], None)), [3]);
+        assert!(sent.resend(2, &[0], None).is_empty());
+    }
+
+    #[test]
+    fn resends_until_deadline() {
+        let mut sent = SentFrames::new(&ArqParams{ deadline: 0.01, max_retries: 2 }, 1.0);
+        sent.keep(&frame(1, 2, 100));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(sent.resend(1, &[0], None).is_empty());
+        // pruned as new frames come
+        sent.keep(&frame(2, 2, 100));
+        assert_eq!(sent.frames.len(), 1);
+        assert_eq!(offsets(&sent.resend(2, &[0], None)), [0]);
+    }
+
+    #[test]
+    fn retransmits_ahead() {
+        let mut throttler = RateThrottler::new("test".into(), 0.0, 10, true, QueueParams::default());
+        throttler.keep_sent(SentFrames::new(&ArqParams{ deadline: 0.0, max_retries: 2 }, 1.0));
+        throttler.prepare(frame(1, 3, 100), 0.0);
+        throttler.prepare(frame(2, 3, 100), 0.0);
+        assert_eq!(throttler.retransmit(1, &[0, 2], None), 2);
+        let order: Vec<_> = std::iter::from_fn(|| throttler.consume()).map(|packet| (packet.seq, packet.offset)).take(3).collect();
+        assert_eq!(order, [(1, 0), (1, 2), (1, 0)]);
+    }
+}
@@ -1,95 +1,354 @@
-use std::thread::{JoinHandle, sleep, yield_now};
-use std::sync::{Arc, Mutex, mpsc};
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use log::trace;
+use serde::{Serialize, Deserialize};
 
+use core::packet::{PacketStruct, PacketSender, PacketReceiver, tos2ac};
 use crate::conf::ConnParams;
-use crate::packet::{PacketStruct, PacketSender, PacketReceiver, tos2ac};
-use crate::dispatcher::{UdpDispatcher, SourceInput};
 
-type BrokerConn = (PacketReceiver, PacketSender);
+const BROKER_QUEUE_LENGTH: usize = 50000; // packets per stream, as the sockets of the dispatcher
+const SOCKET_BACKLOG: usize = 64;         // packets queued to a socket, beyond which the head waits in the broker
+const DRR_QUANTUM: f64 = 1500.0;          // bytes per round, for a weight of 1.0
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BrokerPolicy {
+    /// Strict priority by access category (`priority`, else `tos2ac`), round robin within one.
+    #[default]
+    #[serde(rename = "priority")]
+    Priority,
+    /// Weighted fair queueing (self-clocked), by the `weight` of each stream.
+    #[serde(rename = "wfq")]
+    Wfq,
+    /// Deficit round robin, with a quantum proportional to the `weight` of each stream.
+    #[serde(rename = "drr")]
+    Drr,
+    /// Earliest deadline first, each packet due `target_rtt` after leaving its source.
+    #[serde(rename = "edf")]
+    Edf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BrokerParams {
+    #[serde(default)] pub policy: BrokerPolicy, //default: "priority"
+    #[serde(default)] pub rate: f64,            //default: 0.0 (Mbps), unlimited
+}
+
 struct Application {
-    conn: BrokerConn,
-    priority: String
+    name: String,
+    ac: usize,
+    weight: f64,
+    deadline: f64,
+    conn: PacketReceiver,
+    socket_infos: Vec<PacketSender>,
+    head: Option<PacketStruct>,
+    finish: f64,  // WFQ finish tag of the head
+    deficit: f64, // DRR deficit counter
+    drops: Arc<AtomicUsize>, // packets the socket of their path did not take
 }
-type GuardedApplications = Arc<Mutex<Vec<Application>>>;
-
-fn policy_priority_fifo(all_apps: Vec<GuardedApplications>) {
-    let passthrough = |app:&Application| {
-        let _ = app.priority;
-        for packet in app.conn.0.try_iter() {
-            if let Err(_) = app.conn.1.send(packet) {
-                return false;
+
+impl Application {
+    // the next packet of the stream, if any, false once its source is gone
+    fn fill(&mut self, virtual_time: f64) -> bool {
+        if self.head.is_some() {
+            return true;
+        }
+        match self.conn.try_recv() {
+            Ok(packet) => {
+                self.finish = self.finish.max(virtual_time) + packet.length as f64 / self.weight;
+                self.head = Some(packet);
+                true
+            }
+            Err(flume::TryRecvError::Empty) => {
+                self.deficit = 0.0;
+                true
             }
+            Err(flume::TryRecvError::Disconnected) => false,
         }
-        if app.priority.contains("guarded,") {
-            sleep( Duration::from_micros(10) );
+    }
+
+    // whether the head can be forwarded, rather than wait for room in the socket of its path
+    fn is_ready(&self) -> bool {
+        self.head.as_ref().is_some_and(|packet| {
+            self.socket_infos.get(packet.path as usize).is_none_or(|sender| sender.len() < SOCKET_BACKLOG)
+        })
+    }
+
+    fn forward(&mut self) -> Option<usize> {
+        let packet = self.head.take()?;
+        let length = packet.length as usize;
+        let sent = match self.socket_infos.get(packet.path as usize) {
+            Some(sender) => sender.try_send(packet).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(length)
+    }
+}
+
+struct Scheduler {
+    policy: BrokerPolicy,
+    apps: Vec<Application>,
+    next: usize,       // round robin position
+    virtual_time: f64, // WFQ
+}
+
+impl Scheduler {
+    fn select(&mut self) -> Option<usize> {
+        let ready = |app: &Application| app.is_ready();
+        match self.policy {
+            BrokerPolicy::Priority => {
+                let ac = self.apps.iter().filter(|app| ready(app)).map(|app| app.ac).min()?;
+                let index = (0..self.apps.len()).map(|i| (self.next + i) % self.apps.len())
+                    .find(|&i| ready(&self.apps[i]) && self.apps[i].ac == ac)?;
+                self.next = index + 1;
+                Some(index)
+            }
+            BrokerPolicy::Wfq => {
+                let index = (0..self.apps.len()).filter(|&i| ready(&self.apps[i]))
+                    .min_by(|&a, &b| self.apps[a].finish.total_cmp(&self.apps[b].finish))?;
+                self.virtual_time = self.apps[index].finish;
+                Some(index)
+            }
+            BrokerPolicy::Drr => {
+                if !self.apps.iter().any(ready) {
+                    return None;
+                }
+                loop {
+                    let index = self.next % self.apps.len();
+                    let is_ready = self.apps[index].is_ready();
+                    let app = &mut self.apps[index];
+                    match app.head {
+                        // held back by its socket, keeping its deficit
+                        Some(_) if !is_ready => (),
+                        Some(ref packet) if packet.length as f64 <= app.deficit => {
+                            app.deficit -= packet.length as f64;
+                            return Some(index);
+                        }
+                        // the quantum is granted once per visit of the round
+                        Some(_) => app.deficit += DRR_QUANTUM * app.weight,
+                        None => app.deficit = 0.0,
+                    }
+                    self.next = index + 1;
+                }
+            }
+            BrokerPolicy::Edf => {
+                let due = |app: &Application| app.head.as_ref().map_or(f64::MAX, |packet| packet.timestamp + app.deadline);
+                (0..self.apps.len()).filter(|&i| ready(&self.apps[i]))
+                    .min_by(|&a, &b| due(&self.apps[a]).total_cmp(&due(&self.apps[b])))
+            }
         }
-        true
-    };
+    }
+}
+
+fn broker_thread(params: BrokerParams, apps: Vec<Application>) {
+    let spin_sleeper = spin_sleep::SpinSleeper::new(100_000)
+                        .with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
+    let mut scheduler = Scheduler { policy: params.policy, apps, next: 0, virtual_time: 0.0 };
+    let mut next_time = SystemTime::now();
 
     loop {
-        for ac_apps in all_apps.iter() {
-            yield_now();
-            ac_apps.lock().unwrap().retain(passthrough);
-            sleep( Duration::from_micros(10) );
+        let virtual_time = scheduler.virtual_time;
+        scheduler.apps.retain_mut(|app| app.fill(virtual_time));
+        if scheduler.apps.is_empty() {
+            break;
+        }
+
+        // hold the egress to `rate`, without accumulating credit while idle
+        let Some(index) = scheduler.select() else {
+            std::thread::sleep( Duration::from_nanos(100_000) );
+            next_time = SystemTime::now();
+            continue;
+        };
+        if params.rate > 0.0 {
+            if let Ok(remaining_time) = next_time.duration_since( SystemTime::now() ) {
+                spin_sleeper.sleep( remaining_time );
+            }
+        }
+        let app = &mut scheduler.apps[index];
+        trace!("Broker: Time {} -> {} seq {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64(),
+            app.name, app.head.as_ref().map_or(0, |packet| packet.seq));
+        if let Some(length) = app.forward() {
+            if params.rate > 0.0 {
+                next_time += Duration::from_secs_f64( 8.0 * length as f64 / (params.rate * 1e6) );
+            }
         }
     }
 }
 
+/// Scheduler of the packets of all streams ahead of the dispatcher, as per `BrokerParams`.
 pub struct GlobalBroker {
-    name: Option<String>,
-    pub dispatcher: UdpDispatcher,
-    apps: [GuardedApplications; 4]
+    params: BrokerParams,
+    apps: Vec<Application>,
 }
 
 impl GlobalBroker {
-    pub fn new(name:Option<String> ) -> Self {
-        let apps = [
-            Arc::new(Mutex::new( Vec::<Application>::new() )),
-            Arc::new(Mutex::new( Vec::<Application>::new() )),
-            Arc::new(Mutex::new( Vec::<Application>::new() )),
-            Arc::new(Mutex::new( Vec::<Application>::new() ))
-        ];
-        let dispatcher = UdpDispatcher::new();
+    pub fn new(params: BrokerParams) -> Self {
+        Self { params, apps: Vec::new() }
+    }
 
-        Self { name, dispatcher, apps }
+    /// Take over the sockets of a stream, returning the senders to use instead, one per path,
+    /// along with the count of its packets dropped by the broker.
+    pub fn add(&mut self, name: &str, param: &ConnParams, socket_infos: Vec<PacketSender>) -> (Vec<PacketSender>, Arc<AtomicUsize>) {
+        let (tx, conn) = flume::bounded::<PacketStruct>(BROKER_QUEUE_LENGTH);
+        let senders = vec![tx; socket_infos.len()];
+        let weight = if param.weight > 0.0 { param.weight } else { 1.0 };
+        let drops = Arc::new(AtomicUsize::new(0));
+        self.apps.push(Application {
+            name: name.to_string(), ac: param.access_category().unwrap_or( tos2ac(param.tos) ), weight, deadline: param.target_rtt,
+            conn, socket_infos, head: None, finish: 0.0, deficit: 0.0, drops: drops.clone(),
+        });
+        (senders, drops)
     }
 
-    pub fn add(&mut self, param: ConnParams) -> SourceInput {
-        let tos = param.tos;
-        let priority = param.priority.clone();
-        let links = param.links.clone();
-        let tx_parts = param.tx_parts.clone();
-        let ac = tos2ac( param.tos );
+    pub fn start(&mut self) -> JoinHandle<()> {
+        let params = self.params;
+        let apps = std::mem::take(&mut self.apps);
+        println!("Broker: {:?} over {} streams, rate {} Mbps.", params.policy, apps.len(), params.rate);
+        thread::spawn(move || {
+            broker_thread(params, apps);
+        })
+    }
+}
 
-        let (broker_tx, blocked_signal, tx_part_ctl) = {
-            self.dispatcher.start_new(
-                links,
-                tos, 
-                tx_parts
-            )
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match self.name {
-            None => (broker_tx, blocked_signal, tx_part_ctl),
-            Some(_) => {
-                let (tx, broker_rx) = mpsc::channel::<PacketStruct>();
+    fn packets(timestamps: &[f64], length: u16) -> Vec<PacketStruct> {
+        timestamps.iter().map(|&timestamp| {
+            let mut packet = PacketStruct::new(5202, 0);
+            packet.set_length(length);
+            packet.timestamp = timestamp;
+            packet
+        }).collect()
+    }
 
-                let conn = (broker_rx, broker_tx);
-                let app = Application{ conn, priority };
-                self.apps[ac].lock().unwrap().push( app );
+    // a stream whose source has queued `packets` and is gone
+    fn app(name: &str, tos: u8, weight: f64, deadline: f64, packets: Vec<PacketStruct>) -> Application {
+        let (tx, conn) = flume::unbounded();
+        packets.into_iter().for_each(|packet| tx.send(packet).unwrap());
+        let (socket_tx, _) = flume::unbounded();
+        Application { name: name.to_string(), ac: tos2ac(tos), weight, deadline, conn,
+            socket_infos: vec![socket_tx], head: None, finish: 0.0, deficit: 0.0, drops: Default::default() }
+    }
 
-                (tx, blocked_signal, tx_part_ctl)
-            }
+    // names of the streams in the order their packets are selected, until all are drained
+    fn schedule(policy: BrokerPolicy, apps: Vec<Application>) -> String {
+        run(&mut Scheduler { policy, apps, next: 0, virtual_time: 0.0 })
+    }
+
+    fn run(scheduler: &mut Scheduler) -> String {
+        let mut order = String::new();
+        loop {
+            let virtual_time = scheduler.virtual_time;
+            scheduler.apps.retain_mut(|app| app.fill(virtual_time));
+            let Some(index) = scheduler.select() else { break };
+            order += &scheduler.apps[index].name;
+            scheduler.apps[index].forward();
         }
+        order
     }
 
-    pub fn start(&mut self) -> JoinHandle<()> {
-        let apps:Vec<_> = self.apps.iter().map(|app| app.clone()).collect();
+    #[test]
+    fn priority() {
+        let apps = vec![
+            app("A", 0xC0, 1.0, 0.0, packets(&[0.0; 3], 1000)),
+            app("B", 0x00, 1.0, 0.0, packets(&[0.0; 2], 1000)),
+            app("C", 0xE0, 1.0, 0.0, packets(&[0.0; 2], 1000)),
+        ];
+        // round robin within the highest access category
+        assert_eq!(schedule(BrokerPolicy::Priority, apps), "ACACABB");
+    }
 
-        std::thread::spawn(move || {
-            policy_priority_fifo(apps);
-        })
+    #[test]
+    fn wfq() {
+        let apps = vec![
+            app("A", 0, 2.0, 0.0, packets(&[0.0; 6], 1000)),
+            app("B", 0, 1.0, 0.0, packets(&[0.0; 6], 1000)),
+        ];
+        assert_eq!(schedule(BrokerPolicy::Wfq, apps), "AABAABAABBBB");
+        // by bytes rather than packets
+        let apps = vec![
+            app("A", 0, 1.0, 0.0, packets(&[0.0; 4], 500)),
+            app("B", 0, 1.0, 0.0, packets(&[0.0; 2], 1000)),
+        ];
+        assert_eq!(schedule(BrokerPolicy::Wfq, apps), "AABAAB");
+    }
+
+    #[test]
+    fn drr() {
+        let apps = vec![
+            app("A", 0, 1.0, 0.0, packets(&[0.0; 6], 1000)),
+            app("B", 0, 2.0, 0.0, packets(&[0.0; 9], 1000)),
+        ];
+        let order = schedule(BrokerPolicy::Drr, apps);
+        assert_eq!(order, "ABBBAABBBABBBAA");
+        // quanta of 1500 and 3000 bytes, the deficit carried over the rounds
+        assert_eq!(order[..9].matches('B').count(), 2 * order[..9].matches('A').count());
+    }
+
+    #[test]
+    fn edf() {
+        let apps = vec![
+            app("A", 0xE0, 1.0, 0.1, packets(&[1.0, 1.01], 1000)),
+            app("B", 0x00, 1.0, 0.02, packets(&[1.05, 1.2], 1000)),
+        ];
+        // due at 1.1 and 1.11, and at 1.07 and 1.22, whatever the access category
+        assert_eq!(schedule(BrokerPolicy::Edf, apps), "BAAB");
+    }
+
+    #[test]
+    fn forwards_to_path() {
+        let mut app = app("A", 0, 1.0, 0.0, packets(&[0.0], 1000));
+        let (socket_tx, socket_rx) = flume::unbounded();
+        app.socket_infos = vec![app.socket_infos[0].clone(), socket_tx];
+        assert!(app.fill(0.0));
+        app.head.as_mut().unwrap().path = 1;
+        assert_eq!(app.forward(), Some(1000));
+        assert_eq!(socket_rx.try_recv().unwrap().length, 1000);
+        assert_eq!(app.forward(), None);
+        // drained, its source gone
+        assert!(!app.fill(0.0));
+    }
+
+    #[test]
+    fn holds_back_full_sockets() {
+        for (policy, order) in [(BrokerPolicy::Priority, "AABB"), (BrokerPolicy::Wfq, "AABB"),
+                                (BrokerPolicy::Drr, "BBAA"), (BrokerPolicy::Edf, "AABB")] {
+            // A would come first under each policy, but for its socket
+            let mut apps = vec![
+                app("A", 0xE0, 4.0, 0.01, packets(&[0.0; 2], 1000)),
+                app("B", 0x00, 1.0, 1.0, packets(&[0.0; 2], 1000)),
+            ];
+            let (socket_tx, socket_rx) = flume::unbounded();
+            (0..SOCKET_BACKLOG).for_each(|_| socket_tx.send(PacketStruct::new(5202, 0)).unwrap());
+            apps[0].socket_infos = vec![socket_tx];
+            let mut scheduler = Scheduler { policy, apps, next: 0, virtual_time: 0.0 };
+            scheduler.apps.iter_mut().for_each(|app| assert!(app.fill(0.0)));
+            assert!(!scheduler.apps[0].is_ready());
+            assert_eq!(scheduler.select(), Some(1), "{:?}", policy);
+            // with room again
+            socket_rx.drain();
+            assert_eq!(run(&mut scheduler), order, "{:?}", policy);
+            assert_eq!(socket_rx.len(), 2);
+        }
     }
 
+    #[test]
+    fn counts_drops() {
+        let mut app = app("A", 0, 1.0, 0.0, packets(&[0.0; 3], 1000));
+        let (socket_tx, socket_rx) = flume::bounded(1);
+        app.socket_infos = vec![socket_tx];
+        for path in [0, 0, 1] {
+            assert!(app.fill(0.0));
+            app.head.as_mut().unwrap().path = path;
+            // forced through, as if the socket filled up since `is_ready`
+            assert_eq!(app.forward(), Some(1000));
+        }
+        assert_eq!((socket_rx.len(), app.drops.load(Ordering::Relaxed)), (1, 2));
+    }
 }
@@ -3,15 +3,28 @@ use rand::prelude::*;
 use rand::distributions::Standard;
 use serde::{Serialize, Deserialize};
 
+use core::packet::{max_payload_len, tos2ac, udp_max_length, RtpParams, APP_HEADER_LENGTH, CHECKSUM_LENGTH, ETHERNET_MTU, RTP_OVERHEAD};
+use core::fec::{FecParams, REPAIR_OVERHEAD};
+use core::crypto::{PayloadCipher, CIPHER_OVERHEAD};
+use core::pcap::FlowFilter;
+use core::quic::{QuicMapping, QUIC_DATAGRAM_OVERHEAD};
 use crate::{link::Link, source::STREAM_PROTO};
+use crate::broker::BrokerParams;
+use crate::tx_part_ctl::ControlParams;
+use crate::rate_ctl::RateControlParams;
+use crate::arq::ArqParams;
+use crate::throttle::{PacingParams, QueueParams, StaleParams};
+use crate::limiter::LimiterParams;
+use crate::generator::{CbrParams, FrameGenerator, OnOffParams, PoissonParams, VideoParams};
 
 const fn _default_duration() -> [f64; 2] { [0.0, f64::MAX] }
 const fn _default_loops() -> usize { usize::MAX }
+const fn _default_weight() -> f64 { 1.0 }
 fn _random_value<T>() -> T where Standard: Distribution<T> { rand::thread_rng().gen() }
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct ConnParams {
-    pub npy_file: String,
+    #[serde(default)] pub npy_file: String, //default: "", for generated streams
     #[serde(default = "_random_value")]     //default:
     pub port: u16,                          //         <random>
     #[serde(default = "_default_duration")] //default:
@@ -24,43 +37,188 @@ pub struct ConnParams {
     pub loops: usize,                       //         +inf
     #[serde(default)] pub tos: u8,          //default: 0
     #[serde(default)] pub throttle: f64,    //default: 0.0
-    #[serde(default)] pub priority: String, //default: ""
+    #[serde(default)] pub limiter: LimiterParams, //default: {"type": "window"}
+    #[serde(default)] pub rate_control: Option<RateControlParams>, //default: null, fixed `throttle`
+    #[serde(default)] pub drop_stale: Option<StaleParams>, //default: null, frames sent however late
+    #[serde(default)] pub pacing: PacingParams, //default: {"mode": "burst"}
+    #[serde(default)] pub queue: QueueParams, //default: {"overflow": "drop_tail"}, `CYCLED_RATIO * window_size` packets
+    #[serde(default)] pub priority: String, //default: "", the access category of `tos`, else "VO", "VI", "BE" or "BK"
+    #[serde(default = "_default_weight")]   //default:
+    pub weight: f64,                        //         1.0, share under the "wfq" and "drr" broker policies
     #[serde(default)] pub calc_rtt: bool,   //default: false
     #[serde(default)] pub no_logging: bool, //default: false
     #[serde(default)] pub links: Vec<Link>, //default: [[]]
     #[serde(default)] pub tx_parts: Vec<f64>, //default: []
+    #[serde(default)] pub tx_part_control: Option<ControlParams>, //default: null, fixed `tx_parts`
+    #[serde(default)] pub mtu: Option<usize>, //default: 1500, unless given per link
+    #[serde(default)] pub fragment_size: Option<usize>, //default: <largest fitting all links>
+    #[serde(default)] pub checksum: bool,   //default: false
+    #[serde(default)] pub fec: Option<FecParams>, //default: null
+    #[serde(default)] pub arq: Option<ArqParams>, //default: null, no retransmission
+    #[serde(default)] pub key: Option<String>, //default: null, 64 hex digits to encrypt payloads
+    #[serde(default)] pub pcap_filter: FlowFilter, //default: {}, any flow of a .pcap/.pcapng file
+    #[serde(default)] pub replay_payload: bool, //default: false, only timing and sizes
+    #[serde(default)] pub rtp: Option<RtpParams>, //default: null, native framing
 }
 
+impl ConnParams {
+    /// Header extensions and repair shard prefix, beyond `APP_HEADER_LENGTH`.
+    pub fn overhead(&self) -> usize {
+        (if self.checksum { CHECKSUM_LENGTH } else { 0 })
+            + (if self.fec.is_some() { REPAIR_OVERHEAD } else { 0 })
+            + (if self.key.is_some() { CIPHER_OVERHEAD } else { 0 })
+            + (if self.rtp.is_some() { RTP_OVERHEAD } else { 0 })
+    }
+
+    /// Fragment payload size, `fragment_size` if given, otherwise the largest fitting the MTU of every link.
+    pub fn payload_len(&self) -> usize {
+        if let Some(fragment_size) = self.fragment_size {
+            return fragment_size;
+        }
+        let mtu = self.mtu.unwrap_or(ETHERNET_MTU);
+        self.links.iter()
+            .map(|link| max_payload_len(link.mtu.unwrap_or(mtu), link.is_ipv6()))
+            .min()
+            .unwrap_or( max_payload_len(mtu, false) )
+            .saturating_sub(self.overhead())
+    }
 
+    /// Access category ranking the stream under the "priority" broker policy, from 0 (VO) to 3 (BK).
+    pub fn access_category(&self) -> Option<usize> {
+        match self.priority.to_uppercase().as_str() {
+            "" => Some( tos2ac(self.tos) ),
+            "VO" => Some(0),
+            "VI" => Some(1),
+            "BE" => Some(2),
+            "BK" => Some(3),
+            _ => None,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum StreamParam {
     TCP(ConnParams),
-    UDP(ConnParams)
+    UDP(ConnParams),
+    // a single kernel MPTCP connection, scheduled over the subflows instead of `tx_parts`
+    MPTCP(ConnParams),
+    // QUIC connections, one per link, carrying the fragments as DATAGRAM frames or streams
+    QUIC(QuicStream),
+    // synthetic frames sent over UDP, instead of replaying `npy_file`
+    CBR(Generated<CbrParams>),
+    Poisson(Generated<PoissonParams>),
+    OnOff(Generated<OnOffParams>),
+    Video(Generated<VideoParams>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Generated<G> {
+    #[serde(flatten)] pub params: ConnParams,
+    #[serde(flatten)] pub generator: G,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuicStream {
+    #[serde(flatten)] pub params: ConnParams,
+    #[serde(default)] pub mapping: QuicMapping, //default: "datagram"
 }
 
 impl std::fmt::Display for StreamParam {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let (_type, _param) =
-        match self {
-            Self::TCP(p) => ("TCP", p),
-            Self::UDP(p) => ("UDP", p)
+        let _param = self.params();
+        let _source = match self {
+            Self::TCP(_) | Self::UDP(_) | Self::MPTCP(_) => format!("file: \"{}\"", _param.npy_file),
+            Self::QUIC(q) => format!("file: \"{}\", mapping: {:?}", _param.npy_file, q.mapping),
+            Self::CBR(g) => serde_json::to_string(&g.generator).unwrap_or_default(),
+            Self::Poisson(g) => serde_json::to_string(&g.generator).unwrap_or_default(),
+            Self::OnOff(g) => serde_json::to_string(&g.generator).unwrap_or_default(),
+            Self::Video(g) => serde_json::to_string(&g.generator).unwrap_or_default(),
         };
-        let _file:String = _param.npy_file.clone();
 
         write!(f,
-            "{type} {{ port: {port}, tos: {tos}, throttle: {throttle} Mbps, file: \"{file}\", loops: {loops} }}",
-            type=_type, port=_param.port, tos=_param.tos, throttle=_param.throttle, loops=_param.loops as isize, file=_file
+            "{type} {{ port: {port}, tos: {tos}, throttle: {throttle} Mbps, {source}, loops: {loops} }}",
+            type=self.type_name(), port=_param.port, tos=_param.tos, throttle=_param.throttle, loops=_param.loops as isize, source=_source
         )
     }
 }
 
 impl StreamParam {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::TCP(_) => "TCP",
+            Self::UDP(_) => "UDP",
+            Self::MPTCP(_) => "MPTCP",
+            Self::QUIC(_) => "QUIC",
+            Self::CBR(_) => "CBR",
+            Self::Poisson(_) => "Poisson",
+            Self::OnOff(_) => "OnOff",
+            Self::Video(_) => "Video",
+        }
+    }
+
+    pub fn params(&self) -> &ConnParams {
+        match self {
+            Self::TCP(p) | Self::UDP(p) | Self::MPTCP(p) => p,
+            Self::QUIC(q) => &q.params,
+            Self::CBR(g) => &g.params,
+            Self::Poisson(g) => &g.params,
+            Self::OnOff(g) => &g.params,
+            Self::Video(g) => &g.params,
+        }
+    }
+
+    pub fn params_mut(&mut self) -> &mut ConnParams {
+        match self {
+            Self::TCP(p) | Self::UDP(p) | Self::MPTCP(p) => p,
+            Self::QUIC(q) => &mut q.params,
+            Self::CBR(g) => &mut g.params,
+            Self::Poisson(g) => &mut g.params,
+            Self::OnOff(g) => &mut g.params,
+            Self::Video(g) => &mut g.params,
+        }
+    }
+
+    /// A fresh generator for synthetic streams, `None` when replaying `npy_file`.
+    pub fn generator(&self) -> Option<Box<dyn FrameGenerator>> {
+        match self {
+            Self::TCP(_) | Self::UDP(_) | Self::MPTCP(_) | Self::QUIC(_) => None,
+            Self::CBR(g) => Some(Box::new(g.generator.clone())),
+            Self::Poisson(g) => Some(Box::new(g.generator.clone())),
+            Self::OnOff(g) => Some(Box::new(g.generator.clone())),
+            Self::Video(g) => Some(Box::new(g.generator.clone())),
+        }
+    }
+
+    fn is_generator_valid(&self) -> bool {
+        match self {
+            Self::TCP(_) | Self::UDP(_) | Self::MPTCP(_) | Self::QUIC(_) => true,
+            Self::CBR(g) => g.generator.is_valid(),
+            Self::Poisson(g) => g.generator.is_valid(),
+            Self::OnOff(g) => g.generator.is_valid(),
+            Self::Video(g) => g.generator.is_valid(),
+        }
+    }
+
     pub fn validate(mut self, root:Option<&Path>, duration:f64) -> Option<Self> {
-        let ( Self::TCP(ref mut param) | Self::UDP(ref mut param) ) = self;
+        // validate generator parameters
+        if !self.is_generator_valid() {
+            eprintln!("Invalid {} parameters for port {}.", self.type_name(), self.params().port);
+            return None;
+        }
+        let is_generated = self.generator().is_some();
+        let is_mptcp = matches!(self, Self::MPTCP(_));
+        // leave room for the QUIC packet around each fragment
+        if let Self::QUIC(QuicStream { params, mapping: QuicMapping::Datagram }) = &mut self {
+            if params.fragment_size.is_none() {
+                params.fragment_size = Some( params.payload_len().saturating_sub(QUIC_DATAGRAM_OVERHEAD) );
+            }
+        }
+        let param = self.params_mut();
 
         // validate npy file existence
-        if !param.npy_file.starts_with(STREAM_PROTO) {
+        if !is_generated && !param.npy_file.starts_with(STREAM_PROTO) {
             let cwd = std::env::current_dir().unwrap();
             let path_trail1 = cwd.join( &param.npy_file );
             let path_trail2 = root.unwrap_or( cwd.as_path() ).join( &param.npy_file );
@@ -75,6 +233,110 @@ impl StreamParam {
             }
         }
 
+        // validate fragment size
+        let payload_len = param.payload_len();
+        if payload_len == 0 || APP_HEADER_LENGTH + param.overhead() + payload_len > udp_max_length(u16::MAX as usize, false) {
+            eprintln!("Invalid fragment size {} for port {}.", payload_len, param.port);
+            return None;
+        }
+
+        // validate FEC
+        if param.fec.as_ref().is_some_and(|fec| !fec.is_valid()) {
+            eprintln!("Invalid FEC parity for port {}.", param.port);
+            return None;
+        }
+
+        // validate ARQ, driven by the NACKs on the pong channel
+        if let Some(ref arq) = param.arq {
+            if !arq.is_valid() {
+                eprintln!("Invalid arq parameters for port {}.", param.port);
+                return None;
+            }
+            if !param.calc_rtt {
+                eprintln!("arq requires calc_rtt for port {}.", param.port);
+                return None;
+            }
+        }
+
+        // the frames of an MPTCP stream all take the single connection from its first link,
+        // scheduled by the kernel over the subflows of its path manager
+        if is_mptcp {
+            if param.tx_part_control.is_some() {
+                eprintln!("tx_part_control is not supported by MPTCP streams for port {}.", param.port);
+                return None;
+            }
+            if param.tx_parts.len() > 1 {
+                eprintln!("tx_parts ignored for MPTCP port {}, the kernel schedules the frames.", param.port);
+            }
+            for link in param.links.iter().skip(1) {
+                eprintln!("Link {} -> {} ignored for MPTCP port {}, add it with `ip mptcp endpoint` instead.",
+                    link.tx_ipaddr, link.rx_ipaddr, param.port);
+            }
+            param.links.truncate(1);
+            param.tx_parts = vec![1.0; param.links.len()];
+        }
+
+        // validate broker priority, else ranked by `tos`
+        if param.access_category().is_none() {
+            eprintln!("Invalid priority {:?} for port {}, expecting \"VO\", \"VI\", \"BE\" or \"BK\"; using the access category of tos {}.",
+                param.priority, param.port, param.tos);
+            param.priority.clear();
+        }
+
+        // validate tx_parts controller, driven by the RTT of each link
+        if let Some(ref control) = param.tx_part_control {
+            if !control.is_valid() {
+                eprintln!("Invalid tx_part_control parameters for port {}.", param.port);
+                return None;
+            }
+            if !param.calc_rtt {
+                eprintln!("tx_part_control requires calc_rtt for port {}.", param.port);
+                return None;
+            }
+        }
+
+        // validate rate limiter
+        if !param.limiter.is_valid() {
+            eprintln!("Invalid limiter parameters for port {}.", param.port);
+            return None;
+        }
+
+        // validate intra-frame pacing
+        if !param.pacing.is_valid() {
+            eprintln!("Invalid pacing parameters for port {}.", param.port);
+            return None;
+        }
+
+        // validate throttler queue
+        if !param.queue.is_valid() {
+            eprintln!("Invalid queue parameters for port {}.", param.port);
+            return None;
+        }
+
+        // validate stale frame dropping
+        if param.drop_stale.as_ref().is_some_and(|stale| !stale.is_valid()) {
+            eprintln!("Invalid drop_stale parameters for port {}.", param.port);
+            return None;
+        }
+
+        // validate rate controller, driven by the ACKs
+        if let Some(ref control) = param.rate_control {
+            if !control.is_valid() {
+                eprintln!("Invalid rate_control parameters for port {}.", param.port);
+                return None;
+            }
+            if !param.calc_rtt {
+                eprintln!("rate_control requires calc_rtt for port {}.", param.port);
+                return None;
+            }
+        }
+
+        // validate pre-shared key
+        if param.key.as_deref().is_some_and(|key| PayloadCipher::from_hex(key).is_none()) {
+            eprintln!("Invalid key for port {}, expecting 64 hex digits.", param.port);
+            return None;
+        }
+
         // validate duration
         if param.duration[1] > duration {
             param.duration[1] = duration;
@@ -88,10 +350,8 @@ impl StreamParam {
     }
 
     pub fn name(&self) -> String {
-        match self {
-            StreamParam::TCP(params) => format!("{}@{}", params.port, params.tos),
-            StreamParam::UDP(params) => format!("{}@{}", params.port, params.tos),
-        }
+        let params = self.params();
+        format!("{}@{}", params.port, params.tos)
     }
 
 }
@@ -100,7 +360,116 @@ impl StreamParam {
 pub struct Manifest {
     pub use_agg_socket: Option<bool>,
     pub orchestrator: Option<String>,
+    pub broker: Option<BrokerParams>, // schedules the packets of all streams, if any
     pub window_size: usize,
     pub streams: Vec<StreamParam>,
     pub tx_ipaddrs: Vec<String>
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::packet::MAX_PAYLOAD_LEN;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn params(extra: &str) -> ConnParams {
+        let json = format!(r#"{{ "npy_file": "{}cbr", "port": 5202 {} }}"#, STREAM_PROTO, extra);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn payload_len() {
+        assert_eq!(params("").payload_len(), MAX_PAYLOAD_LEN);
+        assert_eq!(params(r#", "mtu": 9000"#).payload_len(), 9000 - 28 - APP_HEADER_LENGTH);
+        assert_eq!(params(r#", "mtu": 9000, "fragment_size": 1000"#).payload_len(), 1000);
+        assert_eq!(params(r#", "checksum": true"#).payload_len(), MAX_PAYLOAD_LEN - CHECKSUM_LENGTH);
+        let fec = r#", "checksum": true, "fec": { "scheme": "xor" }"#;
+        assert_eq!(params(fec).payload_len(), MAX_PAYLOAD_LEN - CHECKSUM_LENGTH - REPAIR_OVERHEAD);
+        let key = format!(r#", "key": "{}""#, KEY);
+        assert_eq!(params(&key).payload_len(), MAX_PAYLOAD_LEN - CIPHER_OVERHEAD);
+        assert_eq!(params(r#", "rtp": {}"#).payload_len(), MAX_PAYLOAD_LEN - RTP_OVERHEAD);
+        // the smallest over all links
+        let links = r#", "mtu": 9000, "links": [["0.0.0.0", "10.0.0.2"], ["::", "fe80::2", 1500]]"#;
+        assert_eq!(params(links).payload_len(), 1500 - 48 - APP_HEADER_LENGTH);
+    }
+
+    #[test]
+    fn rejects_fragment_size() {
+        let stream = |extra| StreamParam::UDP(params(extra)).validate(None, 10.0);
+        assert!(stream(r#", "fragment_size": 65483"#).is_some());
+        assert!(stream(r#", "fragment_size": 65484"#).is_none());
+        // room left for the header extensions
+        assert!(stream(r#", "fragment_size": 65479, "checksum": true"#).is_some());
+        assert!(stream(r#", "fragment_size": 65480, "checksum": true"#).is_none());
+        assert!(stream(r#", "fragment_size": 0"#).is_none());
+    }
+
+    #[test]
+    fn rejects_fec_parity() {
+        let stream = |extra| StreamParam::UDP(params(extra)).validate(None, 10.0);
+        assert!(stream(r#", "fec": { "scheme": "rs", "parity": 255 }"#).is_some());
+        assert!(stream(r#", "fec": { "scheme": "rs", "parity": 256 }"#).is_none());
+        assert!(stream(r#", "fec": { "scheme": "rs", "parity": 0 }"#).is_none());
+        assert!(stream(r#", "fec": { "scheme": "xor", "parity": 0 }"#).is_some());
+    }
+
+    #[test]
+    fn access_category() {
+        // of `tos` unless given
+        assert_eq!(params(r#", "tos": 192"#).access_category(), Some(tos2ac(192)));
+        assert_eq!(params(r#", "tos": 192, "priority": "bk""#).access_category(), Some(3));
+        assert_eq!(params(r#", "priority": "VI""#).access_category(), Some(1));
+        assert_eq!(params(r#", "priority": "high""#).access_category(), None);
+        let stream = StreamParam::UDP(params(r#", "tos": 192, "priority": "high""#)).validate(None, 10.0).unwrap();
+        assert_eq!(stream.params().access_category(), Some(tos2ac(192)));
+    }
+
+    #[test]
+    fn rejects_key() {
+        let stream = |key: &str| StreamParam::UDP(params(&format!(r#", "key": "{}""#, key))).validate(None, 10.0);
+        assert!(stream(KEY).is_some());
+        assert!(stream(&KEY[1..]).is_none());
+        assert!(stream(&KEY.replace('a', "x")).is_none());
+    }
+
+    #[test]
+    fn generated_streams() {
+        let stream = |json: &str| serde_json::from_str::<StreamParam>(json).unwrap().validate(None, 10.0);
+        let cbr = stream(r#"{ "type": "CBR", "port": 5202, "bitrate": 8.0 }"#).unwrap();
+        assert_eq!((cbr.type_name(), cbr.name()), ("CBR", String::from("5202@0")));
+        assert_eq!(cbr.generator().unwrap().next_frame(), (33_333_333, 33_333));
+        let video = stream(r#"{ "type": "Video", "port": 5202, "i_size": { "mean": 1000 }, "p_size": { "mean": 100 } }"#).unwrap();
+        assert_eq!(video.generator().unwrap().next_frame(), (33_333_333, 1000));
+        assert!(stream(r#"{ "type": "Poisson", "port": 5202, "rate": 0.0, "size": 1000 }"#).is_none());
+    }
+
+    #[test]
+    fn mptcp_stream() {
+        let json = format!(r#"{{ "type": "MPTCP", "npy_file": "{}cbr", "port": 5202 }}"#, STREAM_PROTO);
+        let mptcp = serde_json::from_str::<StreamParam>(&json).unwrap().validate(None, 10.0).unwrap();
+        assert_eq!(mptcp.type_name(), "MPTCP");
+        assert!(mptcp.generator().is_none());
+        assert!(mptcp.to_string().starts_with("MPTCP { port: 5202"));
+
+        // the kernel schedules the frames over the connection from the first link
+        let links = r#""links": [["127.0.0.1", "127.0.0.1"], ["127.0.0.2", "127.0.0.1"]], "tx_parts": [0.5, 0.5], "calc_rtt": true"#;
+        let json = format!(r#"{{ "type": "MPTCP", "npy_file": "{}cbr", "port": 5202, {} }}"#, STREAM_PROTO, links);
+        let mptcp = serde_json::from_str::<StreamParam>(&json).unwrap().validate(None, 10.0).unwrap();
+        assert_eq!((mptcp.params().links.len(), mptcp.params().tx_parts.clone()), (1, vec![1.0]));
+        let json = format!(r#"{{ "type": "MPTCP", "npy_file": "{}cbr", "port": 5202, {}, "tx_part_control": {{}} }}"#, STREAM_PROTO, links);
+        assert!(serde_json::from_str::<StreamParam>(&json).unwrap().validate(None, 10.0).is_none());
+    }
+
+    #[test]
+    fn quic_fragment_size() {
+        let quic = |extra: &str| {
+            let json = format!(r#"{{ "type": "QUIC", "npy_file": "{}cbr", "port": 5202 {} }}"#, STREAM_PROTO, extra);
+            serde_json::from_str::<StreamParam>(&json).unwrap().validate(None, 10.0).unwrap()
+        };
+        // room left for the QUIC packet around each DATAGRAM frame
+        assert_eq!(quic("").params().payload_len(), MAX_PAYLOAD_LEN - QUIC_DATAGRAM_OVERHEAD);
+        assert_eq!(quic(r#", "mapping": "stream""#).params().payload_len(), MAX_PAYLOAD_LEN);
+        assert_eq!(quic(r#", "fragment_size": 1000"#).params().payload_len(), 1000);
+    }
+}
@@ -1,65 +1,300 @@
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::thread::{self};
-use std::net::ToSocketAddrs;
 use std::time::{Duration, SystemTime};
 use log::trace;
 
 use crate::link::Link;
 
-use core::packet::{self, any_as_u8_slice, PacketReceiver, PacketStruct, APP_HEADER_LENGTH};
+use core::packet::{PacketReceiver, PacketStruct};
+use core::crypto::PayloadCipher;
+use core::pcap::PcapWriter;
+use core::packet::udp_max_length;
+use core::quic::{self, QuicMapping};
 use core::socket::{*};
-use std::net::UdpSocket;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
 
-pub fn dispatch(links: Vec<Link>, tos:u8) -> HashMap<String, flume::Sender<PacketStruct>> {
-    // create Hashmap for each tx_ipaddr and set each non blocking
-    let mut socket_infos = HashMap::new();
+type GuardedPcapWriter = Arc<Mutex<PcapWriter>>;
+
+pub fn dispatch(links: Vec<Link>, tos:u8, cipher: Option<PayloadCipher>, pcap: Option<GuardedPcapWriter>) -> Option<Vec<flume::Sender<PacketStruct>>> {
+    // create a sender for each link, indexed by path, and set each socket non blocking;
+    // none at all if any link fails, the paths of the packets relying on all of them
+    let mut socket_infos = Vec::new();
 
     let mut handles = Vec::new();
-    for link in links.iter() {
+    for (path, link) in links.iter().enumerate() {
         let tx_ipaddr = link.tx_ipaddr.clone();
-        let rx_addr =  format!("{}:0",link.rx_ipaddr.clone()).to_socket_addrs().unwrap().next().unwrap();
+        let rx_addr = match link.rx_addr() {
+            Some(rx_addr) => rx_addr,
+            None => {
+                eprintln!("Invalid receiver address: ip_addr {}.", link.rx_ipaddr);
+                return None;
+            }
+        };
         let socket = create_udp_socket(tos, tx_ipaddr.clone());
         let (socket_tx, socket_rx) = flume::bounded::<PacketStruct>(50000);
         if let Some(socket) = socket {
             socket.set_nonblocking(true).unwrap();
-            socket_infos.insert(tx_ipaddr.clone(),  socket_tx );
+            socket_infos.push( socket_tx );
+            let cipher = cipher.clone();
+            let pcap = pcap.clone().map(|pcap| (pcap, path as u32, tos));
             let _handle = thread::spawn(move || {
                 let socket = socket.try_clone().unwrap();
-                socket_thread(socket, socket_rx, rx_addr);
+                socket_thread(socket, socket_rx, rx_addr, cipher, pcap);
             });
             handles.push(_handle);
         }
         else{
             eprintln!("Socket creation failure: ip_addr {} tos {}.", tx_ipaddr, tos);
+            return None;
+        }
+    }
+    Some(socket_infos)
+}
+
+/// As `dispatch`, over one TCP connection per link to `port` of the receiver,
+/// along with a handle on each connection to sample its `TCP_INFO`.
+pub fn dispatch_tcp(links: Vec<Link>, tos:u8, cipher: Option<PayloadCipher>, port: u16) -> Option<(Vec<flume::Sender<PacketStruct>>, Vec<TcpStream>)> {
+    let mut socket_infos = Vec::new();
+    let mut streams = Vec::new();
+
+    for link in links.iter() {
+        let mut rx_addr = match link.rx_addr() {
+            Some(rx_addr) => rx_addr,
+            None => {
+                eprintln!("Invalid receiver address: ip_addr {}.", link.rx_ipaddr);
+                return None;
+            }
+        };
+        rx_addr.set_port(port);
+        let stream = match create_tcp_stream(tos, &link.tx_ipaddr, rx_addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Connection failure: ip_addr {} -> {} tos {}: {}.", link.tx_ipaddr, rx_addr, tos, e);
+                return None;
+            }
+        };
+        let (socket_tx, socket_rx) = flume::bounded::<PacketStruct>(50000);
+        socket_infos.push( socket_tx );
+        streams.push( stream.try_clone().unwrap() );
+        let cipher = cipher.clone();
+        thread::spawn(move || {
+            tcp_socket_thread(stream, socket_rx, rx_addr, cipher);
+        });
+    }
+    Some((socket_infos, streams))
+}
+
+/// As `dispatch_tcp`, over a single MPTCP connection from the first link, whose subflows
+/// over the other links are left to the kernel path manager.
+pub fn dispatch_mptcp(links: Vec<Link>, tos:u8, cipher: Option<PayloadCipher>, port: u16) -> Option<Vec<flume::Sender<PacketStruct>>> {
+    let link = links.first()?;
+    let mut rx_addr = match link.rx_addr() {
+        Some(rx_addr) => rx_addr,
+        None => {
+            eprintln!("Invalid receiver address: ip_addr {}.", link.rx_ipaddr);
+            return None;
+        }
+    };
+    rx_addr.set_port(port);
+    let stream = match create_mptcp_stream(tos, &link.tx_ipaddr, rx_addr) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Connection failure: ip_addr {} -> {} tos {}: {}.", link.tx_ipaddr, rx_addr, tos, e);
+            return None;
+        }
+    };
+    let (socket_tx, socket_rx) = flume::bounded::<PacketStruct>(50000);
+    thread::spawn(move || {
+        tcp_socket_thread(stream, socket_rx, rx_addr, cipher);
+    });
+    Some(vec![socket_tx])
+}
+
+/// As `dispatch_tcp`, over one QUIC connection per link, each fragment mapped per `mapping`.
+pub fn dispatch_quic(links: Vec<Link>, tos:u8, cipher: Option<PayloadCipher>, port: u16, mtu: usize, mapping: QuicMapping) -> Option<Vec<flume::Sender<PacketStruct>>> {
+    let mut socket_infos = Vec::new();
+
+    for link in links.iter() {
+        let mut rx_addr = match link.rx_addr() {
+            Some(rx_addr) => rx_addr,
+            None => {
+                eprintln!("Invalid receiver address: ip_addr {}.", link.rx_ipaddr);
+                return None;
+            }
+        };
+        rx_addr.set_port(port);
+        let socket = match create_udp_socket(tos, link.tx_ipaddr.clone()) {
+            Some(socket) => socket,
+            None => {
+                eprintln!("Socket creation failure: ip_addr {} tos {}.", link.tx_ipaddr, tos);
+                return None;
+            }
+        };
+        socket.set_nonblocking(true).unwrap();
+        let initial_mtu = udp_max_length(link.mtu.unwrap_or(mtu), rx_addr.is_ipv6()) as u16;
+
+        // the connection is driven by the thread, which reports the handshake outcome
+        let (socket_tx, socket_rx) = flume::bounded::<PacketStruct>(50000);
+        let (ready_tx, ready_rx) = flume::bounded::<Result<(), String>>(1);
+        let cipher = cipher.clone();
+        thread::spawn(move || {
+            let runtime = match quic::runtime() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e.to_string()));
+                    return;
+                }
+            };
+            runtime.block_on(async move {
+                let connection = match quic_connect(socket, rx_addr, initial_mtu).await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                quic_socket_thread(connection, socket_rx, cipher, mapping).await;
+            });
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => socket_infos.push( socket_tx ),
+            Ok(Err(e)) => {
+                eprintln!("Connection failure: ip_addr {} -> {} tos {}: {}.", link.tx_ipaddr, rx_addr, tos, e);
+                return None;
+            }
+            Err(_) => return None,
+        }
+    }
+    Some(socket_infos)
+}
+
+async fn quic_connect(socket: UdpSocket, rx_addr: std::net::SocketAddr, initial_mtu: u16) -> Result<quinn::Connection, String> {
+    let config = quic::client_config(Some(initial_mtu))?;
+    let runtime = Arc::new(quinn::TokioRuntime);
+    let mut endpoint = quinn::Endpoint::new(quinn::EndpointConfig::default(), None, socket, runtime)
+        .map_err(|e| e.to_string())?;
+    endpoint.set_default_client_config(config);
+    let connecting = endpoint.connect(rx_addr, quic::SERVER_NAME).map_err(|e| e.to_string())?;
+    connecting.await.map_err(|e| e.to_string())
+}
+
+// Datagrams as DATAGRAM frames, or written as over TCP to the stream of their frame,
+// the stream being finished once the next frame starts.
+async fn quic_socket_thread(connection: quinn::Connection, rx:PacketReceiver, cipher: Option<PayloadCipher>, mapping: QuicMapping) {
+    let addr = connection.remote_address();
+    let mut stream: Option<(u32, quinn::SendStream)> = None;
+    let mut too_large = false;
+    let mut buf = Vec::new();
+    while let Ok(packet) = rx.recv_async().await {
+        buf.resize(TCP_LENGTH_PREFIX + packet.wire_length(), 0);
+        let length = match packet.encode(&mut buf[TCP_LENGTH_PREFIX..], cipher.as_ref()) {
+            Ok(length) => length,
+            Err(e) => {
+                eprintln!("Packet encoding failure: seq {} offset {}: {}.", packet.seq, packet.offset, e);
+                continue;
+            }
+        };
+
+        let res = match mapping {
+            QuicMapping::Datagram => {
+                match connection.send_datagram(buf[TCP_LENGTH_PREFIX..TCP_LENGTH_PREFIX + length].to_vec().into()) {
+                    Err(quinn::SendDatagramError::TooLarge) => {
+                        if !too_large {
+                            eprintln!("Datagram of {} bytes too large for {} (max {:?}), reduce fragment_size.", length, addr, connection.max_datagram_size());
+                            too_large = true;
+                        }
+                        Ok(())
+                    }
+                    res => res.map_err(|e| e.to_string()),
+                }
+            }
+            QuicMapping::Stream => {
+                buf[..TCP_LENGTH_PREFIX].copy_from_slice(&(length as u32).to_le_bytes());
+                if stream.as_ref().is_none_or(|(seq, _)| *seq != packet.seq) {
+                    if let Some((_, mut last)) = stream.take() {
+                        let _ = last.finish();
+                    }
+                    match connection.open_uni().await {
+                        Ok(send) => stream = Some((packet.seq, send)),
+                        Err(e) => {
+                            eprintln!("Connection to {} lost: {}.", addr, e);
+                            break;
+                        }
+                    }
+                }
+                let (_, send) = stream.as_mut().unwrap();
+                send.write_all(&buf[..TCP_LENGTH_PREFIX + length]).await.map_err(|e| e.to_string())
+            }
+        };
+        if let Err(e) = res {
+            eprintln!("Connection to {} lost: {}.", addr, e);
             break;
         }
+        if packet.is_last_of_path() {
+            trace!("Socket: Time {} -> seq {}-offset {}-path {}-ip_addr {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() , packet.seq, packet.offset, packet.path, addr);
+        }
     }
-    socket_infos
 }
 
-fn socket_thread(sock: UdpSocket, rx:PacketReceiver, mut addr:std::net::SocketAddr) {
+// Each packet is written as its datagram, preceded by its length (u32, little-endian).
+fn tcp_socket_thread(mut stream: TcpStream, rx:PacketReceiver, addr:std::net::SocketAddr, cipher: Option<PayloadCipher>) {
+    let mut buf = Vec::new();
+    for packet in rx.iter() {
+        buf.resize(TCP_LENGTH_PREFIX + packet.wire_length(), 0);
+        let length = match packet.encode(&mut buf[TCP_LENGTH_PREFIX..], cipher.as_ref()) {
+            Ok(length) => length,
+            Err(e) => {
+                eprintln!("Packet encoding failure: seq {} offset {}: {}.", packet.seq, packet.offset, e);
+                continue;
+            }
+        };
+        buf[..TCP_LENGTH_PREFIX].copy_from_slice(&(length as u32).to_le_bytes());
+        if let Err(e) = stream.write_all(&buf[..TCP_LENGTH_PREFIX + length]) {
+            eprintln!("Connection to {} lost: {}.", addr, e);
+            break;
+        }
+        if packet.is_last_of_path() {
+            trace!("Socket: Time {} -> seq {}-offset {}-path {}-ip_addr {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() , packet.seq, packet.offset, packet.path, addr);
+        }
+    }
+}
+
+fn socket_thread(sock: UdpSocket, rx:PacketReceiver, mut addr:std::net::SocketAddr, cipher: Option<PayloadCipher>,
+    pcap: Option<(GuardedPcapWriter, u32, u8)>) {
+    let local_addr = sock.local_addr().unwrap();
 
     let spin_sleeper = spin_sleep::SpinSleeper::new(10_000)
     .with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
 
+    let mut buf = Vec::new();
     loop {
         let packets:Vec<_> = rx.try_iter().collect();
-        if packets.len()==0 {
+        if packets.is_empty() {
             spin_sleeper.sleep(Duration::from_nanos(100_000));
             continue;
         }
         for packet in packets.iter() {
-            let length = APP_HEADER_LENGTH + packet.length as usize;
-            let buf = unsafe{ any_as_u8_slice(packet) };
+            buf.resize(packet.wire_length(), 0);
+            let length = match packet.encode(&mut buf, cipher.as_ref()) {
+                Ok(length) => length,
+                Err(e) => {
+                    eprintln!("Packet encoding failure: seq {} offset {}: {}.", packet.seq, packet.offset, e);
+                    continue;
+                }
+            };
             loop {
                 addr.set_port( packet.port );
-                match sock.send_to(&buf[..length], &addr) {
+                match sock.send_to(&buf[..length], addr) {
                     Ok(_len) => {
-                        match packet::get_packet_type(packet.indicators) {
-                            packet::PacketType::SL | packet::PacketType::DSL | packet::PacketType::DFL => {
-                                trace!("Socket: Time {} -> seq {}-offset {}-ip_addr {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() , packet.seq as u32, packet.offset as u16, addr);
+                        if let Some((ref pcap, interface, tos)) = pcap {
+                            if let Err(e) = pcap.lock().unwrap().write(interface, local_addr, addr, tos, &buf[..length]) {
+                                eprintln!("Pcap write failure: {}.", e);
                             }
-                            _ => {}
+                        }
+                        if packet.is_last_of_path() {
+                            trace!("Socket: Time {} -> seq {}-offset {}-path {}-ip_addr {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() , packet.seq, packet.offset, packet.path, addr);
                         }
                         break
                     }
@@ -72,4 +307,110 @@ fn socket_thread(sock: UdpSocket, rx:PacketReceiver, mut addr:std::net::SocketAd
             } 
         }   
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn frames_tcp_datagrams() {
+        let listener = bind_tcp_listener("127.0.0.1", 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let link = Link { tx_ipaddr: String::from("127.0.0.1"), rx_ipaddr: String::from("127.0.0.1"), mtu: None };
+        let (senders, streams) = dispatch_tcp(vec![link], 0, None, port).unwrap();
+        assert_eq!((senders.len(), streams.len()), (1, 1));
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        for (seq, length) in [(7, 100), (8, 0)] {
+            let mut packet = PacketStruct::new(port, 100);
+            packet.seq = seq;
+            packet.set_length(length);
+            senders[0].send(packet).unwrap();
+        }
+        for (seq, length) in [(7, 100), (8, 0)] {
+            let mut prefix = [0u8; TCP_LENGTH_PREFIX];
+            stream.read_exact(&mut prefix).unwrap();
+            let mut buf = vec![0u8; u32::from_le_bytes(prefix) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let packet = PacketStruct::decode(&buf, None).unwrap();
+            assert_eq!((packet.seq, packet.length), (seq, length));
+        }
+    }
+
+    #[test]
+    fn refused_connection() {
+        // nothing listening on the port of a closed listener
+        let port = bind_tcp_listener("127.0.0.1", 0).unwrap().local_addr().unwrap().port();
+        let link = Link { tx_ipaddr: String::from("127.0.0.1"), rx_ipaddr: String::from("127.0.0.1"), mtu: None };
+        assert!(dispatch_tcp(vec![link], 0, None, port).is_none());
+    }
+
+    #[test]
+    fn fails_with_any_link() {
+        let listener = bind_tcp_listener("127.0.0.1", 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let link = Link { tx_ipaddr: String::from("127.0.0.1"), rx_ipaddr: String::from("127.0.0.1"), mtu: None };
+        let invalid = Link { tx_ipaddr: String::from("127.0.0.1"), rx_ipaddr: String::from("receiver"), mtu: None };
+        assert!(dispatch_tcp(vec![link.clone(), invalid.clone()], 0, None, port).is_none());
+        assert!(dispatch(vec![link, invalid], 0, None, None).is_none());
+    }
+
+    // datagrams received over one QUIC connection, as DATAGRAM frames or streams
+    fn quic_server(mapping: QuicMapping, count: usize) -> (u16, flume::Receiver<Vec<u8>>) {
+        let (port_tx, port_rx) = flume::bounded(1);
+        let (datagram_tx, datagram_rx) = flume::unbounded();
+        thread::spawn(move || quic::runtime().unwrap().block_on(async move {
+            let addr = std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+            let server = quinn::Endpoint::server(quic::server_config().unwrap(), addr).unwrap();
+            port_tx.send(server.local_addr().unwrap().port()).unwrap();
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let mut received = 0;
+            while received < count {
+                match mapping {
+                    QuicMapping::Datagram => {
+                        datagram_tx.send(connection.read_datagram().await.unwrap().to_vec()).unwrap();
+                        received += 1;
+                    }
+                    QuicMapping::Stream => {
+                        // the stream of the last frame stays open until the next one starts
+                        let mut stream = connection.accept_uni().await.unwrap();
+                        let mut prefix = [0u8; TCP_LENGTH_PREFIX];
+                        while received < count && stream.read_exact(&mut prefix).await.is_ok() {
+                            let mut datagram = vec![0u8; u32::from_le_bytes(prefix) as usize];
+                            stream.read_exact(&mut datagram).await.unwrap();
+                            datagram_tx.send(datagram).unwrap();
+                            received += 1;
+                        }
+                    }
+                }
+            }
+        }));
+        (port_rx.recv().unwrap(), datagram_rx)
+    }
+
+    #[test]
+    fn maps_quic_fragments() {
+        for mapping in [QuicMapping::Datagram, QuicMapping::Stream] {
+            let (port, datagrams) = quic_server(mapping, 3);
+            let link = Link { tx_ipaddr: String::from("127.0.0.1"), rx_ipaddr: String::from("127.0.0.1"), mtu: None };
+            let senders = dispatch_quic(vec![link], 0, None, port, 1500, mapping).unwrap();
+            assert_eq!(senders.len(), 1);
+            // two frames, the first of two fragments
+            for (seq, offset) in [(7, 0), (7, 1), (8, 0)] {
+                let mut packet = PacketStruct::new(port, 100);
+                (packet.seq, packet.offset) = (seq, offset);
+                packet.set_length(100);
+                senders[0].send(packet).unwrap();
+            }
+            let mut received: Vec<_> = (0..3).map(|_| {
+                let datagram = datagrams.recv_timeout(Duration::from_secs(5)).unwrap();
+                let packet = PacketStruct::decode(&datagram, None).unwrap();
+                (packet.seq, packet.offset)
+            }).collect();
+            received.sort();
+            assert_eq!(received, [(7, 0), (7, 1), (8, 0)], "{:?}", mapping);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Serialize, Deserialize};
+use rand::prelude::*;
+use rand_distr::{Exp, Normal};
+
+const fn _default_fps() -> f64 { 30.0 }
+const fn _default_gop() -> usize { 30 }
+
+/// Source of frames, as the rows of a trace.
+pub trait FrameGenerator: Send {
+    /// Time until the frame after (ns) and size (bytes) of the next frame.
+    fn next_frame(&mut self) -> (u64, usize);
+
+    /// Payload of the last frame, if not a dummy one.
+    fn payload(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+fn to_nanos(seconds: f64) -> u64 {
+    (seconds.max(0.0) * 1e9).round() as u64
+}
+
+// bytes per frame at `bitrate` Mbps
+fn frame_size(bitrate: f64, fps: f64) -> usize {
+    (bitrate * 1e6 / 8.0 / fps).round() as usize
+}
+
+/// Constant bitrate, with frames of equal size at a fixed rate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CbrParams {
+    pub bitrate: f64,                    // Mbps
+    #[serde(default = "_default_fps")]   //default:
+    pub fps: f64,                        //         30.0
+}
+
+impl CbrParams {
+    pub fn is_valid(&self) -> bool {
+        self.bitrate > 0.0 && self.fps > 0.0
+    }
+}
+
+impl FrameGenerator for CbrParams {
+    fn next_frame(&mut self) -> (u64, usize) {
+        (to_nanos(1.0 / self.fps), frame_size(self.bitrate, self.fps))
+    }
+}
+
+/// Poisson arrivals of frames of equal size.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoissonParams {
+    pub rate: f64,  // mean frames per second
+    pub size: usize, // bytes
+}
+
+impl PoissonParams {
+    pub fn is_valid(&self) -> bool {
+        self.rate > 0.0 && self.size > 0
+    }
+}
+
+impl FrameGenerator for PoissonParams {
+    fn next_frame(&mut self) -> (u64, usize) {
+        let interval = Exp::new(self.rate).unwrap().sample(&mut thread_rng());
+        (to_nanos(interval), self.size)
+    }
+}
+
+/// Constant bitrate bursts, with exponentially distributed on and off durations.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnOffParams {
+    pub bitrate: f64,                    // Mbps, while on
+    #[serde(default = "_default_fps")]   //default:
+    pub fps: f64,                        //         30.0
+    pub on: f64,                         // mean on duration, in seconds
+    pub off: f64,                        // mean off duration, in seconds
+    #[serde(skip)] remaining: f64,       // of the current on period
+}
+
+impl OnOffParams {
+    pub fn is_valid(&self) -> bool {
+        self.bitrate > 0.0 && self.fps > 0.0 && self.on > 0.0 && self.off >= 0.0
+    }
+}
+
+impl FrameGenerator for OnOffParams {
+    fn next_frame(&mut self) -> (u64, usize) {
+        let mut rng = thread_rng();
+        let exp = |mean: f64, rng: &mut ThreadRng| if mean > 0.0 { Exp::new(1.0 / mean).unwrap().sample(rng) } else { 0.0 };
+        if self.remaining <= 0.0 {
+            self.remaining = exp(self.on, &mut rng);
+        }
+
+        let mut interval = 1.0 / self.fps;
+        self.remaining -= interval;
+        // the off period starts after the last frame of the burst
+        if self.remaining <= 0.0 {
+            interval += exp(self.off, &mut rng);
+        }
+        (to_nanos(interval), frame_size(self.bitrate, self.fps))
+    }
+}
+
+/// Normal distribution of frame sizes, truncated at zero.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SizeDistribution {
+    pub mean: f64,               // bytes
+    #[serde(default)] pub std: f64, //default: 0.0
+}
+
+impl SizeDistribution {
+    fn sample(&self) -> usize {
+        let size = Normal::new(self.mean, self.std).map_or(self.mean, |normal| normal.sample(&mut thread_rng()));
+        size.max(0.0).round() as usize
+    }
+}
+
+/// Video with a GOP structure: an I frame followed by `gop - 1` P frames.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoParams {
+    #[serde(default = "_default_fps")]   //default:
+    pub fps: f64,                        //         30.0
+    #[serde(default = "_default_gop")]   //default:
+    pub gop: usize,                      //         30
+    pub i_size: SizeDistribution,
+    pub p_size: SizeDistribution,
+    #[serde(skip)] index: usize,         // of the next frame in the GOP
+}
+
+impl VideoParams {
+    pub fn is_valid(&self) -> bool {
+        self.fps > 0.0 && self.gop > 0 && self.i_size.std >= 0.0 && self.p_size.std >= 0.0
+    }
+}
+
+impl FrameGenerator for VideoParams {
+    fn next_frame(&mut self) -> (u64, usize) {
+        let size = if self.index == 0 { self.i_size.sample() } else { self.p_size.sample() };
+        self.index = (self.index + 1) % self.gop;
+        (to_nanos(1.0 / self.fps), size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(generator: &mut dyn FrameGenerator, num: usize) -> Vec<(u64, usize)> {
+        (0..num).map(|_| generator.next_frame()).collect()
+    }
+
+    fn size(mean: f64) -> SizeDistribution {
+        SizeDistribution { mean, std: 0.0 }
+    }
+
+    #[test]
+    fn cbr() {
+        let mut cbr = CbrParams { bitrate: 8.0, fps: 25.0 };
+        assert!(frames(&mut cbr, 10).iter().all(|frame| *frame == (40_000_000, 40_000)));
+        assert!(cbr.payload().is_none());
+    }
+
+    #[test]
+    fn video_gop() {
+        let mut video = VideoParams { fps: 50.0, gop: 3, i_size: size(1000.0), p_size: size(100.0), index: 0 };
+        let sizes: Vec<_> = frames(&mut video, 7).into_iter().map(|(interval, size)| {
+            assert_eq!(interval, 20_000_000);
+            size
+        }).collect();
+        assert_eq!(sizes, [1000, 100, 100, 1000, 100, 100, 1000]);
+        let mut video = VideoParams { gop: 1, index: 0, ..video };
+        assert!(frames(&mut video, 3).iter().all(|(_, size)| *size == 1000));
+    }
+
+    #[test]
+    fn truncated_sizes() {
+        let sizes = SizeDistribution { mean: 10.0, std: 100.0 };
+        assert!((0..1000).map(|_| sizes.sample()).any(|size| size == 0));
+    }
+
+    #[test]
+    fn on_off_bursts() {
+        let mut on_off = OnOffParams { bitrate: 8.0, fps: 10.0, on: 1.0, off: 2.0, remaining: 0.0 };
+        let frames = frames(&mut on_off, 100_000);
+        assert!(frames.iter().all(|(interval, size)| *interval >= 100_000_000 && *size == 100_000));
+        // bursts of 10 frames on average, then 2 seconds off
+        let bursts = frames.iter().filter(|(interval, _)| *interval > 100_000_000).count();
+        assert!((8_000..12_000).contains(&bursts), "{} bursts", bursts);
+        let total: u64 = frames.iter().map(|(interval, _)| interval).sum();
+        let on_time = frames.len() as f64 * 0.1;
+        let off_ratio = 1.0 - on_time / (total as f64 / 1e9);
+        assert!((0.62..0.71).contains(&off_ratio), "{} off", off_ratio);
+
+        let mut always_on = OnOffParams { off: 0.0, remaining: 0.0, ..on_off };
+        assert!(self::frames(&mut always_on, 100).iter().all(|(interval, _)| *interval == 100_000_000));
+    }
+
+    #[test]
+    fn poisson_mean() {
+        let mut poisson = PoissonParams { rate: 100.0, size: 1200 };
+        let frames = frames(&mut poisson, 100_000);
+        assert!(frames.iter().all(|(_, size)| *size == 1200));
+        let mean = frames.iter().map(|(interval, _)| *interval as f64).sum::<f64>() / frames.len() as f64;
+        assert!((9.7e6..10.3e6).contains(&mean), "{} ns", mean);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert!(!CbrParams { bitrate: 1.0, fps: 0.0 }.is_valid());
+        assert!(!CbrParams { bitrate: -1.0, fps: 30.0 }.is_valid());
+        // no frame at all
+        assert!(!CbrParams { bitrate: 0.0, fps: 30.0 }.is_valid());
+        assert!(!PoissonParams { rate: 0.0, size: 1000 }.is_valid());
+        assert!(!PoissonParams { rate: 10.0, size: 0 }.is_valid());
+        assert!(!OnOffParams { bitrate: 0.0, fps: 30.0, on: 1.0, off: 1.0, remaining: 0.0 }.is_valid());
+        assert!(!OnOffParams { bitrate: 1.0, fps: 30.0, on: 0.0, off: 1.0, remaining: 0.0 }.is_valid());
+        assert!(!OnOffParams { bitrate: 1.0, fps: 30.0, on: 1.0, off: -1.0, remaining: 0.0 }.is_valid());
+        assert!(!VideoParams { fps: 30.0, gop: 0, i_size: size(1000.0), p_size: size(100.0), index: 0 }.is_valid());
+        let negative_std = SizeDistribution { mean: 100.0, std: -1.0 };
+        assert!(!VideoParams { fps: 30.0, gop: 30, i_size: size(1000.0), p_size: negative_std, index: 0 }.is_valid());
+    }
+}
@@ -1,6 +1,8 @@
-use std::{net::UdpSocket, collections::HashMap, time::{Duration, SystemTime}};
+use std::{collections::HashMap, time::{Duration, SystemTime}};
 use serde::{Serialize, Deserialize};
+use core::socket::{bind_udp_socket, TcpInfo};
 use crate::source::SourceManager;
+use crate::limiter::LimiterParams;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Statistics {
@@ -11,12 +13,21 @@ pub struct Statistics {
     pub throughput: f64,
     pub tx_parts: Vec<f64>,
     pub throttle: f64,
+    pub limiter: LimiterParams,
+    pub tcp_info: Option<Vec<TcpInfo>>, // latest sample per link, for TCP streams
+    pub broker_queue: Option<usize>, // packets awaiting the broker
+    pub broker_drops: Option<usize>, // packets the broker could not hand to their socket
+    pub stale_frames: Option<usize>, // frames dropped from the throttler past their deadline
+    pub queue_packets: usize, // packets in the throttler
+    pub queue_bytes: usize,
+    pub queue_drops: usize, // packets dropped on overflow of the throttler
 }
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 enum RequestValue {
     Throttle(HashMap<String, f64>),
     TxPart(HashMap<String, Vec<f64>>),
+    Limiter(HashMap<String, LimiterParams>),
     Statistics(HashMap<String, f64>),
 }
 
@@ -49,40 +60,44 @@ impl IPCDaemon {
     fn handle_request(&self, req:Request) -> Option<Response> {
         match req.cmd {
             RequestValue::Throttle(data) => {
-                let _:Vec<_> = data.iter().map(|(name, value)| {
+                data.iter().for_each(|(name, value)| {
                     self.sources[name].throttle(*value);
-                }).collect();
+                });
                 
                 // TODO: reset RTT records for all
                 //
-                return None;
+                None
             },
 
             RequestValue::TxPart(data) => {
-                let _:Vec<_> = data.iter().map(|(name, value)| {
+                data.iter().for_each(|(name, value)| {
                     self.sources[name].set_tx_parts(value.clone());
-                }).collect();
+                });
+                //
+                None
+            },
+
+            RequestValue::Limiter(data) => {
+                data.iter().for_each(|(name, value)| {
+                    self.sources[name].set_limiter(*value);
+                });
                 //
-                return None;
+                None
             },
 
             RequestValue::Statistics(_)  => {
-                let body = Some( self.sources.iter().filter_map(|(name,src)| {
-                    match src.statistics() {
-                        Some(stat) => Some(( name.clone(), stat )),
-                        None => None
-                    }
-                }).collect() );
+                let body = self.sources.iter().filter_map(|(name,src)| {
+                    src.statistics().map(|stat| ( name.clone(), stat ))
+                }).collect();
                 //
-                return Some(Response{ cmd: ResponseValue::Statistics(body.unwrap())});
+                Some(Response{ cmd: ResponseValue::Statistics(body)})
             }
         }
     }
 
     pub fn start_loop(&self, duration:f64) {
         let deadline = SystemTime::now() + Duration::from_secs_f64(duration);
-        let addr = format!("{}:{}",self.tx_ipaddr, self.ipc_port);
-        let sock = UdpSocket::bind(&addr).unwrap();
+        let sock = bind_udp_socket(&self.tx_ipaddr, self.ipc_port).unwrap();
         sock.set_nonblocking(true).unwrap();
         let mut buf = [0; 2048];
 
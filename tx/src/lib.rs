@@ -1,11 +1,18 @@
 mod throttle;
+mod limiter;
 
 mod dispatcher;
 mod rtt;
+mod tcp_info;
 mod tx_part_ctl;
+mod rate_ctl;
+mod arq;
+mod trace;
+pub mod generator;
 mod statistic;
 
 pub mod conf;
 pub mod link;
 pub mod source;
+pub mod broker;
 pub mod ipc;
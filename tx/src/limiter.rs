@@ -0,0 +1,301 @@
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+
+use core::packet::UDP_MAX_LENGTH;
+use crate::throttle::CycledVecDequeue;
+
+type Time = SystemTime;
+type Size = usize;
+
+static MAX_ERR_RATIO: f64 = 0.01;
+const METER_INTERVAL: f64 = 0.1; // seconds between two rate estimates of a token bucket
+
+const fn _default_burst() -> usize { 10 * UDP_MAX_LENGTH }
+const fn _default_time_constant() -> f64 { 0.1 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type")]
+pub enum LimiterParams {
+    /// Average rate over the last `window_size` packets.
+    #[serde(rename = "window")]
+    Window {
+        #[serde(default)] window_size: usize,        //default: 0, the `window_size` of the manifest
+    },
+    /// Tokens filling at the throttle up to `burst`, one per byte sent.
+    #[serde(rename = "token_bucket")]
+    TokenBucket {
+        #[serde(default = "_default_burst")]         //default:
+        burst: usize,                                //         10 full packets (bytes)
+    },
+    /// Exponentially weighted moving average of the rate, decaying with `time_constant`.
+    #[serde(rename = "ewma")]
+    Ewma {
+        #[serde(default = "_default_time_constant")] //default:
+        time_constant: f64,                          //         0.1 (s)
+    },
+}
+
+impl Default for LimiterParams {
+    fn default() -> Self {
+        Self::Window { window_size: 0 }
+    }
+}
+
+impl LimiterParams {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Window { .. } => true,
+            Self::TokenBucket { burst } => *burst > 0,
+            Self::Ewma { time_constant } => *time_constant > 0.0,
+        }
+    }
+
+    /// A fresh limiter, over `window_size` packets unless given its own.
+    pub fn limiter(&self, window_size: usize) -> Box<dyn RateLimiter> {
+        match *self {
+            Self::Window { window_size: 0 } => Box::new(WindowLimiter::new(window_size)),
+            Self::Window { window_size: size } => Box::new(WindowLimiter::new(size)),
+            Self::TokenBucket { burst } => Box::new(TokenBucket::new(burst)),
+            Self::Ewma { time_constant } => Box::new(EwmaLimiter::new(time_constant)),
+        }
+    }
+}
+
+/// Admission of the packets of a stream under its throttle.
+pub trait RateLimiter: Send {
+    /// Whether `size_bytes` more at `now` would exceed `throttle` (Mbps, 0.0 for no limit);
+    /// if not, they are accounted as sent.
+    fn exceeds_with(&mut self, throttle: f64, size_bytes: usize, now: Time) -> bool;
+
+    /// Rate of the packets sent (Mbps) up to `now`, once known.
+    fn rate_mbps(&mut self, now: Time) -> Option<f64>;
+}
+
+fn elapsed(since: Time, now: Time) -> f64 {
+    now.duration_since(since).unwrap_or_default().as_secs_f64()
+}
+
+struct WindowLimiter {
+    window: CycledVecDequeue<(Time, Size)>,
+    sum_bytes: usize,
+    acc_error: usize,
+    max_error: usize,
+    last_rate: f64,
+}
+
+impl WindowLimiter {
+    fn new(window_size: usize) -> Self {
+        let window = CycledVecDequeue::new(window_size);
+        let max_error = (MAX_ERR_RATIO * window_size as f64) as usize * UDP_MAX_LENGTH;
+        Self { window, sum_bytes: 0, acc_error: 0, max_error, last_rate: 0.0 }
+    }
+
+    fn current_rate_mbps(&mut self, extra_bytes:Option<usize>, now: Time) -> Option<f64> {
+        if self.acc_error < self.max_error {
+            return Some(self.last_rate);
+        } else {
+            self.acc_error = 0;
+        }
+
+        let acc_size = self.sum_bytes + extra_bytes.unwrap_or(0);
+
+        let _last_time = self.window.front()?.0;
+        let acc_time = now.duration_since( _last_time ).unwrap_or_default();
+        let acc_time = acc_time.as_nanos();
+
+        let average_rate_mbps = 8.0 * (acc_size as f64/1e6) / (acc_time as f64*1e-9);
+        self.last_rate = average_rate_mbps;
+        Some(average_rate_mbps)
+    }
+
+    fn push(&mut self, size_bytes: usize, now: Time) {
+        self.sum_bytes += size_bytes;
+        if let Some(item) = self.window.push(( now, size_bytes )) {
+            self.sum_bytes -= item.1;
+            self.acc_error += item.1;
+        }
+    }
+}
+
+impl RateLimiter for WindowLimiter {
+    fn exceeds_with(&mut self, throttle: f64, size_bytes: usize, now: Time) -> bool {
+        if throttle==0.0 || self.window.len()==0 {
+            self.push(size_bytes, now);
+            return false;
+        }
+
+        self.acc_error += size_bytes;
+
+        let average_rate_mbps = self.current_rate_mbps( Some(size_bytes), now );
+        if average_rate_mbps.unwrap() < throttle {
+            self.push(size_bytes, now);
+            false
+        }
+        else {
+            true
+        }
+    }
+
+    fn rate_mbps(&mut self, now: Time) -> Option<f64> {
+        self.current_rate_mbps(None, now)
+    }
+}
+
+struct TokenBucket {
+    burst: usize,
+    tokens: f64,          // bytes
+    filled: Option<Time>,
+    meter: (Option<Time>, usize, f64), // start, bytes and rate (Mbps) of the measurement
+}
+
+impl TokenBucket {
+    fn new(burst: usize) -> Self {
+        Self { burst, tokens: burst as f64, filled: None, meter: (None, 0, 0.0) }
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    fn exceeds_with(&mut self, throttle: f64, size_bytes: usize, now: Time) -> bool {
+        if let Some(filled) = self.filled {
+            self.tokens = (self.tokens + throttle * 1e6 / 8.0 * elapsed(filled, now)).min(self.burst as f64);
+        }
+        self.filled = Some(now);
+
+        // a packet beyond the burst waits for a full bucket
+        if throttle != 0.0 && self.tokens < size_bytes.min(self.burst) as f64 {
+            return true;
+        }
+        self.tokens = (self.tokens - size_bytes as f64).max(0.0);
+        self.meter.0.get_or_insert(now);
+        self.meter.1 += size_bytes;
+        false
+    }
+
+    fn rate_mbps(&mut self, now: Time) -> Option<f64> {
+        let (start, bytes, rate) = &mut self.meter;
+        let duration = elapsed((*start)?, now);
+        if duration >= METER_INTERVAL {
+            *rate = 8.0 * (*bytes as f64 / 1e6) / duration;
+            (*start, *bytes) = (Some(now), 0);
+        }
+        Some(*rate)
+    }
+}
+
+struct EwmaLimiter {
+    time_constant: f64,
+    rate: f64,            // bytes per second
+    updated: Option<Time>,
+}
+
+impl EwmaLimiter {
+    fn new(time_constant: f64) -> Self {
+        Self { time_constant, rate: 0.0, updated: None }
+    }
+
+    fn decay(&mut self, now: Time) {
+        if let Some(updated) = self.updated {
+            self.rate *= (-elapsed(updated, now) / self.time_constant).exp();
+        }
+        self.updated = Some(now);
+    }
+}
+
+impl RateLimiter for EwmaLimiter {
+    fn exceeds_with(&mut self, throttle: f64, size_bytes: usize, now: Time) -> bool {
+        self.decay(now);
+        // each packet adds its impulse to the average
+        let rate = self.rate + size_bytes as f64 / self.time_constant;
+        if throttle != 0.0 && 8.0 * rate / 1e6 > throttle && self.rate > 0.0 {
+            return true;
+        }
+        self.rate = rate;
+        false
+    }
+
+    fn rate_mbps(&mut self, now: Time) -> Option<f64> {
+        self.updated?;
+        self.decay(now);
+        Some(8.0 * self.rate / 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    // `ms` milliseconds into the test
+    fn at(ms: f64) -> Time {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000) + Duration::from_secs_f64(ms / 1e3)
+    }
+
+    // admitted out of `count` back-to-back packets of `size` bytes
+    fn admitted(limiter: &mut dyn RateLimiter, throttle: f64, count: usize, size: usize, now: Time) -> usize {
+        (0..count).filter(|_| !limiter.exceeds_with(throttle, size, now)).count()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn parses_params() {
+        let params: LimiterParams = serde_json::from_str(r#"{"type": "token_bucket"}"#).unwrap();
+        assert_eq!(params, LimiterParams::TokenBucket { burst: 10 * UDP_MAX_LENGTH });
+        let params: LimiterParams = serde_json::from_str(r#"{"type": "ewma", "time_constant": 0.5}"#).unwrap();
+        assert_eq!(params, LimiterParams::Ewma { time_constant: 0.5 });
+        assert_eq!(serde_json::from_str::<LimiterParams>(r#"{"type": "window"}"#).unwrap(), LimiterParams::default());
+        assert!(serde_json::from_str::<LimiterParams>(r#"{"type": "leaky"}"#).is_err());
+        assert!(!LimiterParams::TokenBucket { burst: 0 }.is_valid());
+        assert!(!LimiterParams::Ewma { time_constant: 0.0 }.is_valid());
+    }
+
+    #[test]
+    fn unlimited() {
+        for params in [LimiterParams::default(), LimiterParams::TokenBucket { burst: 1000 }, LimiterParams::Ewma { time_constant: 0.1 }] {
+            let mut limiter = params.limiter(10);
+            assert_eq!(limiter.rate_mbps(at(0.0)), None);
+            assert_eq!(admitted(limiter.as_mut(), 0.0, 100, 1000, at(0.0)), 100);
+            assert!(limiter.rate_mbps(at(1000.0)).is_some());
+        }
+    }
+
+    #[test]
+    fn window() {
+        let mut limiter = LimiterParams::default().limiter(10);
+        // the first packet sets the start of the window, any other at once exceeds the rate
+        assert_eq!(admitted(limiter.as_mut(), 1.0, 10, 1000, at(0.0)), 1);
+        // 2000 bytes over 20 ms, 0.8 Mbps, not a third one
+        assert_eq!(admitted(limiter.as_mut(), 1.0, 10, 1000, at(20.0)), 1);
+        assert_close(limiter.rate_mbps(at(20.0)).unwrap(), 0.8);
+    }
+
+    #[test]
+    fn token_bucket() {
+        // 1e6 bytes/s
+        let mut limiter = LimiterParams::TokenBucket { burst: 10_000 }.limiter(10);
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 20, 1000, at(0.0)), 10);
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 20, 1000, at(5.0)), 5);
+        // up to the burst
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 20, 1000, at(105.0)), 10);
+        assert_close(limiter.rate_mbps(at(105.0)).unwrap(), 8.0 * 25_000.0 / 1e6 / 0.105);
+
+        // a packet beyond the burst waits for a full bucket
+        let mut limiter = LimiterParams::TokenBucket { burst: 1000 }.limiter(10);
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 2, 1500, at(0.0)), 1);
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 2, 1500, at(0.5)), 0);
+        assert_eq!(admitted(limiter.as_mut(), 8.0, 2, 1500, at(2.0)), 1);
+    }
+
+    #[test]
+    fn ewma() {
+        let mut limiter = LimiterParams::Ewma { time_constant: 0.1 }.limiter(10);
+        // 1000 bytes add 0.08 Mbps, the first packet always passes
+        assert_eq!(admitted(limiter.as_mut(), 0.05, 2, 1000, at(0.0)), 1);
+        assert_close(limiter.rate_mbps(at(0.0)).unwrap(), 0.08);
+        assert_eq!(admitted(limiter.as_mut(), 0.2, 2, 1000, at(0.0)), 1);
+        // decaying by e after the time constant
+        assert_close(limiter.rate_mbps(at(100.0)).unwrap(), 0.16 / std::f64::consts::E);
+    }
+}
@@ -1,10 +1,19 @@
 
+use std::net::{IpAddr, ToSocketAddrs};
 use serde::de::Deserializer;
 use serde::{Serialize, Deserialize};
 #[derive(Serialize, Debug,Clone)]
 pub struct Link {
     pub tx_ipaddr: String,
     pub rx_ipaddr: String,
+    pub mtu: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkRepr {
+    Pair(String, String),             // [tx_ipaddr, rx_ipaddr]
+    WithMtu(String, String, usize),   // [tx_ipaddr, rx_ipaddr, mtu]
 }
 
 impl<'de> Deserialize<'de> for Link {
@@ -12,10 +21,51 @@ impl<'de> Deserialize<'de> for Link {
     where
         D: Deserializer<'de>,
     {
-        let arr: [String; 2] = Deserialize::deserialize(deserializer)?;
-        Ok(Link {
-            tx_ipaddr: arr[0].clone(),
-            rx_ipaddr: arr[1].clone(),
-        })
+        let (tx_ipaddr, rx_ipaddr, mtu) = match LinkRepr::deserialize(deserializer)? {
+            LinkRepr::Pair(tx_ipaddr, rx_ipaddr) => (tx_ipaddr, rx_ipaddr, None),
+            LinkRepr::WithMtu(tx_ipaddr, rx_ipaddr, mtu) => (tx_ipaddr, rx_ipaddr, Some(mtu)),
+        };
+        Ok(Link { tx_ipaddr, rx_ipaddr, mtu })
+    }
+}
+
+impl Link {
+    /// Resolve the receiver address, accepting IPv4/IPv6 literals and host names.
+    pub fn rx_addr(&self) -> Option<std::net::SocketAddr> {
+        (self.rx_ipaddr.as_str(), 0).to_socket_addrs().ok()?.next()
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        match self.rx_ipaddr.parse::<IpAddr>() {
+            Ok(ip) => ip.is_ipv6(),
+            Err(_) => self.rx_addr().is_some_and(|addr| addr.is_ipv6()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(rx_ipaddr: &str) -> Link {
+        Link { tx_ipaddr: String::from("0.0.0.0"), rx_ipaddr: String::from(rx_ipaddr), mtu: None }
+    }
+
+    #[test]
+    fn parses_literals() {
+        assert_eq!(link("192.168.1.2").rx_addr(), Some("192.168.1.2:0".parse().unwrap()));
+        assert_eq!(link("fe80::1").rx_addr(), Some("[fe80::1]:0".parse().unwrap()));
+        assert!(!link("192.168.1.2").is_ipv6());
+        assert!(link("fe80::1").is_ipv6());
+    }
+
+    #[test]
+    fn deserializes_pair() {
+        let link: Link = serde_json::from_str(r#"["0.0.0.0", "::1"]"#).unwrap();
+        assert_eq!((link.tx_ipaddr.as_str(), link.rx_ipaddr.as_str(), link.mtu), ("0.0.0.0", "::1", None));
+        assert!(link.is_ipv6());
+        let link: Link = serde_json::from_str(r#"["0.0.0.0", "10.0.0.2", 9000]"#).unwrap();
+        assert_eq!(link.mtu, Some(9000));
+        assert!(serde_json::from_str::<Link>(r#"["0.0.0.0"]"#).is_err());
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,17 @@
 mod conf;
 mod throttle;
+mod limiter;
 mod source;
 mod dispatcher;
+mod broker;
 mod rtt;
+mod tcp_info;
 mod ipc;
 mod tx_part_ctl;
+mod rate_ctl;
+mod arq;
+mod trace;
+mod generator;
 mod link;
 mod statistic;
 
@@ -17,8 +24,8 @@ use std::time::SystemTime;
 // use std::rc::Rc;
 
 use clap::Parser;
-use serde_json;
 
+use crate::broker::GlobalBroker;
 use crate::conf::Manifest;
 use crate::ipc::IPCDaemon;
 use crate::source::SourceManager;
@@ -36,6 +43,9 @@ struct ProgArgs {
     /// IPC Port for real-time access
     #[clap(long, default_value_t = 11112)]
     ipc_port: u16,
+    /// Write the transmitted packets to `logs/tx-<stream>.pcapng`, one interface per link.
+    #[clap(long)]
+    pcap: bool,
 }
 
 fn main() {
@@ -54,11 +64,17 @@ fn main() {
     println!("Sliding Window Size: {}.", window_size);
 
     // spawn the source thread
-    let mut sources:HashMap<_,_> = streams.into_iter().map(|stream| {
-        let src = SourceManager::new(stream, window_size);
+    let mut sources:HashMap<_,_> = streams.into_iter().filter_map(|stream| {
+        let src = SourceManager::new(stream, window_size, args.pcap)?;
         let name = src.name.clone();
-        (name, src)
+        Some((name, src))
     }).collect();
+    // schedule all streams through the broker
+    if let Some(params) = manifest.broker {
+        let mut broker = GlobalBroker::new(params);
+        sources.values_mut().for_each(|src| src.connect(&mut broker));
+        broker.start();
+    }
     let _handles:Vec<_> = sources.iter_mut().enumerate().map(|(i,(_name,src))| {
         src.start(i+1, String::from("0.0.0.0"))
     }).collect();
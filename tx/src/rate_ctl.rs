@@ -0,0 +1,446 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use log::trace;
+use serde::{Serialize, Deserialize};
+
+use crate::rtt::{Feedback, FeedbackReceiver};
+use crate::throttle::RateThrottler;
+
+const fn _default_interval() -> f64 { 0.1 }
+const fn _default_min_rate() -> f64 { 0.5 }
+
+const REORDER_FRAMES: u32 = 3;       // frames completed after a pending one, for it to be lost
+const MAX_PENDING_TIME: f64 = 10.0;  // seconds before forgetting a frame never acknowledged
+const DELIVERY_WINDOW: f64 = 0.5;    // seconds of deliveries in the delivery rate, over the frame bursts
+// GCC-style
+const TRENDLINE_SAMPLES: usize = 20;   // intervals
+const OVERUSE_GRADIENT: f64 = 0.01;  // RTT increase (s) per second
+const INCREASE_FACTOR: f64 = 1.08;    // per update
+const DECREASE_FACTOR: f64 = 0.85;
+const MAX_DELIVERY_RATIO: f64 = 1.5; // no increase beyond, when limited by the source
+// BBR-style
+const MIN_RTT_WINDOW: f64 = 10.0;    // seconds
+const MAX_BW_INTERVALS: usize = 10;
+const STARTUP_GAIN: f64 = 2.0;
+const STARTUP_GROWTH: f64 = 1.25;
+const STARTUP_ROUNDS: usize = 3;
+const PROBE_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const DRAIN_GAIN: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionControl {
+    /// Delay gradient with multiplicative increase, and a loss-based cap (as GCC).
+    #[default]
+    #[serde(rename = "gcc")]
+    Gcc,
+    /// Pacing gain cycles over the bottleneck bandwidth, probed while below the target (as BBR).
+    #[serde(rename = "bbr")]
+    Bbr,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateControlParams {
+    #[serde(default)] pub algorithm: CongestionControl, //default: "gcc"
+    #[serde(default = "_default_interval")]             //default:
+    pub interval: f64,                                  //         0.1 (s)
+    #[serde(default = "_default_min_rate")]             //default:
+    pub min_rate: f64,                                  //         0.5 (Mbps)
+    #[serde(default)] pub max_rate: f64,                //default: 0.0 (Mbps), unbounded
+}
+
+impl RateControlParams {
+    pub fn is_valid(&self) -> bool {
+        self.interval > 0.0 && self.min_rate > 0.0 && (self.max_rate == 0.0 || self.max_rate >= self.min_rate)
+    }
+
+    /// A fresh instance of the control law.
+    pub fn algorithm(&self) -> Box<dyn RateAlgorithm> {
+        match self.algorithm {
+            CongestionControl::Gcc => Box::new(GccControl::default()),
+            CongestionControl::Bbr => Box::new(BbrControl::default()),
+        }
+    }
+}
+
+/// ACK feedback over the last control interval.
+pub struct RateFeedback {
+    pub time: f64,
+    pub target_rtt: f64,
+    pub delays: Vec<(f64, f64)>, // (time, delay) of each ACK, since its packet left the socket
+    pub lost: usize,             // frames
+    pub delivered: usize,        // frames
+    pub delivery_rate: f64,      // Mbps of the frames delivered, over the last half second
+    pub send_rate: f64,          // Mbps measured by the throttler
+}
+
+impl RateFeedback {
+    pub fn loss_rate(&self) -> f64 {
+        let frames = self.lost + self.delivered;
+        if frames == 0 { 0.0 } else { self.lost as f64 / frames as f64 }
+    }
+
+    /// Fraction of the ACKs beyond `target_rtt`.
+    pub fn outage_rate(&self) -> f64 {
+        if self.delays.is_empty() {
+            return 0.0;
+        }
+        self.delays.iter().filter(|(_, delay)| *delay > self.target_rtt).count() as f64 / self.delays.len() as f64
+    }
+
+    pub fn mean_delay(&self) -> Option<f64> {
+        (!self.delays.is_empty()).then(|| self.delays.iter().map(|(_, delay)| delay).sum::<f64>() / self.delays.len() as f64)
+    }
+}
+
+/// Control law of the throttle of a stream, applied at every interval.
+pub trait RateAlgorithm: Send {
+    /// The next throttle (Mbps), from the current one.
+    fn update(&mut self, rate: f64, feedback: &RateFeedback) -> f64;
+}
+
+#[derive(Default)]
+struct GccControl {
+    samples: VecDeque<(f64, f64)>, // (time, mean delay) of the last intervals
+}
+
+impl GccControl {
+    // least squares slope of the delay over time
+    fn gradient(&self) -> Option<f64> {
+        if self.samples.len() < TRENDLINE_SAMPLES / 2 {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let (mean_t, mean_d) = self.samples.iter().fold((0.0, 0.0), |(t, d), (ti, di)| (t + ti / n, d + di / n));
+        let (cov, var) = self.samples.iter().fold((0.0, 0.0), |(cov, var), (t, d)| {
+            (cov + (t - mean_t) * (d - mean_d), var + (t - mean_t).powi(2))
+        });
+        (var > 0.0).then(|| cov / var)
+    }
+}
+
+impl RateAlgorithm for GccControl {
+    fn update(&mut self, rate: f64, feedback: &RateFeedback) -> f64 {
+        // no ACK, no news
+        let Some(delay) = feedback.mean_delay() else {
+            return if feedback.loss_rate() > 0.1 { rate * (1.0 - 0.5 * feedback.loss_rate()) } else { rate };
+        };
+        self.samples.push_back((feedback.time, delay));
+        if self.samples.len() > TRENDLINE_SAMPLES {
+            self.samples.pop_front();
+        }
+        let gradient = self.gradient().unwrap_or(0.0);
+        let over_target = delay > feedback.target_rtt || feedback.outage_rate() > 0.5;
+
+        // delay-based: decrease below the delivered rate on overuse, hold on underuse
+        let mut next = if gradient > OVERUSE_GRADIENT || over_target {
+            // a fresh trendline, not to decrease again on the same overuse
+            self.samples.clear();
+            DECREASE_FACTOR * if feedback.delivery_rate > 0.0 { feedback.delivery_rate.min(rate) } else { rate }
+        } else if gradient < -OVERUSE_GRADIENT {
+            rate
+        } else {
+            rate * INCREASE_FACTOR
+        };
+        if feedback.delivery_rate > 0.0 {
+            next = next.min(rate.max(MAX_DELIVERY_RATIO * feedback.delivery_rate));
+        }
+
+        // loss-based cap
+        let loss = feedback.loss_rate();
+        if loss > 0.1 {
+            next = next.min(rate * (1.0 - 0.5 * loss));
+        } else if loss > 0.02 {
+            next = next.min(rate);
+        }
+        next
+    }
+}
+
+#[derive(Default)]
+struct BbrControl {
+    min_delays: VecDeque<(f64, f64)>, // (time, delay) of the minima, increasing
+    bandwidths: VecDeque<f64>,        // delivery rate of the last intervals
+    full_bandwidth: f64,
+    full_rounds: usize,
+    probing: bool,                    // out of startup
+    cycle: usize,
+}
+
+impl RateAlgorithm for BbrControl {
+    fn update(&mut self, rate: f64, feedback: &RateFeedback) -> f64 {
+        // windowed minimum delay and maximum bandwidth
+        for (time, delay) in feedback.delays.iter() {
+            while self.min_delays.back().is_some_and(|(_, d)| d >= delay) {
+                self.min_delays.pop_back();
+            }
+            self.min_delays.push_back((*time, *delay));
+        }
+        while self.min_delays.front().is_some_and(|(t, _)| feedback.time - t > MIN_RTT_WINDOW) {
+            self.min_delays.pop_front();
+        }
+        self.bandwidths.push_back(feedback.delivery_rate);
+        if self.bandwidths.len() > MAX_BW_INTERVALS {
+            self.bandwidths.pop_front();
+        }
+        let bandwidth = self.bandwidths.iter().cloned().fold(0.0, f64::max);
+        if bandwidth == 0.0 {
+            return rate;
+        }
+
+        // drain the queue built beyond the minimum delay, if over the target
+        let min_delay = self.min_delays.front().map_or(0.0, |(_, delay)| *delay);
+        let over_target = feedback.mean_delay().is_some_and(|delay| delay > feedback.target_rtt.max(min_delay))
+            || feedback.outage_rate() > 0.5 || feedback.loss_rate() > 0.1;
+        if over_target {
+            self.probing = true;
+            return DRAIN_GAIN * bandwidth;
+        }
+
+        // startup until the bandwidth stops growing
+        if !self.probing {
+            if bandwidth >= self.full_bandwidth * STARTUP_GROWTH {
+                (self.full_bandwidth, self.full_rounds) = (bandwidth, 0);
+            } else {
+                self.full_rounds += 1;
+                self.probing = self.full_rounds >= STARTUP_ROUNDS;
+            }
+        }
+        let gain = if self.probing {
+            self.cycle += 1;
+            PROBE_GAINS[self.cycle % PROBE_GAINS.len()]
+        } else {
+            STARTUP_GAIN
+        };
+        gain * bandwidth
+    }
+}
+
+/// Frames of a stream between their `Feedback::Sent` and their acknowledgement, or loss.
+#[derive(Default)]
+struct FrameTracker {
+    pending: HashMap<u32, (f64, usize)>, // (time, bytes) of each frame sent
+    deliveries: VecDeque<(f64, usize)>,  // (time, bytes) of each frame delivered
+    highest_complete: u32,
+}
+
+impl FrameTracker {
+    /// Feedback of the interval ending at `time_now`, from its `events`.
+    fn collect(&mut self, events: impl Iterator<Item = Feedback>, time_now: f64, target_rtt: f64, send_rate: f64) -> RateFeedback {
+        let (mut delays, mut delivered) = (Vec::new(), 0);
+        for event in events {
+            match event {
+                // frames cut short by the throttler are not the network's loss
+                Feedback::Sent { seq, time, bytes, queued } => {
+                    if queued {
+                        self.pending.insert(seq, (time, bytes));
+                    }
+                }
+                Feedback::Acked { seq, time, delay, complete } => {
+                    delays.push((time, delay));
+                    if complete {
+                        if let Some((_, bytes)) = self.pending.remove(&seq) {
+                            delivered += 1;
+                            self.deliveries.push_back((time, bytes));
+                        }
+                        self.highest_complete = self.highest_complete.max(seq);
+                    }
+                }
+                // nor those it dropped later on
+                Feedback::Dropped { seq } => {
+                    self.pending.remove(&seq);
+                }
+                Feedback::Nacked { .. } => {}
+            }
+        }
+        // frames overtaken by later ones are lost, whatever their wait in the throttler
+        let highest_complete = self.highest_complete;
+        let lost = self.pending.keys().filter(|&&seq| seq + REORDER_FRAMES < highest_complete).count();
+        self.pending.retain(|&seq, (time, _)| seq + REORDER_FRAMES >= highest_complete && time_now - *time < MAX_PENDING_TIME);
+
+        while self.deliveries.front().is_some_and(|(time, _)| time_now - time > DELIVERY_WINDOW) {
+            self.deliveries.pop_front();
+        }
+        let delivery_rate = 8.0 * self.deliveries.iter().map(|(_, bytes)| bytes).sum::<usize>() as f64 / 1e6 / DELIVERY_WINDOW;
+
+        RateFeedback{ time: time_now, target_rtt, delays, lost, delivered, delivery_rate, send_rate }
+    }
+}
+
+/// Periodic update of the throttle of a stream by the control law of `params`, from the
+/// feedback of its ACKs; each decision is written to `logs/rate-<name>.txt` as:
+///   time mean_delay loss_rate outage_rate delivery_rate send_rate throttle
+pub fn control_thread(name: String, params: RateControlParams, target_rtt: f64, duration: f64,
+    throttler: Arc<Mutex<RateThrottler>>, feedback: FeedbackReceiver)
+{
+    let mut algorithm = params.algorithm();
+    let mut logger = if cfg!(target_os = "android") {
+        None
+    } else {
+        Some( File::create( format!("logs/rate-{}.txt", name) ).unwrap() )
+    };
+    let max_rate = if params.max_rate > 0.0 { params.max_rate } else { f64::MAX };
+    // start from the given throttle, or the lowest rate if unlimited
+    let mut rate = {
+        let mut throttler = throttler.lock().unwrap();
+        if throttler.throttle <= 0.0 {
+            throttler.throttle = params.min_rate;
+        }
+        throttler.throttle.clamp(params.min_rate, max_rate)
+    };
+    let mut frames = FrameTracker::default();
+    let stop_time = SystemTime::now() + Duration::from_secs_f64(duration);
+
+    while SystemTime::now() < stop_time {
+        thread::sleep( Duration::from_secs_f64(params.interval) );
+        let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+
+        let send_rate = throttler.lock().unwrap().last_rate;
+        let feedback = frames.collect(feedback.try_iter(), time_now, target_rtt, send_rate);
+        rate = algorithm.update(rate, &feedback).clamp(params.min_rate, max_rate);
+        throttler.lock().unwrap().throttle = rate;
+
+        let mean_delay = feedback.mean_delay().unwrap_or(0.0);
+        trace!("Rate: Time {} -> {} delay {:.6} loss {:.3} outage {:.3} delivery {:.3} throttle {:.3}", time_now, name,
+            mean_delay, feedback.loss_rate(), feedback.outage_rate(), feedback.delivery_rate, rate);
+        if let Some(ref mut logger) = logger {
+            let message = format!("{:.6} {:.6} {:.3} {:.3} {:.3} {:.3} {:.3}\n", time_now, mean_delay,
+                feedback.loss_rate(), feedback.outage_rate(), feedback.delivery_rate, feedback.send_rate, rate);
+            logger.write_all( message.as_bytes() ).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feedback(time: f64, delay: Option<f64>, lost: usize, delivered: usize, delivery_rate: f64) -> RateFeedback {
+        RateFeedback{ time, target_rtt: 0.1, delays: delay.map(|delay| (time, delay)).into_iter().collect(),
+            lost, delivered, delivery_rate, send_rate: 0.0 }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn feedback_rates() {
+        let mut fb = feedback(0.0, None, 1, 3, 0.0);
+        assert_close(fb.loss_rate(), 0.25);
+        assert_eq!((fb.outage_rate(), fb.mean_delay()), (0.0, None));
+        fb.delays = vec![(0.0, 0.05), (0.0, 0.2), (0.0, 0.3), (0.0, 0.05)];
+        assert_close(fb.outage_rate(), 0.5);
+        assert_close(fb.mean_delay().unwrap(), 0.15);
+        assert_eq!(feedback(0.0, None, 0, 0, 0.0).loss_rate(), 0.0);
+    }
+
+    #[test]
+    fn gcc_without_acks() {
+        let mut gcc = GccControl::default();
+        assert_close(gcc.update(10.0, &feedback(0.0, None, 0, 0, 0.0)), 10.0);
+        assert_close(gcc.update(10.0, &feedback(0.1, None, 1, 1, 0.0)), 7.5);
+    }
+
+    #[test]
+    fn gcc_increases_then_backs_off_on_delay_gradient() {
+        let mut gcc = GccControl::default();
+        let mut rate = 10.0;
+        // the delay grows by 0.02 s per second, still below the target
+        for i in 0..TRENDLINE_SAMPLES / 2 - 1 {
+            let next = gcc.update(rate, &feedback(0.1 * i as f64, Some(0.001 + 0.002 * i as f64), 0, 1, 0.0));
+            assert_close(next, rate * INCREASE_FACTOR);
+            rate = next;
+        }
+        let i = TRENDLINE_SAMPLES / 2 - 1;
+        assert_close(gcc.update(rate, &feedback(0.1 * i as f64, Some(0.001 + 0.002 * i as f64), 0, 1, 0.0)),
+            DECREASE_FACTOR * rate);
+        // on a fresh trendline
+        assert!(gcc.samples.is_empty());
+    }
+
+    #[test]
+    fn gcc_caps() {
+        let mut gcc = GccControl::default();
+        // over the target, below the delivered rate
+        assert_close(gcc.update(10.0, &feedback(0.0, Some(0.2), 0, 1, 8.0)), DECREASE_FACTOR * 8.0);
+        // limited by the source
+        assert_close(gcc.update(10.0, &feedback(0.1, Some(0.01), 0, 1, 2.0)), 10.0);
+        assert_close(gcc.update(10.0, &feedback(0.2, Some(0.01), 0, 1, 8.0)), 10.0 * INCREASE_FACTOR);
+        // by the loss
+        assert_close(gcc.update(10.0, &feedback(0.3, Some(0.01), 1, 19, 0.0)), 10.0);
+        assert_close(gcc.update(10.0, &feedback(0.4, Some(0.01), 1, 4, 0.0)), 9.0);
+    }
+
+    #[test]
+    fn bbr_startup_then_probes() {
+        let mut bbr = BbrControl::default();
+        assert_close(bbr.update(10.0, &feedback(0.0, Some(0.005), 0, 0, 0.0)), 10.0);
+        assert_close(bbr.update(10.0, &feedback(0.1, Some(0.005), 0, 1, 1.0)), STARTUP_GAIN * 1.0);
+        assert_close(bbr.update(2.0, &feedback(0.2, Some(0.005), 0, 1, 2.0)), STARTUP_GAIN * 2.0);
+        // the bandwidth stops growing
+        for i in 0..STARTUP_ROUNDS - 1 {
+            assert_close(bbr.update(4.0, &feedback(0.3 + 0.1 * i as f64, Some(0.005), 0, 1, 2.0)), STARTUP_GAIN * 2.0);
+            assert!(!bbr.probing);
+        }
+        for i in 0..PROBE_GAINS.len() {
+            let next = bbr.update(2.0, &feedback(1.0 + 0.1 * i as f64, Some(0.005), 0, 1, 2.0));
+            assert_close(next, PROBE_GAINS[(i + 1) % PROBE_GAINS.len()] * 2.0);
+            assert!(bbr.probing);
+        }
+    }
+
+    #[test]
+    fn bbr_drains() {
+        let mut bbr = BbrControl::default();
+        assert_close(bbr.update(10.0, &feedback(0.0, Some(0.2), 0, 1, 4.0)), DRAIN_GAIN * 4.0);
+        assert!(bbr.probing);
+        // the bandwidth is the maximum over the window
+        assert_close(bbr.update(2.0, &feedback(0.1, Some(0.005), 1, 1, 1.0)), DRAIN_GAIN * 4.0);
+        assert_close(bbr.update(2.0, &feedback(0.2, Some(0.005), 0, 1, 1.0)), PROBE_GAINS[1] * 4.0);
+        // the latest of equal minima
+        assert_eq!(bbr.min_delays.front(), Some(&(0.2, 0.005)));
+    }
+
+    fn sent(seq: u32, time: f64) -> Feedback {
+        Feedback::Sent { seq, time, bytes: 12_500, queued: true }
+    }
+
+    fn acked(seq: u32, time: f64) -> Feedback {
+        Feedback::Acked { seq, time, delay: 0.005, complete: true }
+    }
+
+    #[test]
+    fn tracks_frames() {
+        let mut frames = FrameTracker::default();
+        let mut events = (1..=6).map(|seq| sent(seq, 1.0)).collect::<Vec<_>>();
+        // cut short by the throttler
+        events.push(Feedback::Sent { seq: 7, time: 1.0, bytes: 12_500, queued: false });
+        events.extend([acked(2, 1.1), acked(6, 1.1), acked(7, 1.1)]);
+        let feedback = frames.collect(events.into_iter(), 1.2, 0.1, 0.0);
+        // 1 and 3 overtaken by 7, 4 and 5 not yet
+        assert_eq!((feedback.lost, feedback.delivered, feedback.delays.len()), (2, 2, 3));
+        assert!((feedback.delivery_rate - 8.0 * 25_000.0 / 1e6 / DELIVERY_WINDOW).abs() < 1e-9);
+        assert_eq!(frames.pending.len(), 2);
+
+        // the deliveries out of the window
+        let feedback = frames.collect(std::iter::empty(), 1.7, 0.1, 0.0);
+        assert_eq!((feedback.lost, feedback.delivery_rate), (0, 0.0));
+    }
+
+    #[test]
+    fn throttler_drops_are_not_lost() {
+        let mut frames = FrameTracker::default();
+        let events = vec![sent(1, 1.0), sent(2, 1.0), sent(3, 1.0), Feedback::Dropped { seq: 1 },
+            sent(4, 1.0), sent(5, 1.0), acked(5, 1.1), Feedback::Dropped { seq: 2 }];
+        let feedback = frames.collect(events.into_iter(), 1.2, 0.1, 0.0);
+        assert_eq!((feedback.lost, feedback.delivered), (0, 1));
+        let mut pending: Vec<_> = frames.pending.keys().copied().collect();
+        pending.sort();
+        assert_eq!(pending, [3, 4]);
+        assert_eq!(frames.collect([acked(9, 1.3)].into_iter(), 1.4, 0.1, 0.0).lost, 2);
+    }
+}
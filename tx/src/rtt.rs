@@ -1,63 +1,96 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::collections::HashMap;
-use std::net::UdpSocket;
 use std::thread::{self, JoinHandle};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use core::packet;
+use core::packet::{PacketStruct, FRAME_COMPLETE};
+use core::socket::bind_udp_socket;
 use crate::statistic::rtt_records::RttRecords;
 
 type SeqRecords = HashMap<u32,f64>;
 type GuardedSeqRecords = Arc<Mutex<SeqRecords>>;
 
 type GuardedRttRecords = Arc<Mutex<RttRecords>>;
-pub type RttSender = flume::Sender<u32>;
-type RttReceiver = flume::Receiver<u32>;
+pub type RttSender = flume::Sender<(u32, usize, bool)>; // (seq, bytes, whether queued whole) of each frame
+type RttReceiver = flume::Receiver<(u32, usize, bool)>;
+pub type FeedbackReceiver = flume::Receiver<Feedback>;
+pub type FeedbackSenders = Vec<flume::Sender<Feedback>>;
 static PONG_PORT_INC:u16 = 1024;
 
+/// Frames handed to the throttler, their ACKs and NACKs, for sender-side control.
+#[derive(Debug, Clone)]
+pub enum Feedback {
+    /// `queued` unless the throttler had no room for some of its packets.
+    Sent { seq: u32, time: f64, bytes: usize, queued: bool },
+    /// `delay` since the acknowledged packet left the socket.
+    Acked { seq: u32, time: f64, delay: f64, complete: bool },
+    /// Offsets `missing` at the receiver, and all those from `tail` on if any.
+    Nacked { seq: u32, missing: Vec<u16>, tail: Option<u16> },
+    /// Packets of the frame dropped by the throttler once queued, stale or making room.
+    Dropped { seq: u32 },
+}
+
 pub struct RttRecorder {
     record_handle: Option<JoinHandle<()>>,
     recv_handle: Option<JoinHandle<()>>,
     name: String,
     port: u16,
+    feedback: FeedbackSenders,
     pub rtt_records: GuardedRttRecords,
 }
 
-fn record_thread(rx: RttReceiver, records: GuardedSeqRecords) {
-    while let Ok(seq) = rx.recv() {
+fn record_thread(rx: RttReceiver, records: GuardedSeqRecords, feedback: FeedbackSenders) {
+    while let Ok((seq, bytes, queued)) = rx.recv() {
         let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
         let mut _records = records.lock().unwrap();
         _records.insert(seq, time_now);
+        for tx in feedback.iter() {
+            let _ = tx.send(Feedback::Sent{ seq, time: time_now, bytes, queued });
+        }
     }
 }
 
-fn pong_recv_thread(name: String, port: u16, seq_records: GuardedSeqRecords, rtt_records: GuardedRttRecords, tx_ipaddr:String) {
+fn pong_recv_thread(name: String, port: u16, seq_records: GuardedSeqRecords, rtt_records: GuardedRttRecords, tx_ipaddr:String, feedback: FeedbackSenders) {
     let mut buf = [0; 2048];
-    let sock = UdpSocket::bind( format!("{}:{}",tx_ipaddr, port)).unwrap();
+    let sock = bind_udp_socket(&tx_ipaddr, port).unwrap();
     let mut logger = if cfg!(target_os = "android") {
         None
     } else {
         Some( File::create( format!("logs/rtt-{}.txt", name) ).unwrap() )
     };
 
-    while let Ok(_) = sock.recv_from(&mut buf) {
-        let seq = u32::from_le_bytes( buf[..4].try_into().unwrap() );
-        let indicator = u8::from_le_bytes( buf[18..19].try_into().unwrap() );
+    while let Ok((len, _)) = sock.recv_from(&mut buf) {
+        let packet = match PacketStruct::decode(&buf[..len], None) {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+        if let Some((missing, tail)) = packet.nacked() {
+            for tx in feedback.iter() {
+                let _ = tx.send(Feedback::Nacked{ seq: packet.seq, missing: missing.clone(), tail });
+            }
+            continue;
+        }
+        let (seq, path) = (packet.seq, packet.path as usize);
+        let frame_complete = packet.indicators & FRAME_COMPLETE != 0;
         let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
         if let Some(last_time) = {
             let mut _records = seq_records.lock().unwrap();
             _records.get(&seq).cloned()
         } {
             let rtt = time_now - last_time;
-            let is_complete = rtt_records.lock().unwrap().update(seq as usize,  packet::get_packet_type(indicator), rtt);
+            for tx in feedback.iter() {
+                let delay = time_now - packet.timestamp;
+                let _ = tx.send(Feedback::Acked{ seq, time: time_now, delay, complete: frame_complete });
+            }
+            let is_complete = rtt_records.lock().unwrap().update(seq as usize, path, frame_complete, rtt);
             if is_complete {
                 let mut _records = seq_records.lock().unwrap(); 
                 _records.remove(&seq);
             }
             if let Some(ref mut logger) = logger {
-                let message = format!("{} {:.6} {:.6} \n", seq, rtt, packet::channel_info(indicator));
+                let message = format!("{} {:.6} {} \n", seq, rtt, path);
                 logger.write_all( message.as_bytes() ).unwrap();
             }
         };
@@ -65,28 +98,41 @@ fn pong_recv_thread(name: String, port: u16, seq_records: GuardedSeqRecords, rtt
 }
 
 impl RttRecorder {
-    pub fn new(name:&String, port:u16, mul_link_num: usize, target_rtt: f64) -> Self {
-        let name = name.clone();
+    pub fn new(name:&str, port:u16, mul_link_num: usize, target_rtt: f64) -> Self {
+        let name = name.to_string();
         let port = port + PONG_PORT_INC; //pong recv port
         let record_handle = None;
         let recv_handle = None;
         let rtt_records = Arc::new(Mutex::new(RttRecords::new(200, mul_link_num, target_rtt)));
-        RttRecorder{ name, port, record_handle, recv_handle, rtt_records }
+        RttRecorder{ name, port, record_handle, recv_handle, feedback: Vec::new(), rtt_records }
+    }
+
+    /// Events of each frame from now on, to subscribe before `start`.
+    pub fn subscribe(&mut self) -> FeedbackReceiver {
+        let (tx, rx) = flume::unbounded();
+        self.feedback.push(tx);
+        rx
+    }
+
+    /// Senders to the subscribers so far, for the events of the throttler.
+    pub fn subscribers(&self) -> FeedbackSenders {
+        self.feedback.clone()
     }
 
     pub fn start(&mut self,tx_ipaddr:String) -> RttSender {
         // let (tx, rx) = mpsc::channel::<u32>();
-        let (tx, rx) = flume::unbounded::<u32>();
+        let (tx, rx) = flume::unbounded::<(u32, usize, bool)>();
         let (name, port) = (self.name.clone(), self.port);
         let seq_records1: GuardedSeqRecords = Arc::new(Mutex::new(HashMap::new()));
         let seq_records2 = seq_records1.clone();
         let rtt_records  = Arc::clone(&self.rtt_records);
+        let (feedback1, feedback2) = (self.feedback.clone(), std::mem::take(&mut self.feedback));
 
         self.record_handle = Some(
-            thread::spawn(move || { record_thread(rx, seq_records1); })
+            thread::spawn(move || { record_thread(rx, seq_records1, feedback1); })
         );
         self.recv_handle = Some(
-            thread::spawn(move || { pong_recv_thread(name, port, seq_records2, rtt_records, tx_ipaddr ); } )
+            thread::spawn(move || { pong_recv_thread(name, port, seq_records2, rtt_records, tx_ipaddr, feedback2); } )
         );
 
         tx
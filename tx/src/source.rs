@@ -1,94 +1,158 @@
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
-use ndarray::prelude::*;
-use ndarray_npy::read_npy;
 use log::trace;
 
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
 use core::packet::*;
+use core::fec::{repair_packets, FecParams};
+use core::crypto::PayloadCipher;
+use core::pcap::PcapWriter;
 use crate::conf::{StreamParam, ConnParams};
-use crate::dispatcher::dispatch;
+use crate::broker::GlobalBroker;
+use crate::dispatcher::{dispatch, dispatch_mptcp, dispatch_quic, dispatch_tcp};
 use crate::throttle::RateThrottler;
+use crate::limiter::LimiterParams;
 use crate::rtt::{RttRecorder,RttSender};
 use crate::ipc::Statistics;
-use crate::tx_part_ctl::TxPartCtler;
+use crate::tcp_info::TcpInfoSampler;
+use crate::tx_part_ctl::{self, TxPartCtler};
+use crate::rate_ctl;
+use crate::arq::{self, SentFrames};
+use crate::trace::TraceReplay;
+use crate::generator::FrameGenerator;
 
 type GuardedThrottler = Arc<Mutex<RateThrottler>>;
 type GuardedTxPartCtler = Arc<Mutex<TxPartCtler>>;
-type SokcetInfo = HashMap<String, flume::Sender<PacketStruct>>;
+type SokcetInfo = Vec<flume::Sender<PacketStruct>>;
 
 pub const STREAM_PROTO: &str = "stream://";
 
+// an empty frame is still sent as one empty fragment
+fn fragment_num(size_bytes: usize, payload_len: usize) -> usize {
+    size_bytes.div_ceil(payload_len).max(1)
+}
+
+fn fragment_length(offset: u16, size_bytes: usize, payload_len: usize) -> u16 {
+    size_bytes.saturating_sub(offset as usize * payload_len).min(payload_len) as u16
+}
+
+// repair packets are sent after all the fragments of the frame
+fn append_repairs(fec: &Option<FecParams>, packets: &mut Vec<PacketStruct>) {
+    if let Some(fec) = fec {
+        let mut fragments = packets.clone();
+        fragments.sort_by_key(|packet| packet.offset);
+        fragments.dedup_by_key(|packet| packet.offset);
+        packets.extend( repair_packets(fec, &fragments) );
+    }
+}
+
+// RTP timestamp of a frame `elapsed_ns` after the first one
+fn set_rtp_timestamp(template: &mut PacketStruct, base: u32, elapsed_ns: u64) {
+    if let Some(ref mut rtp) = template.rtp {
+        rtp.timestamp = base.wrapping_add((elapsed_ns as f64 * RTP_CLOCK_RATE / 1e9) as u64 as u32);
+    }
+}
+
+// the RTP marker bit on the last packet of the frame in sending order, the paths being shuffled
+fn set_rtp_marker(packets: &mut [PacketStruct]) {
+    let last = packets.len().saturating_sub(1);
+    for (i, packet) in packets.iter_mut().enumerate() {
+        if let Some(ref mut rtp) = packet.rtp {
+            rtp.marker = i == last;
+        }
+    }
+}
+
+// RTP sequence numbers count the packets handed to the sockets, over all paths
+fn set_rtp_sequence(packet: &mut PacketStruct, sequence: &mut u16) {
+    if let Some(ref mut rtp) = packet.rtp {
+        rtp.sequence = *sequence;
+        *sequence = sequence.wrapping_add(1);
+    }
+}
+
 pub fn stream_thread(throttler:GuardedThrottler, tx_part_ctler:GuardedTxPartCtler, rtt_tx: Option<RttSender>, params: ConnParams, socket_infos:SokcetInfo, dest: BufferReceiver)
 {
-    let mut template = PacketStruct::new(params.port);
+    let payload_len = params.payload_len();
+    let mut template = PacketStruct::new(params.port, payload_len);
+    template.checksum = params.checksum;
+    template.encrypted = params.key.is_some();
+    template.rtp = params.rtp.as_ref().map(RtpHeader::new);
+    let (rtp_base, mut rtp_sequence) = template.rtp.map_or((0, 0), |rtp| (rtp.timestamp, rtp.sequence));
     let stop_time  = SystemTime::now().checked_add( Duration::from_secs_f64(params.duration[1]) ).unwrap();
+    let start_time = SystemTime::now();
+    let mut last_ns = None;
+    let is_arq = throttler.lock().unwrap().is_keeping_sent();
 
     while SystemTime::now() <= stop_time {
-        // 0. wait for the next packet
-        let buffer = dest.recv().unwrap();
+        // 0. wait for the next packet with room for it, processing the queue meanwhile
+        let buffer = if dest.is_empty() || throttler.lock().unwrap().is_blocking() {
+            match throttler.lock().unwrap().try_consume(|mut packet| {
+                let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+                packet.timestamp = time_now;
+                set_rtp_sequence(&mut packet, &mut rtp_sequence);
+                if let Some(sender) = socket_infos.get(packet.path as usize) {
+                    let _ = sender.try_send(packet);
+                }
+                true
+            }) {
+                Some(_) => continue,
+                // retransmissions may come until the next packet
+                None if is_arq => match dest.recv_timeout( Duration::from_nanos(100_000) ) {
+                    Ok(buffer) => buffer,
+                    Err(flume::RecvTimeoutError::Timeout) => continue,
+                    Err(flume::RecvTimeoutError::Disconnected) => break,
+                },
+                None => dest.recv().unwrap()
+            }
+        } else {
+            dest.recv().unwrap()
+        };
         let size_bytes = buffer.len();
 
         // 1. generate packets
         let mut packets: Vec<PacketStruct> = Vec::new();
-        let (_num, _remains) = (size_bytes/MAX_PAYLOAD_LEN, size_bytes%MAX_PAYLOAD_LEN);
-        let num = _num + if _remains > 0 { 1 } else { 0 };
+        let (_num, _remains) = (size_bytes/payload_len, size_bytes%payload_len);
+        let num = fragment_num(size_bytes, payload_len);
         template.next_seq(_num, _remains);
+        let elapsed_ns = SystemTime::now().duration_since(start_time).unwrap_or_default().as_nanos() as u64;
+        set_rtp_timestamp(&mut template, rtp_base, elapsed_ns);
+        // paced over the interval since the previous frame, if any
+        let interval = last_ns.map_or(0.0, |last_ns| elapsed_ns.saturating_sub(last_ns) as f64 * 1e-9);
+        last_ns = Some(elapsed_ns);
 
         let mut packet_states = tx_part_ctler.lock().unwrap().get_packet_states(num);
         let mut rng = thread_rng();
         packet_states.shuffle(&mut rng);
 
         for packet_state in packet_states {
-            for (offset, packet_type) in packet_state {
-                let length = if offset == (num - 1) as u16 {
-                    _remains as u16
-                } else {
-                    MAX_PAYLOAD_LEN as u16
-                };
+            for (offset, path, indicators) in packet_state {
+                let length = fragment_length(offset, size_bytes, payload_len);
 
                 template.set_length(length);
                 template.set_offset(offset);
-                template.set_indicator(packet_type);
+                template.set_path(path, indicators);
                 template.set_payload(&buffer[
-                    (offset as usize * MAX_PAYLOAD_LEN) ..
-                    (offset as usize * MAX_PAYLOAD_LEN) + length as usize
+                    (offset as usize * payload_len) ..
+                    (offset as usize * payload_len) + length as usize
                 ]);
 
                 packets.push(template.clone());
             }
         }
+        append_repairs(&params.fec, &mut packets);
+        set_rtp_marker(&mut packets);
 
         // 2. append to application-layer queue
-        throttler.lock().unwrap().prepare( packets );
+        let num_packets = packets.len();
+        let queued = throttler.lock().unwrap().prepare( packets, interval ) == num_packets;
         // report RTT
         if let Some(ref r_tx) = rtt_tx {
-            r_tx.send(template.seq).unwrap();
-        }
-
-        // 3. process queue, aware of blocked status
-        while SystemTime::now() < stop_time {
-            match throttler.lock().unwrap().try_consume(|mut packet| {
-                let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-                packet.timestamp = time_now;
-                match tx_part_ctler.lock() {
-                    Ok(controller) => {
-                        let ip_addr = controller.packet_to_ipaddr(packet.indicators.clone());
-                        if let Some(sender) = socket_infos.get(&ip_addr) {
-                            let _ = sender.try_send(packet);
-                        }
-                    }
-                    Err(_) => (),
-                }
-                true
-            }) {
-                Some(_) => continue,
-                None=> break
-            }
+            r_tx.send((template.seq, size_bytes, queued)).unwrap();
         }
     }
 
@@ -97,16 +161,31 @@ pub fn stream_thread(throttler:GuardedThrottler, tx_part_ctler:GuardedTxPartCtle
 }
 
 pub fn source_thread(throttler:GuardedThrottler, tx_part_ctler:GuardedTxPartCtler, rtt_tx: Option<RttSender>,
-    params: ConnParams, socket_infos:SokcetInfo)
+    params: ConnParams, socket_infos:SokcetInfo, generator: Option<Box<dyn FrameGenerator>>)
 {
-    let trace: Array2<u64> = read_npy(&params.npy_file).expect("loading failed.");
-    let (start_offset, duration) = (params.start_offset, params.duration);
-    let mut template = PacketStruct::new(params.port);
+    let mut frames: Box<dyn FrameGenerator> = match generator {
+        Some(generator) => generator,
+        None => match TraceReplay::load(&params) {
+            Ok(trace) => Box::new(trace),
+            Err(e) => {
+                eprintln!("Trace loading failure: {}.", e);
+                return;
+            }
+        }
+    };
+    let duration = params.duration;
+    let payload_len = params.payload_len();
+    let mut template = PacketStruct::new(params.port, payload_len);
+    template.checksum = params.checksum;
+    template.encrypted = params.key.is_some();
+    template.rtp = params.rtp.as_ref().map(RtpHeader::new);
+    let (rtp_base, mut rtp_sequence) = template.rtp.map_or((0, 0), |rtp| (rtp.timestamp, rtp.sequence));
     let spin_sleeper = spin_sleep::SpinSleeper::new(100_000)
                         .with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
+    let is_arq = throttler.lock().unwrap().is_keeping_sent();
 
     let mut loops = 0;
-    let mut idx = start_offset;
+    let mut elapsed_ns = 0; // of the trace, for RTP timestamps
     let stop_time  = SystemTime::now().checked_add( Duration::from_secs_f64(duration[1]) ).unwrap();
 
     spin_sleeper.sleep( Duration::from_secs_f64(duration[0]) );
@@ -115,40 +194,45 @@ pub fn source_thread(throttler:GuardedThrottler, tx_part_ctler:GuardedTxPartCtle
 
         let deadline = if loops < params.loops {
             // 0. next iteration
-            idx = (idx + 1) % trace.shape()[0];
-            let size_bytes = trace[[idx, 1]] as usize;
-            let interval_ns = trace[[idx, 0]];
+            let (interval_ns, size_bytes) = frames.next_frame();
 
             // 1. generate packets
             let mut packets = Vec::new();
-            let (_num, _remains) = (size_bytes/MAX_PAYLOAD_LEN, size_bytes%MAX_PAYLOAD_LEN);
-            let num = _num + if _remains > 0 { 1 } else { 0 };
+            let (_num, _remains) = (size_bytes/payload_len, size_bytes%payload_len);
+            let num = fragment_num(size_bytes, payload_len);
             template.next_seq(_num, _remains);
-            let mut packet_states: Vec<Vec<(u16, PacketType)>> = tx_part_ctler.lock().unwrap().get_packet_states(num);
+            set_rtp_timestamp(&mut template, rtp_base, elapsed_ns);
+            elapsed_ns += interval_ns;
+            let mut packet_states = tx_part_ctler.lock().unwrap().get_packet_states(num);
 
             let mut rng = thread_rng();
             packet_states.shuffle(&mut rng);
 
             for packet_state in packet_states {
-                for (offset, packet_type) in packet_state {
-                    let length = if offset == (num - 1) as u16 {
-                        _remains as u16
-                    } else {
-                        MAX_PAYLOAD_LEN as u16
-                    };
-                    
+                for (offset, path, indicators) in packet_state {
+                    let length = fragment_length(offset, size_bytes, payload_len);
+
                     template.set_length(length);
                     template.set_offset(offset);
-                    template.set_indicator(packet_type);
-                    
+                    template.set_path(path, indicators);
+                    if let Some(payload) = frames.payload() {
+                        template.set_payload(&payload[
+                            (offset as usize * payload_len) ..
+                            (offset as usize * payload_len) + length as usize
+                        ]);
+                    }
+
                     packets.push(template.clone());
                 }
             }
+            append_repairs(&params.fec, &mut packets);
+            set_rtp_marker(&mut packets);
             // 2. append to application-layer queue
-            throttler.lock().unwrap().prepare( packets );
+            let num_packets = packets.len();
+            let queued = throttler.lock().unwrap().prepare( packets, interval_ns as f64 * 1e-9 ) == num_packets;
             // report RTT
             if let Some(ref r_tx) = rtt_tx {
-                r_tx.send(template.seq).unwrap();
+                r_tx.send((template.seq, size_bytes, queued)).unwrap();
             }
 
             SystemTime::now() + Duration::from_nanos(interval_ns)
@@ -158,24 +242,22 @@ pub fn source_thread(throttler:GuardedThrottler, tx_part_ctler:GuardedTxPartCtle
         };
         
         
-        trace!("Source: Time {} -> seq {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64(), template.seq as u32);
-        // 3. process queue, aware of blocked status
-        while SystemTime::now() < deadline {
+        trace!("Source: Time {} -> seq {}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64(), template.seq);
+        // 3. process queue, aware of blocked status, until the next frame is due and has room
+        while SystemTime::now() < deadline ||
+            (SystemTime::now() < stop_time && throttler.lock().unwrap().is_blocking()) {
             match throttler.lock().unwrap().try_consume(|mut packet| {
                 let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
                 packet.timestamp = time_now;
-                match tx_part_ctler.lock() {
-                    Ok(controller) => {
-                        let ip_addr = controller.packet_to_ipaddr(packet.indicators.clone());
-                        if let Some(sender) = socket_infos.get(&ip_addr) {
-                            let _ = sender.try_send(packet);
-                        }
-                    }
-                    Err(_) => (),
+                set_rtp_sequence(&mut packet, &mut rtp_sequence);
+                if let Some(sender) = socket_infos.get(packet.path as usize) {
+                    let _ = sender.try_send(packet);
                 }
                 true
             }) {
                 Some(_) => continue,
+                // retransmissions may come until the next frame
+                None if is_arq => std::thread::sleep( Duration::from_nanos(100_000) ),
                 None=> break
             }
         }
@@ -202,23 +284,60 @@ pub struct SourceManager{
     //
     throttler: GuardedThrottler,
     rtt: Option<RttRecorder>,
+    tcp_info: Option<TcpInfoSampler>,
     tx_part_ctler: Arc<Mutex<TxPartCtler>>,
     //
     socket_infos: Vec<SokcetInfo>,
+    broker: Option<(PacketSender, Arc<AtomicUsize>)>, // and the packets it dropped
 }
 
 impl SourceManager {
-    pub fn new(stream: StreamParam, window_size:usize) -> Self {
-        let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = stream;
+    /// The manager of `stream`, `None` if any of its links fails.
+    pub fn new(stream: StreamParam, window_size:usize, pcap:bool) -> Option<Self> {
+        let params = stream.params();
         let mut name = stream.name();
 
-        let socket_infos = vec![dispatch(params.links.clone(), params.tos)].into();
+        let cipher = params.key.as_deref().and_then(PayloadCipher::from_hex);
+        let is_tcp = matches!(stream, StreamParam::TCP(_));
+        let is_mptcp = matches!(stream, StreamParam::MPTCP(_));
+        let is_udp = !is_tcp && !is_mptcp && !matches!(stream, StreamParam::QUIC(_));
+        if pcap && !is_udp {
+            eprintln!("Pcap capture is only supported over UDP: {}.", name);
+        }
+        let pcap = (pcap && is_udp).then(|| {
+            let interfaces: Vec<_> = params.links.iter().enumerate()
+                .map(|(i, link)| format!("link{} {}->{}", i, link.tx_ipaddr, link.rx_ipaddr))
+                .collect();
+            PcapWriter::create(&format!("logs/tx-{}.pcapng", name), &interfaces)
+                .map_err(|e| eprintln!("Pcap creation failure for {}: {}.", name, e)).ok()
+        }).flatten().map(|pcap| Arc::new(Mutex::new(pcap)));
+        let dispatched = if is_tcp {
+            dispatch_tcp(params.links.clone(), params.tos, cipher, params.port)
+                .map(|(socket_infos, streams)| (socket_infos, Some(TcpInfoSampler::new(&name, streams))))
+        } else if let StreamParam::QUIC(ref q) = stream {
+            let mtu = params.mtu.unwrap_or(ETHERNET_MTU);
+            dispatch_quic(params.links.clone(), params.tos, cipher, params.port, mtu, q.mapping).map(|socket_infos| (socket_infos, None))
+        } else if is_mptcp {
+            dispatch_mptcp(params.links.clone(), params.tos, cipher, params.port).map(|socket_infos| (socket_infos, None))
+        } else {
+            dispatch(params.links.clone(), params.tos, cipher, pcap).map(|socket_infos| (socket_infos, None))
+        };
+        let Some((socket_infos, tcp_info)) = dispatched else {
+            eprintln!("Link setup failure, stream skipped: {}.", name);
+            return None;
+        };
+        let socket_infos = vec![socket_infos];
 
 
-        let throttler = Arc::new(Mutex::new(
-            RateThrottler::new(name.clone(), params.throttle, window_size, params.no_logging, false)
-        ));
-        let link_num = params.links.len();
+        let mut throttler = RateThrottler::new(name.clone(), params.throttle, window_size, params.no_logging, params.queue);
+        throttler.set_limiter(params.limiter);
+        if let Some(stale) = params.drop_stale {
+            throttler.drop_stale( stale.deadline(params.target_rtt) );
+        }
+        throttler.pace(params.pacing);
+        let throttler = Arc::new(Mutex::new(throttler));
+        let (links, tx_parts) = (params.links.clone(), params.tx_parts.clone());
+        let link_num = links.len();
         let target_rtt = params.target_rtt;
 
         let rtt =  match params.calc_rtt {
@@ -227,7 +346,7 @@ impl SourceManager {
         };
 
         let tx_part_ctler = Arc::new(Mutex::new(
-            TxPartCtler::new(params.tx_parts.clone(), params.links.clone())
+            TxPartCtler::new(tx_parts, links)
         ));
 
         let start_timestamp = SystemTime::now();
@@ -241,7 +360,16 @@ impl SourceManager {
             (vec![], vec![])
         };
 
-        Self{ name, stream, throttler, rtt, tx_part_ctler, socket_infos, start_timestamp, stop_timestamp, source, dest }
+        Some(Self{ name, stream, throttler, rtt, tcp_info, tx_part_ctler, socket_infos, broker: None, start_timestamp, stop_timestamp, source, dest })
+    }
+
+    /// Hand the packets of the stream over to `broker`, ahead of its sockets.
+    pub fn connect(&mut self, broker: &mut GlobalBroker) {
+        if let Some(socket_infos) = self.socket_infos.pop() {
+            let (socket_infos, drops) = broker.add(&self.name, self.stream.params(), socket_infos);
+            self.broker = socket_infos.first().cloned().map(|sender| (sender, drops));
+            self.socket_infos.push(socket_infos);
+        }
     }
 
     pub fn throttle(&self, throttle:f64) {
@@ -250,9 +378,19 @@ impl SourceManager {
         }
     }
 
+    pub fn set_limiter(&self, limiter:LimiterParams) {
+        if !limiter.is_valid() {
+            return;
+        }
+        if let Ok(ref mut throttler) = self.throttler.lock() {
+            throttler.set_limiter(limiter);
+        }
+    }
+
     pub fn set_tx_parts(&self, tx_parts:Vec<f64>) {
         if let Ok(ref mut tx_part_ctler) = self.tx_part_ctler.lock() {
             if tx_parts.len() != tx_part_ctler.tx_parts.len() {
+                eprintln!("tx_parts of {} ignored, expecting {} parts.", self.name, tx_part_ctler.tx_parts.len());
                 return;
             }
             tx_part_ctler.set_tx_parts(tx_parts);
@@ -265,8 +403,11 @@ impl SourceManager {
             return None;
         }
     
-        let throughput = self.throttler.lock().ok()?.last_rate;
-        let throttle = self.throttler.lock().ok()?.throttle;
+        let throttler = self.throttler.lock().ok()?;
+        let (throughput, throttle, limiter) = (throttler.last_rate, throttler.throttle, throttler.limiter_params());
+        let stale_frames = self.stream.params().drop_stale.is_some().then_some(throttler.stale_frames);
+        let ((queue_packets, queue_bytes), queue_drops) = (throttler.occupancy(), throttler.queue_drops);
+        drop(throttler);
     
         let (rtt, channel_rtts, outage_rate, ch_outage_rates) = if let Some(ref rtt) = self.rtt {
             let stats = rtt.rtt_records.lock().unwrap().statistic();
@@ -277,20 +418,49 @@ impl SourceManager {
     
         let tx_parts = self.tx_part_ctler.lock().ok()?.tx_parts.clone();
 
-        
+        let tcp_info = self.tcp_info.as_ref().map(|sampler| sampler.samples.lock().unwrap().clone());
+        let (broker_queue, broker_drops) = self.broker.as_ref()
+            .map(|(broker, drops)| (broker.len(), drops.load(Ordering::Relaxed))).unzip();
     
-        Some(Statistics { rtt, channel_rtts, outage_rate, ch_outage_rates, throughput, tx_parts, throttle })
+        Some(Statistics { rtt, channel_rtts, outage_rate, ch_outage_rates, throughput, tx_parts, throttle, limiter, tcp_info, broker_queue, broker_drops, stale_frames, queue_packets, queue_bytes, queue_drops })
     }
 
     pub fn start(&mut self, index:usize, tx_ipaddr:String) -> JoinHandle<()> {
         let throttler = Arc::clone(&self.throttler);
         let tx_part_ctler = Arc::clone(&self.tx_part_ctler);
-        let rtt_tx = match self.rtt {
-            Some(ref mut rtt) => Some( rtt.start(tx_ipaddr) ),
-            None => None
-        };
-        let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = self.stream;
-        let params = params.clone();
+        let params = self.stream.params();
+        if let (Some(arq), Some(rtt)) = (params.arq, self.rtt.as_mut()) {
+            let (name, duration) = (self.name.clone(), params.duration[1]);
+            let throttler = Arc::clone(&self.throttler);
+            throttler.lock().unwrap().keep_sent( SentFrames::new(&arq, params.target_rtt) );
+            let feedback = rtt.subscribe();
+            thread::spawn(move || {
+                arq::nack_thread(name, duration, throttler, feedback);
+            });
+        }
+        if let (Some(control), Some(rtt)) = (params.rate_control.clone(), self.rtt.as_mut()) {
+            let (name, target_rtt, duration) = (self.name.clone(), params.target_rtt, params.duration[1]);
+            let throttler = Arc::clone(&self.throttler);
+            let feedback = rtt.subscribe();
+            throttler.lock().unwrap().report_drops( rtt.subscribers() );
+            thread::spawn(move || {
+                rate_ctl::control_thread(name, control, target_rtt, duration, throttler, feedback);
+            });
+        }
+        let rtt_tx = self.rtt.as_mut().map(|rtt| rtt.start(tx_ipaddr));
+        if let (Some(control), Some(rtt)) = (params.tx_part_control.clone(), self.rtt.as_ref()) {
+            let (name, target_rtt, duration) = (self.name.clone(), params.target_rtt, params.duration[1]);
+            let tx_part_ctler = Arc::clone(&self.tx_part_ctler);
+            let rtt_records = Arc::clone(&rtt.rtt_records);
+            thread::spawn(move || {
+                tx_part_ctl::control_thread(name, control, target_rtt, duration, tx_part_ctler, rtt_records);
+            });
+        }
+        if let Some(ref mut sampler) = self.tcp_info {
+            sampler.start();
+        }
+        let params = self.stream.params().clone();
+        let generator = self.stream.generator();
 
         let _now = SystemTime::now();
         self.start_timestamp = _now + Duration::from_secs_f64( params.duration[0] );
@@ -303,7 +473,7 @@ impl SourceManager {
                 let dest = dest.unwrap();
                 stream_thread(throttler, tx_part_ctler, rtt_tx, params, socket_infos, dest)
             } else {
-                source_thread(throttler, tx_part_ctler, rtt_tx, params, socket_infos);
+                source_thread(throttler, tx_part_ctler, rtt_tx, params, socket_infos, generator);
             }
         });
 
@@ -311,3 +481,46 @@ impl SourceManager {
         source
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtp_template() -> PacketStruct {
+        let mut template = PacketStruct::new(5202, 0);
+        template.rtp = Some(RtpHeader { payload_type: 96, sequence: 0xFFFE, timestamp: 0, ssrc: 1, marker: false });
+        template
+    }
+
+    #[test]
+    fn rtp_timestamps() {
+        let mut template = rtp_template();
+        set_rtp_timestamp(&mut template, 1000, 1_000_000_000);
+        assert_eq!(template.rtp.unwrap().timestamp, 1000 + 90_000);
+        // wrapping around
+        set_rtp_timestamp(&mut template, u32::MAX, 2_000_000_000);
+        assert_eq!(template.rtp.unwrap().timestamp, 180_000 - 1);
+    }
+
+    #[test]
+    fn rtp_sequence_and_marker() {
+        let mut packets = vec![rtp_template(); 3];
+        set_rtp_marker(&mut packets);
+        let mut sequence = 0xFFFE;
+        let fields: Vec<_> = packets.iter_mut().map(|packet| {
+            set_rtp_sequence(packet, &mut sequence);
+            let rtp = packet.rtp.unwrap();
+            (rtp.sequence, rtp.marker)
+        }).collect();
+        // the marker on the last packet in sending order
+        assert_eq!(fields, [(0xFFFE, false), (0xFFFF, false), (0, true)]);
+        assert_eq!(sequence, 1);
+
+        // native framing untouched
+        let mut packets = vec![PacketStruct::new(5202, 0)];
+        set_rtp_marker(&mut packets);
+        set_rtp_sequence(&mut packets[0], &mut sequence);
+        assert!(packets[0].rtp.is_none());
+        assert_eq!(sequence, 1);
+    }
+}
@@ -1,11 +1,16 @@
-use core::packet::PacketType;
 use std::cmp::Ordering;
+
+// consumers of the samples, each counting every sample once on its own
+const IPC_READER: usize = 0;
+const CONTROL_READER: usize = 1;
+const READERS: usize = 2;
+
 #[derive(Debug, Clone)]
 struct RTTEntry {
     seq: usize,
     rtt: f64,
     channel_rtts: Vec<Option<f64>>,
-    visited_rtt: Vec<bool>,
+    visited_rtt: [Vec<bool>; READERS],
     completed: bool,
 }
 
@@ -15,32 +20,17 @@ impl RTTEntry {
             seq,
             rtt: 0.0,
             channel_rtts: vec![None; max_links],
-            visited_rtt: vec![false; max_links + 1],
+            visited_rtt: std::array::from_fn(|_| vec![false; max_links + 1]),
             completed: false,
         }
     }
 
-    fn update_value(&mut self, channel: PacketType, value: f64)  {
+    fn update_value(&mut self, path: usize, frame_complete: bool, value: f64)  {
         self.rtt = value;
-        match channel {
-            PacketType::SLFL => {
-                self.channel_rtts[0] = Some(value);
-                self.completed = true;
-            },
-            PacketType::SLSL => {
-                self.channel_rtts[1] = Some(value);
-                self.completed = true;
-            },
-            PacketType::DFL => {
-                self.channel_rtts[0] = Some(value);
-                self.completed = self.channel_rtts.iter().all(|rtt| rtt.is_some());
-            } 
-            PacketType::DSL => {
-                self.channel_rtts[1] = Some(value);
-                self.completed = self.channel_rtts.iter().all(|rtt| rtt.is_some());
-            },
-            _ => {panic!("Invalid packet type")}
+        if let Some(rtt) = self.channel_rtts.get_mut(path) {
+            *rtt = Some(value);
         }
+        self.completed |= frame_complete;
     }
 }
 
@@ -61,28 +51,23 @@ impl RttRecords {
         }
     }
 
-    pub fn update(&mut self, seq: usize, channel: PacketType, rtt: f64) -> bool{
+    pub fn update(&mut self, seq: usize, path: usize, frame_complete: bool, rtt: f64) -> bool{
         let index = seq % self.max_length;
         // If the entry is already present and seq value is the same, update the value
         // Otherwise, create a new entry
         match &mut self.queue[index] {
-            Some(entry) => {
-                if entry.seq == seq {
-                    entry.update_value(channel, rtt);
-                } else {
-                    self.queue[index] = Some(RTTEntry::new(seq, self.max_links));
-                    self.queue[index].as_mut().unwrap().update_value(channel, rtt);
-                }
+            Some(entry) if entry.seq == seq => {
+                entry.update_value(path, frame_complete, rtt);
             }
-            None => {
+            _ => {
                 self.queue[index] = Some(RTTEntry::new(seq, self.max_links));
-                self.queue[index].as_mut().unwrap().update_value(channel, rtt);
+                self.queue[index].as_mut().unwrap().update_value(path, frame_complete, rtt);
             }
         }
         self.queue[index].as_ref().unwrap().completed
     }
 
-    fn average_between_quantiles(values: &mut Vec<f64>) -> f64 {
+    fn average_between_quantiles(values: &mut [f64]) -> f64 {
         if values.is_empty() {
             0.0
         } else {
@@ -99,7 +84,17 @@ impl RttRecords {
         }
     }
 
+    /// Statistics of the samples since the last call, for IPC queries.
     pub fn statistic(&mut self) -> (f64, Vec<f64>, f64, Vec<f64>) {
+        self.statistic_of(IPC_READER)
+    }
+
+    /// As `statistic`, with the samples since the last call, for the `tx_parts` controller.
+    pub fn control_statistic(&mut self) -> (f64, Vec<f64>, f64, Vec<f64>) {
+        self.statistic_of(CONTROL_READER)
+    }
+
+    fn statistic_of(&mut self, reader: usize) -> (f64, Vec<f64>, f64, Vec<f64>) {
         // Vectors to store RTT and channel RTT values
         let mut rtt_values = Vec::new();
         let mut channel_rtts = vec![Vec::new(); self.max_links];
@@ -108,28 +103,27 @@ impl RttRecords {
         let mut ch_outages = vec![0; self.max_links];
         let mut count = vec![0; self.max_links + 1];
     
-        for entry in &mut self.queue {
-            if let Some(ref mut entry) = entry {
-                for (i, rtt_opt) in entry.channel_rtts.iter().enumerate() {
-                    if let Some(rtt) = rtt_opt {
-                        if !entry.visited_rtt[i + 1] {
-                            entry.visited_rtt[i + 1] = true;
-                            channel_rtts[i].push(*rtt);
-                            if rtt > &self.target_rtt {
-                                ch_outages[i] += 1;
-                            }
-                            count[i + 1] += 1;
+        for entry in self.queue.iter_mut().flatten() {
+            let visited_rtt = &mut entry.visited_rtt[reader];
+            for (i, rtt_opt) in entry.channel_rtts.iter().enumerate() {
+                if let Some(rtt) = rtt_opt {
+                    if !visited_rtt[i + 1] {
+                        visited_rtt[i + 1] = true;
+                        channel_rtts[i].push(*rtt);
+                        if rtt > &self.target_rtt {
+                            ch_outages[i] += 1;
                         }
+                        count[i + 1] += 1;
                     }
                 }
-                if entry.completed && !entry.visited_rtt[0] {
-                    rtt_values.push(entry.rtt);
-                    if entry.rtt > self.target_rtt {
-                        outages += 1.0;
-                    }
-                    count[0] += 1;
-                    entry.visited_rtt[0] = true;
+            }
+            if entry.completed && !visited_rtt[0] {
+                rtt_values.push(entry.rtt);
+                if entry.rtt > self.target_rtt {
+                    outages += 1.0;
                 }
+                count[0] += 1;
+                visited_rtt[0] = true;
             }
         }
     
@@ -156,3 +150,30 @@ impl RttRecords {
     }
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readers_see_every_sample() {
+        let mut records = RttRecords::new(16, 2, 0.01);
+        records.update(0, 0, false, 0.004);
+        assert!(records.update(0, 1, true, 0.02));
+        records.update(1, 0, true, 0.006);
+
+        let (rtt, channel_rtts, outage_rate, ch_outage_rates) = records.control_statistic();
+        assert!((rtt - 0.013).abs() < 1e-9);
+        assert!((channel_rtts[0] - 0.005).abs() < 1e-9 && (channel_rtts[1] - 0.02).abs() < 1e-9);
+        assert_eq!((outage_rate, ch_outage_rates), (0.5, vec![0.0, 1.0]));
+        assert_eq!(records.control_statistic(), (0.0, vec![0.0, 0.0], 0.0, vec![0.0, 0.0]));
+
+        // the IPC queries still count them
+        let (rtt, channel_rtts, _, _) = records.statistic();
+        assert!((rtt - 0.013).abs() < 1e-9);
+        assert!((channel_rtts[0] - 0.005).abs() < 1e-9);
+        records.update(2, 1, true, 0.008);
+        assert_eq!(records.statistic(), (0.008, vec![0.0, 0.008], 0.0, vec![0.0, 0.0]));
+        assert_eq!(records.control_statistic(), (0.008, vec![0.0, 0.008], 0.0, vec![0.0, 0.0]));
+    }
+}
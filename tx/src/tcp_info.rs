@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use core::socket::{tcp_info, TcpInfo};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+type GuardedSamples = Arc<Mutex<Vec<TcpInfo>>>;
+
+/// Periodic `TCP_INFO` samples of the connection of each link, the latest kept for the IPC
+/// and all of them written to `logs/tcp-info-<name>.txt`, one line per link and sample:
+///   time path cwnd srtt rttvar retransmits delivery_rate pacing_rate
+pub struct TcpInfoSampler {
+    name: String,
+    streams: Vec<TcpStream>,
+    handle: Option<JoinHandle<()>>,
+    pub samples: GuardedSamples,
+}
+
+fn sample_thread(name: String, streams: Vec<TcpStream>, samples: GuardedSamples) {
+    let mut logger = if cfg!(target_os = "android") {
+        None
+    } else {
+        Some( File::create( format!("logs/tcp-info-{}.txt", name) ).unwrap() )
+    };
+
+    loop {
+        let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        let mut connected = false;
+        for (path, stream) in streams.iter().enumerate() {
+            let Ok(info) = tcp_info(stream) else { continue };
+            connected = true;
+            samples.lock().unwrap()[path] = info;
+            if let Some(ref mut logger) = logger {
+                let message = format!("{:.6} {} {} {:.6} {:.6} {} {:.3} {:.3}\n", time_now, path,
+                    info.cwnd, info.srtt, info.rttvar, info.retransmits, info.delivery_rate, info.pacing_rate);
+                logger.write_all( message.as_bytes() ).unwrap();
+            }
+        }
+        if !connected {
+            break;
+        }
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+impl TcpInfoSampler {
+    pub fn new(name: &str, streams: Vec<TcpStream>) -> Self {
+        let samples = Arc::new(Mutex::new(vec![TcpInfo::default(); streams.len()]));
+        TcpInfoSampler{ name: name.to_string(), streams, handle: None, samples }
+    }
+
+    pub fn start(&mut self) {
+        let name = self.name.clone();
+        let streams = std::mem::take(&mut self.streams);
+        let samples = Arc::clone(&self.samples);
+        self.handle = Some(
+            thread::spawn(move || { sample_thread(name, streams, samples); })
+        );
+    }
+}
@@ -1,23 +1,125 @@
 use log::trace;
 use std::time::SystemTime;
 use std::collections::VecDeque;
-use core::packet::{PacketStruct,UDP_MAX_LENGTH};
+use serde::{Serialize, Deserialize};
+use core::packet::PacketStruct;
+use crate::arq::SentFrames;
+use crate::limiter::{LimiterParams, RateLimiter};
+use crate::rtt::{Feedback, FeedbackSenders};
 // use std::sync::{Arc, Mutex};
 
-type TIME = SystemTime;
-type SIZE = usize;
-
-static MAX_ERR_RATIO: f64 = 0.01;
 pub static CYCLED_RATIO: usize = 50;
+const PACED_FRAMES: usize = 256; // frames whose packet gap is remembered
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// Frames queued beyond `target_rtt`.
+    #[default]
+    #[serde(rename = "target_rtt")]
+    TargetRtt,
+    /// Frames queued beyond the given `deadline`.
+    #[serde(rename = "deadline")]
+    Deadline,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct StaleParams {
+    #[serde(default)] pub policy: StalePolicy, //default: "target_rtt"
+    #[serde(default)] pub deadline: f64,       //default: 0.0 (s), for the "deadline" policy
+}
 
-struct CycledVecDequeue<T> {
+impl StaleParams {
+    pub fn is_valid(&self) -> bool {
+        match self.policy {
+            StalePolicy::TargetRtt => true,
+            StalePolicy::Deadline => self.deadline > 0.0,
+        }
+    }
+
+    /// Longest time a frame may wait in the throttler.
+    pub fn deadline(&self, target_rtt: f64) -> f64 {
+        match self.policy {
+            StalePolicy::TargetRtt => target_rtt,
+            StalePolicy::Deadline => self.deadline,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PacingMode {
+    /// The packets of a frame back to back, as fast as the throttle allows.
+    #[default]
+    #[serde(rename = "burst")]
+    Burst,
+    /// The packets of a frame evenly spread over the frame interval.
+    #[serde(rename = "interval")]
+    Interval,
+    /// The packets of a frame evenly spread over the given `fraction` of the frame interval.
+    #[serde(rename = "fraction")]
+    Fraction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct PacingParams {
+    #[serde(default)] pub mode: PacingMode, //default: "burst"
+    #[serde(default)] pub fraction: f64,    //default: 0.0, in (0.0, 1.0] for the "fraction" mode
+}
+
+impl PacingParams {
+    pub fn is_valid(&self) -> bool {
+        match self.mode {
+            PacingMode::Burst | PacingMode::Interval => true,
+            PacingMode::Fraction => self.fraction > 0.0 && self.fraction <= 1.0,
+        }
+    }
+
+    /// Time over which the packets of a frame are sent, given the frame `interval`.
+    pub fn span(&self, interval: f64) -> f64 {
+        match self.mode {
+            PacingMode::Burst => 0.0,
+            PacingMode::Interval => interval,
+            PacingMode::Fraction => self.fraction * interval,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// The packets arriving at a full queue are dropped.
+    #[default]
+    #[serde(rename = "drop_tail")]
+    DropTail,
+    /// The oldest packets are dropped to make room.
+    #[serde(rename = "drop_head")]
+    DropHead,
+    /// The arriving frames not fitting whole are dropped whole.
+    #[serde(rename = "drop_frame")]
+    DropFrame,
+    /// The source holds its next frame back until there is room.
+    #[serde(rename = "block")]
+    Block,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct QueueParams {
+    #[serde(default)] pub max_packets: Option<usize>, //default: null, `CYCLED_RATIO * window_size`
+    #[serde(default)] pub max_bytes: Option<usize>,   //default: null, unlimited
+    #[serde(default)] pub max_time: Option<f64>,      //default: null (s), unlimited, for the oldest packet
+    #[serde(default)] pub overflow: QueueOverflow,    //default: "drop_tail"
+}
+
+impl QueueParams {
+    pub fn is_valid(&self) -> bool {
+        self.max_packets != Some(0) && self.max_bytes != Some(0) && self.max_time.is_none_or(|time| time > 0.0)
+    }
+}
+
+pub(crate) struct CycledVecDequeue<T> {
     size: usize,
     fifo: VecDeque<T>
 }
 
-impl<T> CycledVecDequeue<T>
-where T:Sized + Copy
-{
+impl<T> CycledVecDequeue<T> {
     pub fn new(size: usize) -> Self {
         let fifo = VecDeque::with_capacity(size);
         Self{ size, fifo }
@@ -32,11 +134,21 @@ where T:Sized + Copy
 
     pub fn try_push(&mut self, item: T) -> bool {
         if self.size>0 && self.fifo.len()==self.size {
-            return false;
+            false
         }
         else {
             self.fifo.push_back(item);
-            return true;
+            true
+        }
+    }
+
+    pub fn try_push_front(&mut self, item: T) -> bool {
+        if self.size>0 && self.fifo.len()==self.size {
+            false
+        }
+        else {
+            self.fifo.push_front(item);
+            true
         }
     }
 
@@ -52,6 +164,14 @@ where T:Sized + Copy
         self.fifo.pop_front()
     }
 
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.fifo.iter()
+    }
+
+    pub fn retain<F>(&mut self, f: F) where F: FnMut(&T) -> bool {
+        self.fifo.retain(f)
+    }
+
     pub fn reset(&mut self) {
         self.fifo.clear();
     }
@@ -60,79 +180,188 @@ where T:Sized + Copy
 pub struct RateThrottler {
     pub name: String,
     is_log: bool,
-    window: CycledVecDequeue<(TIME, SIZE)>,
+    window_size: usize,
+    limiter_params: LimiterParams,
+    limiter: Box<dyn RateLimiter>,
     buffer: CycledVecDequeue<PacketStruct>,
-    sum_bytes: usize,
-    acc_error: usize,
-    max_error: usize,
+    queue: QueueParams,
+    max_packets: usize,
+    buffer_bytes: usize,
+    pub queue_drops: usize, // packets dropped on overflow
+    drop_reports: FeedbackSenders, // of the frames losing packets once queued
+    last_dropped: Option<u32>,
+    sent: Option<SentFrames>,
+    deadline: Option<f64>,
+    sending: Option<u32>, // frame of the last packet consumed
+    pub stale_frames: usize,
+    pacing: PacingParams,
+    gaps: CycledVecDequeue<(u32, f64)>, // between two packets of a frame (s)
+    paced: usize, // packets of the frame being sent
+    next_send: f64,
     //
     pub throttle: f64,
     pub last_rate:f64,
 }
 
 impl RateThrottler {
-    pub fn new(name:String, throttle: f64, window_size:usize, no_logging:bool, infinite_buffer:bool) -> Self {
-        let buffer = match infinite_buffer {
-            true  => CycledVecDequeue::new(0),
-            false => CycledVecDequeue::new(CYCLED_RATIO * window_size)
-        };
+    pub fn new(name:String, throttle: f64, window_size:usize, no_logging:bool, queue:QueueParams) -> Self {
+        // bounded by `queue` rather than by its size
+        let buffer = CycledVecDequeue::new(0);
+        let max_packets = queue.max_packets.unwrap_or(CYCLED_RATIO * window_size);
         let is_log = !no_logging;
-        let window = CycledVecDequeue::new(window_size);
-        let max_error = (MAX_ERR_RATIO * window_size as f64) as usize * UDP_MAX_LENGTH;
+        let limiter_params = LimiterParams::default();
+        let limiter = limiter_params.limiter(window_size);
 
         // let last_rate = Arc::new(Mutex::new( 0.0 ));
         // let throttle = Arc::new(Mutex::new( throttle ));
 
-        Self{ name, is_log: is_log, window, buffer, throttle, last_rate:0.0,
-                sum_bytes:0, acc_error:0, max_error }
+        Self{ name, is_log, window_size, limiter_params, limiter, buffer, throttle, last_rate:0.0, sent:None,
+                queue, max_packets, buffer_bytes:0, queue_drops:0, drop_reports:Vec::new(), last_dropped:None,
+                deadline:None, sending:None, stale_frames:0,
+                pacing:PacingParams::default(), gaps:CycledVecDequeue::new(PACED_FRAMES), paced:0, next_send:0.0 }
     }
 
-    pub fn reset(&mut self) {
-        self.last_rate = 0.0;
-        (self.sum_bytes,self.acc_error,self.max_error) = (0,0,0);
-        self.window.reset();
-        self.buffer.reset();
+    /// Admit the packets with a fresh limiter of the given kind.
+    pub fn set_limiter(&mut self, params: LimiterParams) {
+        self.limiter_params = params;
+        self.limiter = params.limiter(self.window_size);
     }
 
-    pub fn current_rate_mbps(&mut self, extra_bytes:Option<usize>) -> Option<f64> {
-        if self.acc_error < self.max_error {
-            return Some(self.last_rate);
-            // if let Ok(rate)=self.last_rate.try_lock() { return Some(rate.clone()); }
-        } else {
-            self.acc_error = 0;
-        }
+    pub fn limiter_params(&self) -> LimiterParams {
+        self.limiter_params
+    }
 
-        let acc_size = self.sum_bytes + extra_bytes.unwrap_or(0);
+    /// Spread the packets of each frame over its interval, as given by `pacing`.
+    pub fn pace(&mut self, pacing: PacingParams) {
+        self.pacing = pacing;
+    }
 
-        let _last_time = self.window.front()?.0;
-        let acc_time = SystemTime::now().duration_since( _last_time ).unwrap();
-        let acc_time = acc_time.as_nanos();
+    /// Report the frames losing packets once queued to `senders`, not to take them for network loss.
+    pub fn report_drops(&mut self, senders: FeedbackSenders) {
+        self.drop_reports = senders;
+    }
+
+    /// Drop the whole frames queued longer than `deadline` (s), rather than sending them late.
+    pub fn drop_stale(&mut self, deadline: f64) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Keep the fragments of the frames prepared from now on, for `retransmit`.
+    pub fn keep_sent(&mut self, sent: SentFrames) {
+        self.sent = Some(sent);
+    }
+
+    pub fn is_keeping_sent(&self) -> bool {
+        self.sent.is_some()
+    }
+
+    /// Queue the NACKed fragments of frame `seq` ahead of the buffer, returning how many fit.
+    pub fn retransmit(&mut self, seq: u32, missing: &[u16], tail: Option<u16>) -> usize {
+        let Some(ref mut sent) = self.sent else { return 0 };
+        let mut queued = 0;
+        for packet in sent.resend(seq, missing, tail).into_iter().rev() {
+            let length = packet.length as usize;
+            if self.has_room(length) && self.buffer.try_push_front(packet) {
+                self.buffer_bytes += length;
+                queued += 1;
+            }
+        }
+        queued
+    }
 
-        let average_rate_mbps = 8.0 * (acc_size as f64/1e6) / (acc_time as f64*1e-9);
-        self.last_rate = average_rate_mbps;
-        // if let Ok(mut ref_rate) = self.last_rate.try_lock() {
-        //     *ref_rate = average_rate_mbps;
-        // }
-        Some(average_rate_mbps)
+    pub fn reset(&mut self) {
+        self.last_rate = 0.0;
+        self.limiter = self.limiter_params.limiter(self.window_size);
+        self.buffer.reset();
+        self.buffer_bytes = 0;
+        self.gaps.reset();
+        (self.paced, self.next_send) = (0, 0.0);
     }
 
-    pub fn prepare(&mut self, packets: Vec<PacketStruct>) {
+    /// Queue the packets of a frame due `interval` (s) before the next one, returning how many fit in the buffer.
+    pub fn prepare(&mut self, mut packets: Vec<PacketStruct>, interval: f64) -> usize {
         let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-        let _rate_mbps = self.current_rate_mbps(None).unwrap_or(0.0);
+        let _rate_mbps = self.current_rate_mbps();
         if self.is_log {
             trace!("Name {}, Time {:.9}, Buffer length {}, Rate, {:.6}\n", self.name, timestamp, self.buffer.len(), _rate_mbps);
         }
+        // queued at, until sent
+        packets.iter_mut().for_each(|packet| packet.timestamp = timestamp);
+        let span = self.pacing.span(interval);
+        if let (true, Some(packet)) = (span > 0.0, packets.first()) {
+            self.gaps.push(( packet.seq, span / packets.len() as f64 ));
+        }
+        if let Some(ref mut sent) = self.sent {
+            sent.keep(&packets);
+        }
+        let num_packets = packets.len();
+        if self.queue.overflow == QueueOverflow::DropFrame {
+            let bytes = packets.iter().map(|packet| packet.length as usize).sum::<usize>();
+            if self.buffer.len() + num_packets > self.max_packets || !self.has_room(bytes) {
+                self.queue_drops += num_packets;
+                return 0;
+            }
+        }
+        let mut queued = 0;
         for packet in packets.into_iter() {
-            self.buffer.try_push(packet);
+            let length = packet.length as usize;
+            if self.queue.overflow == QueueOverflow::DropHead {
+                while !self.has_room(length) {
+                    let Some(dropped) = self.pop() else { break };
+                    self.queue_drops += 1;
+                    self.report_dropped(dropped.seq);
+                }
+            }
+            // a blocking queue takes the whole frame, the source waiting for room before the next one
+            if (self.queue.overflow == QueueOverflow::Block || self.has_room(length)) && self.buffer.try_push(packet) {
+                self.buffer_bytes += length;
+                queued += 1;
+            } else {
+                self.queue_drops += 1;
+            }
         }
+        queued
+    }
+
+    /// Whether the source should hold its next frame back, for a blocking queue.
+    pub fn is_blocking(&self) -> bool {
+        self.queue.overflow == QueueOverflow::Block && !self.has_room(0)
+    }
+
+    /// Packets and bytes in the buffer.
+    pub fn occupancy(&self) -> (usize, usize) {
+        (self.buffer.len(), self.buffer_bytes)
     }
 
-    pub fn try_consume<T>(&mut self, callback:T) -> Option<bool>
-    where T: Fn(PacketStruct) -> bool {
+    // whether one more packet of `length` bytes fits in the buffer
+    fn has_room(&self, length: usize) -> bool {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        self.buffer.len() < self.max_packets &&
+            self.queue.max_bytes.is_none_or(|max_bytes| self.buffer_bytes + length <= max_bytes) &&
+            self.queue.max_time.is_none_or(|max_time| {
+                self.buffer.front().is_none_or(|packet| timestamp - packet.timestamp <= max_time)
+            })
+    }
+
+    fn pop(&mut self) -> Option<PacketStruct> {
+        let packet = self.buffer.pop_front()?;
+        self.buffer_bytes -= packet.length as usize;
+        Some(packet)
+    }
+
+    pub fn try_consume<T>(&mut self, mut callback:T) -> Option<bool>
+    where T: FnMut(PacketStruct) -> bool {
+        if let Some(deadline) = self.deadline {
+            self.remove_stale(deadline);
+        }
         match self.buffer.front().cloned() {
             None => None,
             Some(packet) => {
-                if self.exceeds_with(packet.length as usize) {
+                if self.is_paced() {
+                    std::thread::sleep( std::time::Duration::from_nanos(100_000) );
+                    return Some(false);
+                }
+                if self.limiter.exceeds_with(self.throttle, packet.length as usize, SystemTime::now()) {
                     std::thread::sleep( std::time::Duration::from_nanos(100_000) );
                     return Some(false);
                 }
@@ -147,40 +376,230 @@ impl RateThrottler {
         }
     }
 
+    // the frames at the head of the buffer beyond their deadline, with all their packets,
+    // unless already being sent
+    fn remove_stale(&mut self, deadline: f64) {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        while let Some(packet) = self.buffer.front() {
+            if timestamp - packet.timestamp <= deadline || self.sending == Some(packet.seq) {
+                break;
+            }
+            let seq = packet.seq;
+            self.buffer.retain(|packet| packet.seq != seq);
+            self.buffer_bytes = self.buffer.iter().map(|packet| packet.length as usize).sum();
+            self.stale_frames += 1;
+            self.report_dropped(seq);
+            if self.is_log {
+                trace!("Name {}, Time {:.9}, Stale frame {}, Buffer length {}\n", self.name, timestamp, seq, self.buffer.len());
+            }
+        }
+    }
+
+    // once per frame, its packets dropped together
+    fn report_dropped(&mut self, seq: u32) {
+        if self.last_dropped == Some(seq) {
+            return;
+        }
+        self.last_dropped = Some(seq);
+        for tx in self.drop_reports.iter() {
+            let _ = tx.send(Feedback::Dropped{ seq });
+        }
+    }
+
     pub fn consume(&mut self) -> Option<PacketStruct> {
         let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-        let _rate_mbps = self.current_rate_mbps(None).unwrap_or(0.0);
+        let _rate_mbps = self.current_rate_mbps();
         if self.is_log {
             trace!("Name {}, Time {:.9}, Buffer length {}, Rate, {:.6}\n", self.name, timestamp, self.buffer.len(), _rate_mbps);
         }
-        self.buffer.pop_front()
+        let packet = self.pop();
+        // the k-th packet of a frame is due `k * gap` after the frame was queued
+        if let Some(ref packet) = packet {
+            self.paced = if self.sending == Some(packet.seq) { self.paced + 1 } else { 1 };
+            let gap = self.gaps.iter().rev().find(|(seq, _)| *seq == packet.seq).map_or(0.0, |(_, gap)| *gap);
+            self.next_send = packet.timestamp + self.paced as f64 * gap;
+        }
+        self.sending = packet.as_ref().map(|packet| packet.seq);
+        packet
     }
 
-    pub fn exceeds_with(&mut self, size_bytes:usize) -> bool {
-        let _throttle = self.throttle;//.lock().unwrap().clone();
+    // whether the next packet is held back by pacing
+    fn is_paced(&self) -> bool {
+        self.next_send > 0.0 &&
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64() < self.next_send
+    }
 
-        if _throttle==0.0 || self.window.len()==0 {
-            self.sum_bytes += size_bytes;
-            if let Some(item) = self.window.push(( SystemTime::now(), size_bytes )) {
-                self.sum_bytes -= item.1 as usize;
-                self.acc_error += item.1;
-            }
-            return false;
+    fn current_rate_mbps(&mut self) -> f64 {
+        if let Some(rate_mbps) = self.limiter.rate_mbps(SystemTime::now()) {
+            self.last_rate = rate_mbps;
         }
+        self.last_rate
+    }
+}
 
-        self.acc_error += size_bytes;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::frame;
 
-        let average_rate_mbps = self.current_rate_mbps( Some(size_bytes) );
-        if average_rate_mbps.unwrap() < _throttle {
-            self.sum_bytes += size_bytes;
-            if let Some(item) = self.window.push(( SystemTime::now(), size_bytes )) {
-                self.sum_bytes -= item.1 as usize;
-                self.acc_error += item.1;
-            }
-            false
-        }
-        else {
-            true
+    fn throttler(max_packets: usize, overflow: QueueOverflow) -> RateThrottler {
+        let queue = QueueParams { max_packets: Some(max_packets), overflow, ..Default::default() };
+        RateThrottler::new("test".into(), 0.0, 10, true, queue)
+    }
+
+    fn queued(throttler: &RateThrottler) -> Vec<(u32, u16)> {
+        throttler.buffer.fifo.iter().map(|packet| (packet.seq, packet.offset)).collect()
+    }
+
+    // as if queued `secs` earlier
+    fn age(throttler: &mut RateThrottler, secs: f64) {
+        throttler.buffer.fifo.iter_mut().for_each(|packet| packet.timestamp -= secs);
+    }
+
+    #[test]
+    fn drops_stale_frames() {
+        let mut throttler = throttler(10, QueueOverflow::DropTail);
+        throttler.drop_stale(0.01);
+        throttler.prepare(frame(1, 2, 100), 0.0);
+        throttler.prepare(frame(2, 2, 100), 0.0);
+        assert_eq!(throttler.try_consume(|_| true), Some(true));
+        age(&mut throttler, 1.0);
+        // not the frame being sent, nor those behind it
+        assert_eq!(throttler.try_consume(|packet| packet.offset == 1), Some(true));
+        assert_eq!(throttler.stale_frames, 0);
+        throttler.prepare(frame(3, 2, 100), 0.0);
+        assert_eq!(throttler.try_consume(|packet| packet.seq == 3), Some(true));
+        assert_eq!(throttler.stale_frames, 1);
+        assert_eq!(queued(&throttler), [(3, 1)]);
+    }
+
+    #[test]
+    fn keeps_fresh_frames() {
+        let mut throttler = throttler(10, QueueOverflow::DropTail);
+        throttler.drop_stale(10.0);
+        throttler.prepare(frame(1, 2, 100), 0.0);
+        throttler.prepare(frame(2, 2, 100), 0.0);
+        age(&mut throttler, 1.0);
+        assert_eq!(throttler.try_consume(|_| false), Some(false));
+        assert_eq!(throttler.stale_frames, 0);
+        assert_eq!(queued(&throttler), [(1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn stale_deadline() {
+        let params = StaleParams{ policy: StalePolicy::TargetRtt, deadline: 0.0 };
+        assert!(params.is_valid());
+        assert_eq!(params.deadline(0.05), 0.05);
+        let params = StaleParams{ policy: StalePolicy::Deadline, deadline: 0.2 };
+        assert!(params.is_valid());
+        assert_eq!(params.deadline(0.05), 0.2);
+        assert!(!StaleParams{ policy: StalePolicy::Deadline, deadline: 0.0 }.is_valid());
+    }
+
+    #[test]
+    fn paces_frames() {
+        assert_eq!(PacingParams { mode: PacingMode::Fraction, fraction: 0.5 }.span(0.04), 0.02);
+        assert_eq!(PacingParams { mode: PacingMode::Burst, fraction: 0.5 }.span(0.04), 0.0);
+        assert!(!PacingParams { mode: PacingMode::Fraction, fraction: 1.5 }.is_valid());
+
+        let mut paced = throttler(10, QueueOverflow::DropTail);
+        paced.pace(PacingParams { mode: PacingMode::Interval, fraction: 0.0 });
+        // a packet every 10 ms from the time the frame is queued
+        paced.prepare(frame(1, 4, 100), 0.04);
+        paced.prepare(frame(2, 2, 100), 0.04);
+        let queued_at = paced.buffer.front().unwrap().timestamp;
+        for k in 1..=4 {
+            assert_eq!(paced.consume().map(|packet| packet.offset), Some(k - 1));
+            assert!((paced.next_send - (queued_at + 0.01 * k as f64)).abs() < 1e-9);
         }
+        // anew for the next frame, of 2 packets
+        let queued_at = paced.buffer.front().unwrap().timestamp;
+        paced.consume();
+        assert!((paced.next_send - (queued_at + 0.02)).abs() < 1e-9);
+
+        // held back until then
+        paced.next_send = f64::MAX;
+        assert_eq!(paced.try_consume(|_| true), Some(false));
+        paced.next_send = 1.0;
+        assert_eq!(paced.try_consume(|_| true), Some(true));
+
+        // back to back otherwise
+        let mut burst = throttler(10, QueueOverflow::DropTail);
+        burst.prepare(frame(1, 4, 100), 0.04);
+        assert_eq!((0..4).filter_map(|_| burst.try_consume(|_| true)).collect::<Vec<_>>(), [true; 4]);
+        assert!(!burst.is_paced());
+    }
+
+    #[test]
+    fn drop_tail() {
+        let mut throttler = throttler(4, QueueOverflow::DropTail);
+        assert_eq!(throttler.prepare(frame(1, 3, 100), 0.0), 3);
+        assert_eq!(throttler.prepare(frame(2, 3, 100), 0.0), 1);
+        assert_eq!((throttler.occupancy(), throttler.queue_drops), ((4, 400), 2));
+        assert_eq!(queued(&throttler), [(1, 0), (1, 1), (1, 2), (2, 0)]);
+        while throttler.consume().is_some() {}
+        assert_eq!(throttler.occupancy(), (0, 0));
+    }
+
+    #[test]
+    fn drop_head() {
+        let mut throttler = throttler(4, QueueOverflow::DropHead);
+        throttler.prepare(frame(1, 3, 100), 0.0);
+        assert_eq!(throttler.prepare(frame(2, 3, 100), 0.0), 3);
+        assert_eq!(throttler.queue_drops, 2);
+        assert_eq!(queued(&throttler), [(1, 2), (2, 0), (2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn drop_frame() {
+        let mut throttler = throttler(4, QueueOverflow::DropFrame);
+        throttler.prepare(frame(1, 3, 100), 0.0);
+        assert_eq!(throttler.prepare(frame(2, 3, 100), 0.0), 0);
+        assert_eq!(throttler.prepare(frame(3, 1, 100), 0.0), 1);
+        assert_eq!(throttler.queue_drops, 3);
+        assert_eq!(queued(&throttler), [(1, 0), (1, 1), (1, 2), (3, 0)]);
+    }
+
+    #[test]
+    fn block() {
+        let mut throttler = throttler(4, QueueOverflow::Block);
+        throttler.prepare(frame(1, 3, 100), 0.0);
+        assert!(!throttler.is_blocking());
+        // the whole frame is taken, the next one held back
+        assert_eq!(throttler.prepare(frame(2, 3, 100), 0.0), 3);
+        assert_eq!((throttler.occupancy(), throttler.queue_drops), ((6, 600), 0));
+        assert!(throttler.is_blocking());
+        while throttler.consume().is_some() {}
+        assert!(!throttler.is_blocking());
+    }
+
+    #[test]
+    fn max_bytes() {
+        let queue = QueueParams { max_bytes: Some(250), ..Default::default() };
+        let mut throttler = RateThrottler::new("test".into(), 0.0, 10, true, queue);
+        assert_eq!(throttler.prepare(frame(1, 3, 100), 0.0), 2);
+        assert_eq!(throttler.occupancy(), (2, 200));
+        assert!(!QueueParams { max_bytes: Some(0), ..Default::default() }.is_valid());
+        assert!(!QueueParams { max_time: Some(0.0), ..Default::default() }.is_valid());
+    }
+
+    #[test]
+    fn reports_drops() {
+        let (tx, rx) = flume::unbounded();
+        let mut throttler = throttler(4, QueueOverflow::DropHead);
+        throttler.report_drops(vec![tx]);
+        throttler.drop_stale(0.01);
+        throttler.prepare(frame(1, 3, 100), 0.0);
+        throttler.prepare(frame(2, 3, 100), 0.0);
+        age(&mut throttler, 1.0);
+        throttler.prepare(frame(3, 1, 100), 0.0);
+        assert_eq!(throttler.try_consume(|_| true), Some(true));
+        // once per frame, from the head and as stale
+        let dropped: Vec<_> = rx.try_iter().map(|event| match event {
+            Feedback::Dropped { seq } => seq,
+            _ => unreachable!(),
+        }).collect();
+        assert_eq!(dropped, [1, 2]);
+        assert_eq!((throttler.queue_drops, throttler.stale_frames), (3, 1));
     }
 }
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use ndarray::prelude::*;
+use ndarray_npy::read_npy;
+
+use core::pcap::read_flow;
+use crate::conf::ConnParams;
+use crate::generator::FrameGenerator;
+
+pub const PCAP_EXTENSIONS: [&str; 2] = [".pcap", ".pcapng"];
+pub const CSV_EXTENSIONS: [&str; 1] = [".csv"];
+pub const JSONL_EXTENSIONS: [&str; 2] = [".jsonl", ".ndjson"];
+
+/// Rows of [interval_ns, size], where the interval is the time until the next frame,
+/// and the payload of each row when replayed from a capture.
+type Trace = (Array2<u64>, Option<Vec<Vec<u8>>>);
+
+// Timing columns of CSV and JSONL traces, exactly one of them along with `size` (bytes):
+//   interval_ns / interval: time until the next frame, in nanoseconds / seconds (as the npy traces)
+//   timestamp_ns / timestamp: absolute sending time, in nanoseconds / seconds
+const TIMING_COLUMNS: [(&str, Timing); 4] = [
+    ("interval_ns", Timing::Interval(1e-9)),
+    ("interval", Timing::Interval(1.0)),
+    ("timestamp_ns", Timing::Timestamp(1e-9)),
+    ("timestamp", Timing::Timestamp(1.0)),
+];
+const SIZE_COLUMN: &str = "size";
+
+#[derive(Clone, Copy)]
+enum Timing {
+    Interval(f64),  // seconds per unit
+    Timestamp(f64), // seconds per unit
+}
+
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    extensions.iter().any(|ext| file.ends_with(ext))
+}
+
+fn load_trace(params: &ConnParams) -> Result<Trace, String> {
+    let file = params.npy_file.as_str();
+    if has_extension(file, &PCAP_EXTENSIONS) {
+        load_pcap(params)
+    } else if has_extension(file, &CSV_EXTENSIONS) {
+        load_columns(file, read_csv(file)?)
+    } else if has_extension(file, &JSONL_EXTENSIONS) {
+        load_columns(file, read_jsonl(file)?)
+    } else {
+        let trace: Array2<u64> = read_npy(file).map_err(|e| format!("{}: {}", file, e))?;
+        Ok((trace, None))
+    }
+}
+
+/// Frames of a trace, looping from the row after `start_offset`.
+pub struct TraceReplay {
+    trace: Array2<u64>,
+    payloads: Option<Vec<Vec<u8>>>,
+    idx: usize,
+}
+
+impl TraceReplay {
+    pub fn load(params: &ConnParams) -> Result<Self, String> {
+        let (trace, payloads) = load_trace(params)?;
+        if trace.is_empty() {
+            return Err(format!("{}: no frame to replay", params.npy_file));
+        }
+        let idx = params.start_offset % trace.shape()[0];
+        Ok(Self { trace, payloads, idx })
+    }
+}
+
+impl FrameGenerator for TraceReplay {
+    fn next_frame(&mut self) -> (u64, usize) {
+        self.idx = (self.idx + 1) % self.trace.shape()[0];
+        (self.trace[[self.idx, 0]], self.trace[[self.idx, 1]] as usize)
+    }
+
+    fn payload(&self) -> Option<&[u8]> {
+        self.payloads.as_ref().map(|payloads| payloads[self.idx].as_slice())
+    }
+}
+
+// intervals until the next frame from absolute times, the last frame having none
+fn to_intervals(timestamps: &[f64]) -> Vec<f64> {
+    timestamps.iter().enumerate().map(|(i, t)| {
+        timestamps.get(i + 1).map_or(0.0, |next| (next - t).max(0.0))
+    }).collect()
+}
+
+fn load_pcap(params: &ConnParams) -> Result<Trace, String> {
+    let flow = read_flow(&params.npy_file, &params.pcap_filter).map_err(|e| e.to_string())?;
+    let timestamps: Vec<_> = flow.iter().map(|packet| packet.timestamp).collect();
+    let mut trace = Array2::<u64>::zeros((flow.len(), 2));
+    for (i, (packet, interval)) in flow.iter().zip(to_intervals(&timestamps)).enumerate() {
+        trace[[i, 0]] = (interval * 1e9) as u64;
+        trace[[i, 1]] = packet.size as u64;
+    }
+    let payloads = params.replay_payload.then(|| {
+        flow.into_iter().map(|packet| {
+            let mut payload = packet.payload;
+            payload.resize(packet.size, 0); // zero-filled beyond the capture length
+            payload
+        }).collect()
+    });
+    Ok((trace, payloads))
+}
+
+// (column name, value) of each row
+type Rows = Vec<HashMap<String, f64>>;
+
+fn read_csv(file: &str) -> Result<Rows, String> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(file)
+        .map_err(|e| format!("{}: {}", file, e))?;
+    let headers = reader.headers().map_err(|e| format!("{}: {}", file, e))?.clone();
+    reader.records().map(|record| {
+        let record = record.map_err(|e| format!("{}: {}", file, e))?;
+        headers.iter().zip(record.iter()).map(|(name, value)| {
+            let value = value.parse::<f64>().map_err(|_| format!("{}: invalid {} \"{}\"", file, name, value))?;
+            Ok((name.to_string(), value))
+        }).collect()
+    }).collect()
+}
+
+fn read_jsonl(file: &str) -> Result<Rows, String> {
+    let reader = BufReader::new(File::open(file).map_err(|e| format!("{}: {}", file, e))?);
+    reader.lines().enumerate().filter_map(|(i, line)| {
+        match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(
+                serde_json::from_str::<HashMap<String, serde_json::Value>>(&line)
+                    .map_err(|e| format!("{}:{}: {}", file, i + 1, e))
+                    .map(|row| row.into_iter().filter_map(|(k, v)| Some((k, v.as_f64()?))).collect())
+            ),
+            Err(e) => Some(Err(format!("{}: {}", file, e))),
+        }
+    }).collect()
+}
+
+fn load_columns(file: &str, rows: Rows) -> Result<Trace, String> {
+    let Some(first) = rows.first() else {
+        return Ok((Array2::zeros((0, 2)), None));
+    };
+    let timings: Vec<_> = TIMING_COLUMNS.iter().filter(|(name, _)| first.contains_key(*name)).collect();
+    let &[&(timing_column, timing)] = timings.as_slice() else {
+        let names: Vec<_> = TIMING_COLUMNS.iter().map(|(name, _)| *name).collect();
+        return Err(format!("{}: expecting exactly one timing column of {:?}", file, names));
+    };
+
+    let column = |name: &str| -> Result<Vec<f64>, String> {
+        rows.iter().enumerate().map(|(i, row)| {
+            row.get(name).copied().filter(|v| *v >= 0.0)
+                .ok_or_else(|| format!("{}: row {} has no valid {}", file, i + 1, name))
+        }).collect()
+    };
+    let sizes = column(SIZE_COLUMN)?;
+    let intervals = match timing {
+        Timing::Interval(unit) => column(timing_column)?.iter().map(|v| v * unit).collect(),
+        Timing::Timestamp(unit) => to_intervals(&column(timing_column)?.iter().map(|v| v * unit).collect::<Vec<_>>()),
+    };
+
+    let mut trace = Array2::<u64>::zeros((rows.len(), 2));
+    for (i, (interval, size)) in intervals.into_iter().zip(sizes).enumerate() {
+        trace[[i, 0]] = (interval * 1e9).round() as u64;
+        trace[[i, 1]] = size as u64;
+    }
+    Ok((trace, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::testing::temp_path;
+
+    fn load(name: &str, content: &str) -> Result<Array2<u64>, String> {
+        let file = temp_path(name);
+        std::fs::write(&file, content).unwrap();
+        let rows = if has_extension(&file, &CSV_EXTENSIONS) { read_csv(&file) } else { read_jsonl(&file) };
+        let trace = rows.and_then(|rows| load_columns(&file, rows)).map(|(trace, _)| trace);
+        std::fs::remove_file(&file).unwrap();
+        trace
+    }
+
+    #[test]
+    fn csv_intervals() {
+        let trace = load("intervals.csv", "size, interval_ns\n1000, 33000000\n 1500 ,0\n").unwrap();
+        assert_eq!(trace, array![[33_000_000, 1000], [0, 1500]]);
+        let trace = load("intervals-s.csv", "interval,size,extra\n0.5,10,7\n").unwrap();
+        assert_eq!(trace, array![[500_000_000, 10]]);
+    }
+
+    #[test]
+    fn csv_timestamps() {
+        let trace = load("timestamps.csv", "timestamp,size\n10.0,100\n10.25,200\n10.2,300\n").unwrap();
+        // the last frame has no interval, and time never goes backwards
+        assert_eq!(trace, array![[250_000_000, 100], [0, 200], [0, 300]]);
+        let trace = load("timestamps-ns.csv", "timestamp_ns,size\n1000,1\n3000,2\n").unwrap();
+        assert_eq!(trace, array![[2000, 1], [0, 2]]);
+    }
+
+    #[test]
+    fn csv_rejects() {
+        assert!(load("no-timing.csv", "size\n100\n").unwrap_err().contains("exactly one timing column"));
+        assert!(load("two-timings.csv", "interval,timestamp,size\n1,1,100\n").unwrap_err().contains("exactly one timing column"));
+        assert!(load("no-size.csv", "interval\n1\n").unwrap_err().contains("row 1 has no valid size"));
+        assert!(load("negative.csv", "interval,size\n1,100\n-1,100\n").unwrap_err().contains("row 2 has no valid interval"));
+        assert!(load("invalid.csv", "interval,size\n1,big\n").unwrap_err().contains("invalid size \"big\""));
+        assert!(load("ragged.csv", "interval,size\n1,100,3\n").is_err());
+        assert!(load("empty.csv", "interval,size\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn jsonl() {
+        let content = "{\"timestamp\": 1.0, \"size\": 100, \"kind\": \"I\"}\n\n{\"timestamp\": 1.5, \"size\": 50}\n";
+        let trace = load("frames.jsonl", content).unwrap();
+        assert_eq!(trace, array![[500_000_000, 100], [0, 50]]);
+        let trace = load("frames.ndjson", "{\"interval_ns\": 10, \"size\": 1}\n").unwrap();
+        assert_eq!(trace, array![[10, 1]]);
+    }
+
+    #[test]
+    fn jsonl_rejects() {
+        assert!(load("bad.jsonl", "{\"interval\": 1, \"size\": 1}\n{\"interval\": 1,\n").unwrap_err().contains(":2:"));
+        // non-numeric values count as missing
+        assert!(load("string.jsonl", "{\"interval\": 1, \"size\": \"1\"}\n").unwrap_err().contains("row 1 has no valid size"));
+        assert!(load("missing.jsonl", "{\"interval\": 1, \"size\": 1}\n{\"size\": 1}\n").unwrap_err().contains("row 2 has no valid interval"));
+    }
+
+    #[test]
+    fn replays_in_loop() {
+        let file = temp_path("replay.csv");
+        std::fs::write(&file, "interval_ns,size\n1,100\n2,200\n3,300\n").unwrap();
+        let params: ConnParams = serde_json::from_value(serde_json::json!({ "npy_file": file, "start_offset": 4 })).unwrap();
+        let mut replay = TraceReplay::load(&params).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        // from the row after `start_offset`
+        let frames: Vec<_> = (0..4).map(|_| replay.next_frame()).collect();
+        assert_eq!(frames, [(3, 300), (1, 100), (2, 200), (3, 300)]);
+        assert!(replay.payload().is_none());
+    }
+}
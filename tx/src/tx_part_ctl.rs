@@ -1,25 +1,28 @@
-use core::packet::{self, PacketType};
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use log::trace;
+use serde::{Serialize, Deserialize};
+
+use core::packet::{FIRST_OF_PATH, LAST_OF_PATH, LAST_OF_FRAME};
 use crate::link::Link;
+use crate::statistic::rtt_records::RttRecords;
 
-type OffsetPacket = (u16, PacketType);
+type OffsetPacket = (u16, u8, u8); // (offset, path, indicators)
 #[derive(Debug)]
 pub struct TxPartCtler {
     pub tx_parts: Vec<f64>,
-    tx_ipaddrs: Vec<String>,
 }
 
 impl TxPartCtler {
     pub fn new(tx_parts: Vec<f64>, links: Vec<Link>) -> Self {
-        let mut tx_ipaddrs = Vec::new();
-        for link in links.iter() {
-            tx_ipaddrs.push(link.tx_ipaddr.clone());
-        }
-        if tx_parts.len() != tx_ipaddrs.len() {
-            panic!("tx_parts and tx_ipaddrs must have the same length");
+        if tx_parts.len() != links.len() {
+            panic!("tx_parts and links must have the same length");
         }
         TxPartCtler {
             tx_parts,
-            tx_ipaddrs,
         }
     }
 
@@ -27,89 +30,373 @@ impl TxPartCtler {
         self.tx_parts = tx_parts;
     }
 
-    //   Channel 1  <-----------------------
-    // ------------------------->  Channel 0
-    // 0, 1, ..., 12, 13, 14, 15,..., 49, 50
-    //             ^           ^
-    //             |           |
-    //        tx_part_ch1  tx_part_ch0
+    /// Share of the frame sent by each link alone, from the front slices.
+    pub fn shares(&self) -> Vec<f64> {
+        let link_num = self.tx_parts.len();
+        let mut edge = 0.0;
+        let mut shares: Vec<_> = self.tx_parts.iter().take(link_num.saturating_sub(1)).map(|part| {
+            let share = (part.clamp(0.0, 1.0) - edge).max(0.0);
+            edge += share;
+            share
+        }).collect();
+        shares.push(1.0 - edge);
+        shares
+    }
+
+    /// Consecutive slices of the given shares, the last link filling from the back
+    /// with the same overlap with the front slices as before, if any.
+    pub fn set_shares(&mut self, shares: &[f64]) {
+        if shares.len() < 2 || shares.len() != self.tx_parts.len() {
+            return;
+        }
+        // a last link starting at zero was unused, rather than overlapping
+        let (front_edge, back_part) = (1.0 - self.shares().last().copied().unwrap_or(0.0), self.tx_parts[shares.len() - 1]);
+        let overlap = if back_part > 0.0 { front_edge - back_part } else { 0.0 };
+        let mut edge = 0.0;
+        let mut tx_parts: Vec<_> = shares.iter().take(shares.len().saturating_sub(1)).map(|share| {
+            edge += share;
+            edge
+        }).collect();
+        // still from the back, the overlap capped by the front slices
+        tx_parts.push((edge - overlap).clamp(f64::MIN_POSITIVE, 1.0));
+        self.tx_parts = tx_parts;
+    }
+
+    //   Link 0        Link 1            Link N-1  <-----------
+    // ----------> ------------->  ...
+    // 0, 1, ..., 12, 13, 14, 15, ..., 38, 39, 40, ..., 49, 50
+    //             ^             ^          ^
+    //             |             |          |
+    //       tx_parts[0]   tx_parts[1]  tx_parts[N-1]
+    //
+    // Links 0..N-2 take consecutive slices from the front, link i ending at tx_parts[i].
+    // The last link fills from the back down to tx_parts[N-1]; any overlap with the
+    // front slices is sent redundantly. A last link starting at (or below) zero is
+    // unused while the front slices carry anything, and the front then extends to the end.
+
+    fn path_ranges(&self, num: usize) -> Vec<(usize, usize)> {
+        let link_num = self.tx_parts.len();
+        if link_num < 2 {
+            return vec![(0, num)];
+        }
+        let edge = |part: f64| ((part * num as f64).ceil().max(0.0) as usize).min(num);
 
-    fn is_single_channel(&self, num: usize) -> bool {
-        if self.tx_parts.len() < 2 {
-            return true;
+        let mut ranges = Vec::with_capacity(link_num);
+        let mut lo = 0;
+        for part in self.tx_parts[..link_num-1].iter() {
+            let hi = edge(*part).max(lo);
+            ranges.push((lo, hi));
+            lo = hi;
         }
 
-        let tx_part_ch0 = self.tx_parts[0] * num as f64;
-        let tx_part_ch1 = self.tx_parts[1] * num as f64;
-
-        tx_part_ch1 <= 0.0 || ((num - 1) as f64) < tx_part_ch0
-    }
-
-    pub fn get_packet_state(&self, offset: f64, num: usize, channel: u16) -> Option<PacketType> {
-        match channel {
-            1 => {
-                let tx_part_ch0 = self.tx_parts[0] * num as f64;
-                match (offset < tx_part_ch0, offset >= tx_part_ch0 - 1.0) {
-                    (true, false) => Some(PacketType::DFN),
-                    (true, true) => Some(PacketType::DFL),
-                    _ => None,
-                }
-            },
-            2 => {
-                let tx_part_ch1 = self.tx_parts[1] * num as f64;
-                match (offset >= tx_part_ch1, offset < tx_part_ch1 + 1.0, offset == (num - 1) as f64) {
-                    (true, false, false) => Some(PacketType::DSM),
-                    (true, true, false) => Some(PacketType::DSL),
-                    (true, false, true) => Some(PacketType::DSF),
-                    (true, true, true) => Some(PacketType::DSS),
-                    _ => None,
-                }
-            },
-            _ => {
-                if offset == (num - 1) as f64 {
-                    Some(PacketType::SL)
-                } else {
-                    Some(PacketType::SNL)
-                }
+        let back_part = self.tx_parts[link_num-1];
+        if lo >= num || (back_part <= 0.0 && lo > 0) {
+            if let Some(last) = ranges.iter_mut().rev().find(|(lo, hi)| hi > lo) {
+                last.1 = num;
             }
+            ranges.push((num, num));
+        } else {
+            ranges.push((edge(back_part), num));
         }
+        ranges
     }
 
     pub fn get_packet_states(&self, num: usize) -> Vec<Vec<OffsetPacket>> {
-        let mut results = vec![Vec::new(); 3];
-    
-        if self.is_single_channel(num) {
-            for offset in 0..num {
-                if let Some(packet_type) = self.get_packet_state(offset as f64, num, 0) {
-                    results[0].push((offset as u16, packet_type));
-                }
-            }
-        } else {
-            for offset in 0..num {
-                if let Some(packet_type) = self.get_packet_state(offset as f64, num, 1)  {
-                    results[1].push((offset as u16, packet_type));
-                }
-                if let Some(packet_type) = self.get_packet_state(offset as f64, num, 2) {
-                    results[2].push((offset as u16, packet_type));
-                }
+        let ranges = self.path_ranges(num);
+        let last_path = ranges.len() - 1;
+
+        ranges.into_iter().enumerate().map(|(path, (lo, hi))| {
+            let mut results: Vec<OffsetPacket> = (lo..hi).map(|offset| {
+                let mut indicators = 0;
+                if offset == lo { indicators |= FIRST_OF_PATH; }
+                if offset == hi - 1 { indicators |= LAST_OF_PATH; }
+                if offset == num - 1 { indicators |= LAST_OF_FRAME; }
+                (offset as u16, path as u8, indicators)
+            }).collect();
+            // the last link sends from the back of the frame
+            if path > 0 && path == last_path {
+                results.reverse();
             }
+            results
+        }).collect()
+    }
+}
+
+const fn _default_interval() -> f64 { 1.0 }
+const fn _default_step() -> f64 { 0.05 }
+const fn _default_max_outage() -> f64 { 0.05 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ControlAlgorithm {
+    /// Move `step` of share from the worst link beyond target to the best one.
+    #[default]
+    #[serde(rename = "step")]
+    Step,
+    /// Scale down the share of every link beyond the target by its excess, with `step` as gain.
+    #[serde(rename = "proportional")]
+    Proportional,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlParams {
+    #[serde(default)] pub algorithm: ControlAlgorithm, //default: "step"
+    #[serde(default = "_default_interval")]            //default:
+    pub interval: f64,                                 //         1.0 (s)
+    #[serde(default = "_default_step")]                //default:
+    pub step: f64,                                     //         0.05
+    #[serde(default = "_default_max_outage")]          //default:
+    pub max_outage: f64,                               //         0.05, of frames beyond `target_rtt` on a link
+    #[serde(default)] pub min_part: f64,               //default: 0.0, share kept on every link
+}
+
+impl ControlParams {
+    pub fn is_valid(&self) -> bool {
+        self.interval > 0.0 && self.step > 0.0 && (0.0..1.0).contains(&self.min_part)
+    }
+
+    /// A fresh instance of the control law.
+    pub fn algorithm(&self) -> Box<dyn TxPartAlgorithm> {
+        match self.algorithm {
+            ControlAlgorithm::Step => Box::new(StepControl{ step: self.step, max_outage: self.max_outage }),
+            ControlAlgorithm::Proportional => Box::new(ProportionalControl{ gain: self.step }),
         }
-        // inverse results[2]
-        results[2].reverse();
-        results
     }
+}
 
-    pub fn packet_to_ipaddr(&self, indicator: u8) -> String {
+/// Statistics of each link over the last control interval, an RTT of zero when without samples.
+pub struct LinkStatistics {
+    pub target_rtt: f64,
+    pub rtts: Vec<f64>,
+    pub outage_rates: Vec<f64>,
+}
 
-        let channel = {
-            if self.tx_parts.len() >= 2 &&self.tx_parts[0] <= 0.0 {
-                1 as u8
-            }
-            else {
-                packet::channel_info(indicator)
+impl LinkStatistics {
+    // RTT relative to the target, unknown without samples
+    fn load(&self, path: usize) -> Option<f64> {
+        let rtt = self.rtts[path];
+        (rtt > 0.0).then(|| rtt / self.target_rtt + self.outage_rates[path])
+    }
+}
+
+/// Control law of the share of each link (summing to 1), applied at every interval.
+pub trait TxPartAlgorithm: Send {
+    /// The new shares, if to be changed.
+    fn update(&mut self, shares: &[f64], stats: &LinkStatistics) -> Option<Vec<f64>>;
+}
+
+struct StepControl {
+    step: f64,
+    max_outage: f64,
+}
+
+impl TxPartAlgorithm for StepControl {
+    fn update(&mut self, shares: &[f64], stats: &LinkStatistics) -> Option<Vec<f64>> {
+        // a used link without samples lost all of them, an unused one is worth a try
+        let load = |path: usize| stats.load(path).unwrap_or(if shares[path] > 0.0 { f64::INFINITY } else { 0.0 });
+        let paths = 0..shares.len();
+        let worst = paths.clone().filter(|&i| shares[i] > 0.0).max_by(|&a, &b| load(a).total_cmp(&load(b)))?;
+        let best = paths.min_by(|&a, &b| load(a).total_cmp(&load(b)))?;
+        let beyond = stats.rtts[worst] == 0.0 || stats.rtts[worst] > stats.target_rtt || stats.outage_rates[worst] > self.max_outage;
+        if worst == best || !beyond {
+            return None;
+        }
+        let step = self.step.min(shares[worst]);
+        let mut shares = shares.to_vec();
+        shares[worst] -= step;
+        shares[best] += step;
+        Some(shares)
+    }
+}
+
+struct ProportionalControl {
+    gain: f64,
+}
+
+impl TxPartAlgorithm for ProportionalControl {
+    fn update(&mut self, shares: &[f64], stats: &LinkStatistics) -> Option<Vec<f64>> {
+        let shares: Vec<_> = shares.iter().enumerate().map(|(path, share)| {
+            match stats.load(path) {
+                Some(load) => share * (self.gain * (1.0 - load).min(0.0)).exp(),
+                None => *share,
             }
-        };
+        }).collect();
+        let sum: f64 = shares.iter().sum();
+        (sum > 0.0).then(|| shares.iter().map(|share| share / sum).collect())
+    }
+}
+
+// shares kept above `min_part`, summing to 1
+fn bound_shares(shares: &[f64], min_part: f64) -> Vec<f64> {
+    let min_part = min_part.min(1.0 / shares.len() as f64);
+    let spare = 1.0 - min_part * shares.len() as f64;
+    let excess: Vec<_> = shares.iter().map(|share| (share - min_part).max(0.0)).collect();
+    let sum: f64 = excess.iter().sum();
+    excess.iter().map(|excess| {
+        min_part + if sum > 0.0 { spare * excess / sum } else { spare / shares.len() as f64 }
+    }).collect()
+}
+
+fn join(values: &[f64]) -> String {
+    values.iter().map(|value| format!("{:.6}", value)).collect::<Vec<_>>().join(",")
+}
+
+/// Periodic update of `tx_parts` by the control law of `params`, from the RTT of each link;
+/// each decision is written to `logs/tx-parts-<name>.txt` as:
+///   time rtts outage_rates tx_parts
+pub fn control_thread(name: String, params: ControlParams, target_rtt: f64, duration: f64,
+    tx_part_ctler: Arc<Mutex<TxPartCtler>>, rtt_records: Arc<Mutex<RttRecords>>)
+{
+    let mut algorithm = params.algorithm();
+    let mut logger = if cfg!(target_os = "android") {
+        None
+    } else {
+        Some( File::create( format!("logs/tx-parts-{}.txt", name) ).unwrap() )
+    };
+    let stop_time = SystemTime::now() + Duration::from_secs_f64(duration);
+
+    while SystemTime::now() < stop_time {
+        thread::sleep( Duration::from_secs_f64(params.interval) );
+        let (_, rtts, _, outage_rates) = rtt_records.lock().unwrap().control_statistic();
+        let stats = LinkStatistics{ target_rtt, rtts, outage_rates };
+
+        let mut ctler = tx_part_ctler.lock().unwrap();
+        let shares = ctler.shares();
+        if shares.len() < 2 {
+            break;
+        }
+        if let Some(shares) = algorithm.update(&shares, &stats) {
+            ctler.set_shares( &bound_shares(&shares, params.min_part) );
+        }
+        let tx_parts = ctler.tx_parts.clone();
+        drop(ctler);
+
+        let time_now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+        trace!("TxPart: Time {} -> {} rtts {:?} outage_rates {:?} tx_parts {:?}", time_now, name, stats.rtts, stats.outage_rates, tx_parts);
+        if let Some(ref mut logger) = logger {
+            let message = format!("{:.6} {} {} {}\n", time_now, join(&stats.rtts), join(&stats.outage_rates), join(&tx_parts));
+            logger.write_all( message.as_bytes() ).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(tx_parts: &[f64], num: usize) -> Vec<(usize, usize)> {
+        TxPartCtler{ tx_parts: tx_parts.to_vec() }.path_ranges(num)
+    }
+
+    #[test]
+    fn single_link() {
+        assert_eq!(ranges(&[], 10), [(0, 10)]);
+        assert_eq!(ranges(&[1.0], 10), [(0, 10)]);
+    }
 
-        self.tx_ipaddrs[channel as usize].clone()
+    #[test]
+    fn consecutive_slices() {
+        assert_eq!(ranges(&[0.5, 0.5], 10), [(0, 5), (5, 10)]);
+        assert_eq!(ranges(&[0.5, 0.5], 3), [(0, 2), (2, 3)]);
+        assert_eq!(ranges(&[0.3, 0.6, 0.6], 10), [(0, 3), (3, 6), (6, 10)]);
+        assert_eq!(ranges(&[0.25, 0.5, 0.75, 0.75], 8), [(0, 2), (2, 4), (4, 6), (6, 8)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn overlap() {
+        assert_eq!(ranges(&[0.6, 0.4], 10), [(0, 6), (4, 10)]);
+        assert_eq!(ranges(&[0.3, 0.7, 0.5], 10), [(0, 3), (3, 7), (5, 10)]);
+        // the whole frame on both links
+        assert_eq!(ranges(&[1.0, f64::MIN_POSITIVE], 10), [(0, 10), (10, 10)]);
+        assert_eq!(ranges(&[0.9, f64::MIN_POSITIVE], 10), [(0, 9), (1, 10)]);
+    }
+
+    #[test]
+    fn zero_parts() {
+        // the last link unused, the front extending to the end
+        assert_eq!(ranges(&[0.5, 0.0], 10), [(0, 10), (10, 10)]);
+        assert_eq!(ranges(&[0.3, 0.6, 0.0], 10), [(0, 3), (3, 10), (10, 10)]);
+        assert_eq!(ranges(&[0.5, 0.0, 0.0], 10), [(0, 10), (5, 5), (10, 10)]);
+        // the front links unused
+        assert_eq!(ranges(&[0.0, 0.0], 10), [(0, 0), (0, 10)]);
+        assert_eq!(ranges(&[0.0, 0.5, 0.5], 10), [(0, 0), (0, 5), (5, 10)]);
+        assert_eq!(ranges(&[1.0, 0.5], 10), [(0, 10), (10, 10)]);
+        assert_eq!(ranges(&[0.5, 0.5], 1), [(0, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn packet_states() {
+        let ctler = TxPartCtler{ tx_parts: vec![0.4, 0.8, 0.6] };
+        let states = ctler.get_packet_states(5);
+        assert_eq!(states, [
+            vec![(0, 0, FIRST_OF_PATH), (1, 0, LAST_OF_PATH)],
+            vec![(2, 1, FIRST_OF_PATH), (3, 1, LAST_OF_PATH)],
+            // from the back
+            vec![(4, 2, LAST_OF_PATH | LAST_OF_FRAME), (3, 2, FIRST_OF_PATH)],
+        ]);
+        let ctler = TxPartCtler{ tx_parts: vec![1.0] };
+        assert_eq!(ctler.get_packet_states(1), [vec![(0, 0, FIRST_OF_PATH | LAST_OF_PATH | LAST_OF_FRAME)]]);
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        assert!(actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 1e-9), "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn set_shares_keeps_overlap() {
+        let mut ctler = TxPartCtler{ tx_parts: vec![0.6, 0.4] };
+        assert_close(&ctler.shares(), &[0.6, 0.4]);
+        ctler.set_shares(&[0.65, 0.35]);
+        assert_close(&ctler.tx_parts, &[0.65, 0.45]);
+        ctler.set_shares(&[1.0, 0.0]);
+        assert_close(&ctler.tx_parts, &[1.0, 0.8]);
+
+        let mut ctler = TxPartCtler{ tx_parts: vec![0.3, 0.6, 0.6] };
+        ctler.set_shares(&[0.2, 0.2, 0.6]);
+        assert_close(&ctler.tx_parts, &[0.2, 0.4, 0.4]);
+        // an unused last link does not overlap
+        let mut ctler = TxPartCtler{ tx_parts: vec![0.5, 0.0] };
+        assert_close(&ctler.shares(), &[0.5, 0.5]);
+        ctler.set_shares(&[0.7, 0.3]);
+        assert_close(&ctler.tx_parts, &[0.7, 0.7]);
+        // of another number of links
+        ctler.set_shares(&[0.2, 0.3, 0.5]);
+        assert_close(&ctler.tx_parts, &[0.7, 0.7]);
+    }
+
+    #[test]
+    fn bounds_shares() {
+        assert_close(&bound_shares(&[1.0, 0.0], 0.1), &[0.9, 0.1]);
+        assert_close(&bound_shares(&[0.5, 0.5, 0.0], 0.1), &[0.45, 0.45, 0.1]);
+        assert_close(&bound_shares(&[0.0, 0.0], 0.1), &[0.5, 0.5]);
+        assert_close(&bound_shares(&[1.0, 0.0], 0.8), &[0.5, 0.5]);
+    }
+
+    fn stats(rtts: &[f64], outage_rates: &[f64]) -> LinkStatistics {
+        LinkStatistics{ target_rtt: 0.01, rtts: rtts.to_vec(), outage_rates: outage_rates.to_vec() }
+    }
+
+    #[test]
+    fn step_control() {
+        let mut control = StepControl{ step: 0.1, max_outage: 0.05 };
+        let shares = [0.5, 0.5];
+        assert_eq!(control.update(&shares, &stats(&[0.005, 0.008], &[0.0, 0.0])), None);
+        assert_close(&control.update(&shares, &stats(&[0.005, 0.02], &[0.0, 0.0])).unwrap(), &[0.6, 0.4]);
+        assert_close(&control.update(&shares, &stats(&[0.005, 0.008], &[0.0, 0.1])).unwrap(), &[0.6, 0.4]);
+        // a used link without samples, an unused one is worth a try
+        assert_close(&control.update(&shares, &stats(&[0.0, 0.005], &[0.0, 0.0])).unwrap(), &[0.4, 0.6]);
+        assert_close(&control.update(&[0.95, 0.05], &stats(&[0.02, 0.0], &[0.0, 0.0])).unwrap(), &[1.0, 0.0]);
+        assert_close(&control.update(&[1.0, 0.0], &stats(&[0.02, 0.0], &[0.0, 0.0])).unwrap(), &[0.9, 0.1]);
+        assert_close(&control.update(&[0.05, 0.95], &stats(&[0.001, 0.02], &[0.0, 0.0])).unwrap(), &[0.15, 0.85]);
+    }
+
+    #[test]
+    fn proportional_control() {
+        let mut control = ProportionalControl{ gain: 1.0 };
+        assert_close(&control.update(&[0.5, 0.5], &stats(&[0.005, 0.008], &[0.0, 0.0])).unwrap(), &[0.5, 0.5]);
+        let shares = control.update(&[0.5, 0.5], &stats(&[0.005, 0.02], &[0.0, 0.0])).unwrap();
+        let scaled = 0.5 * (-1.0f64).exp();
+        assert_close(&shares, &[0.5 / (0.5 + scaled), scaled / (0.5 + scaled)]);
+        assert_close(&control.update(&[0.5, 0.5], &stats(&[0.0, 0.02], &[0.0, 0.0])).unwrap(), &[0.5 / (0.5 + scaled), scaled / (0.5 + scaled)]);
+    }
+}
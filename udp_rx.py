@@ -3,6 +3,7 @@ import argparse
 import socket, time
 import numpy as np
 import struct
+import zlib
 # import io
 import threading
 # from queue import Queue
@@ -26,14 +27,40 @@ def to_rate(x:str) -> float:
     else:
         raise argparse.ArgumentTypeError('Rate should ends with [B|KB|MB].')
 
+HEADER_FORMAT = '<HBBIHHHBBd'
+HEADER_LENGTH = struct.calcsize(HEADER_FORMAT)
+HEADER_MAGIC = 0x5352
+PROTOCOL_VERSION = 3
+LAST_OF_PATH = 0b0010
+HAS_CHECKSUM = 0b1_0000
+HAS_REPAIR = 0b10_0000
+RTP_VERSION = 2
+
 def extract(buffer):
-    # seq, offset, _length, _port, num, timestamp = struct.unpack(
-    #     '<IHHHHd', buffer[:20])
-    seq, offset, _length, _port, num, indicator , timestamp = struct.unpack(
-    '<IHHHHbd', buffer[:21])
-    # seq, offset, _length, _port, timestamp = struct.unpack(
-    #     '<IHHHd', buffer[:18])
-    return (timestamp, seq, offset, num, indicator)
+    ## RTP framed, the native header being the header extension
+    if len(buffer) >= 16 and buffer[0] >> 6 == RTP_VERSION:
+        start = 12 + 4 * (buffer[0] & 0x0F)
+        if not buffer[0] & 0x10 or len(buffer) < start + 4 or struct.unpack_from('>H', buffer, start)[0] != HEADER_MAGIC:
+            return None
+        buffer = buffer[start+4:]
+    ## reject foreign or malformed datagrams, see `core::packet::PacketStruct::decode`
+    if len(buffer) < HEADER_LENGTH:
+        return None
+    magic, version, header_len, seq, offset, _length, _port, path, flags, timestamp = struct.unpack_from(HEADER_FORMAT, buffer)
+    if magic != HEADER_MAGIC or version != PROTOCOL_VERSION or header_len < HEADER_LENGTH or len(buffer) < header_len + _length:
+        return None
+    if flags & HAS_CHECKSUM:
+        if header_len < HEADER_LENGTH + 4:
+            return None
+        expected, = struct.unpack_from('<I', buffer, HEADER_LENGTH)
+        if zlib.crc32(bytes(buffer[HEADER_LENGTH+4:]), zlib.crc32(bytes(buffer[:HEADER_LENGTH]) + bytes(4))) != expected:
+            return None
+    ## FEC repair packets are not used here
+    if flags & HAS_REPAIR:
+        return None
+    ## path id, plus 10 for the last packet on the path
+    indicator = path + (10 if flags & LAST_OF_PATH else 0)
+    return (timestamp, seq, offset, indicator)
 
 
 # def process_packet(args, _buffer, addr, pong_port, pong_sock, seq_offset):
@@ -49,14 +76,17 @@ def recv_thread(args, sock, pong_port, pong_sock, trigger):
     print('started.')
     seq_offset = []
     while True:
-        _buffer, addr = sock.recvfrom(2048)
+        _buffer, addr = sock.recvfrom(65535)
         received_length += len(_buffer)
         if args.calc_jitter:
-            timestamp, seq, offset, num, indicator = extract(_buffer)
+            header = extract(_buffer)
+            if header is None:
+                continue
+            timestamp, seq, offset, indicator = header
             if seq not in received_record:
                 received_record[seq] = (timestamp, time.time())
             while seq >= len(seq_offset):
-                seq_offset.append([[num, -1], [0,0]])
+                seq_offset.append([[offset + 1, -1], [0,0]])
             if type(received_record[seq]) == tuple:
                 if indicator % 10 == 0 and offset < seq_offset[seq][0][indicator % 10]:
                     seq_offset[seq][0][indicator % 10] = offset
@@ -124,11 +154,12 @@ def main(args):
         t = threading.Thread(target=recv_thread, args=(args, sock, pong_port, pong_sock, trigger), daemon=True)
         t.start()
         
-        _buf = sock.recv(10240)
+        _buf = sock.recv(65535)
         if args.calc_jitter:
-            # timestamp, init_seq, _, __ = extract( _buf )
-            timestamp, init_seq, _, __, ___ = extract( _buf )
-            received_record[init_seq] = ( timestamp, time.time() )
+            header = extract( _buf )
+            if header is not None:
+                timestamp, init_seq, _, __ = header
+                received_record[init_seq] = ( timestamp, time.time() )
         init_time = time.time()
 
     # waiting for fixed duration / length